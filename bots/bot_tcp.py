@@ -12,21 +12,34 @@ class GameIo:
         self.name = name
         self.initted = False
         self.gameover = False
+        # the apple's position in snake mode
+        self.food = None
+        # other lines from the server, like the "WRAP" rule or "BOOST 12"
+        # power-ups
+        self.notices = []
+        # the turn number the server sent with the last positions
+        self.turn = None
 
     # Do one game loop iteration. Returns a (my_pos, their_pos) tuple, or WIN/LOSS/TIE strings if those situations arise
     # On the first call control_char is ignored; this is the call that starts the game.
     # Calls will likely raise exceptions after a WIN/LOSS/TIE condition, depending on server behavior
     def advance(self, control_char):
         if not self.initted:
-            self.socket.send((self.name + "\n").encode("utf-8"))
+            # ask for the turn number with each update
+            self.socket.send(("PROTOCOL 2\n" + self.name + "\n").encode("utf-8"))
             self.initted = True
         else:
             self.socket.send((control_char + "\n").encode("utf-8"))
         line = self._wait_for_line()
-        if line in ["WIN\n", "LOSS\n", "TIE\n"]:
+        # a result may be followed by why the game ended, as in "WIN TURN_LIMIT"
+        while line[0].isalpha() and line.split()[0] not in ["WIN", "LOSS", "TIE"]:
+            self.notices.append(line.strip())
+            line = self._wait_for_line()
+        if line.split()[0] in ["WIN", "LOSS", "TIE"]:
             self.gameover = True
-            return line.strip()
-        my_pos, their_pos = (int(i) for i in line.strip().split(" "))
+            return line.split()[0]
+        self.turn, my_pos, their_pos, *food = (int(i) for i in line.strip().split(" "))
+        self.food = food[0] if food else None
         return my_pos, their_pos
 
     def is_gameover(self):
@@ -8,10 +8,10 @@ print("seq_bot_txt")
 counter = 0
 while True:
     data = input()
-    if data in ["WIN", "LOSS", "TIE"]:
+    if data.split()[0] in ["WIN", "LOSS", "TIE"]:
         print(data)
         break
-    my_pos, their_pos = (int(i) for i in data.strip().split(" "))
+    my_pos, their_pos = (int(i) for i in data.strip().split(" ")[:2])
     print(seq[counter % len(seq)])
     # This is how you might do print debugging:
     print("Moving", seq[counter % len(seq)], file=sys.stderr)
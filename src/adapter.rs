@@ -1,4 +1,3 @@
-use anyhow;
 use std::fmt::Write as _;
 use std::io::{self, BufRead as _, Write as _};
 use std::net::SocketAddr;
@@ -23,6 +22,8 @@ fn main() -> Result<(), anyhow::Error> {
     eprintln!("Adapter Connecting...");
     let stream = TcpStream::connect(opt.host)?;
     stream.set_nonblocking(true)?;
+    // moves are tiny lines that Nagle's algorithm would hold back
+    stream.set_nodelay(true)?;
 
     let (stdin, tcpout) = channel();
 
@@ -45,7 +46,7 @@ fn main() -> Result<(), anyhow::Error> {
                 // have to manually append newline byte
                 write_buffer.clear();
                 writeln!(&mut write_buffer, "{}", val)?;
-                stream.get_mut().write(write_buffer.as_bytes()).unwrap();
+                stream.get_mut().write_all(write_buffer.as_bytes()).unwrap();
             }
             Err(TryRecvError::Empty) => (),
             Err(TryRecvError::Disconnected) => {
@@ -63,7 +64,10 @@ fn main() -> Result<(), anyhow::Error> {
                 return Ok(());
             }
             Ok(_) => {
-                stdout.write(read_buffer.as_bytes())?;
+                if let Some(name) = read_buffer.strip_prefix("OPPONENT ") {
+                    eprintln!("Playing against {}", name.trim_end());
+                }
+                stdout.write_all(read_buffer.as_bytes())?;
             }
             Err(err) => match err.kind() {
                 io::ErrorKind::WouldBlock => (),
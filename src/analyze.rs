@@ -0,0 +1,195 @@
+// Statistics on a game recorded with --record, for the `analyze` subcommand:
+// how long it lasted and what ended it, how quickly each player replied, and
+// how the board was divided between them as the game went on.
+use crate::replay::Replay;
+use snake_ai_battle::{Bitboard, Player, RedBlue, WinState};
+use std::fmt::Write as _;
+use std::path::Path;
+
+// rows in the territory table, however long the game
+const ROWS: usize = 20;
+
+pub fn run(path: &Path) -> Result<(), anyhow::Error> {
+    let replay = Replay::load(path)?;
+    print!("{}", report(&replay)?);
+    Ok(())
+}
+
+// how each position looked to the players
+pub struct Position {
+    pub turn: usize,
+    pub territory: RedBlue<usize>,
+    pub reachable: RedBlue<usize>,
+}
+
+fn report(replay: &Replay) -> Result<String, std::fmt::Error> {
+    let names = &replay.names;
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{}: {} (red) vs {} (blue), {} on {}x{}",
+        replay.match_id, names.red, names.blue, replay.mode, replay.width, replay.height
+    )?;
+
+    let first = &replay.boards[0];
+    let last = &replay.boards[replay.boards.len() - 1];
+    // the clock may have been set back during the game
+    let seconds = last.timestamp_ms.saturating_sub(first.timestamp_ms) as f64 / 1000.0;
+    let ending = match replay.result {
+        Some(WinState::Win) => format!("{} won", names.red),
+        Some(WinState::Loss) => format!("{} won", names.blue),
+        Some(WinState::Tie) => "Tied".to_owned(),
+        None => "Unfinished".to_owned(),
+    };
+    let turns = replay.turns.max(last.turn);
+    writeln!(out, "{} after {} turns, in {:.1}s", ending, turns, seconds)?;
+    for (name, reason) in [
+        (&names.red, replay.end_reasons.red),
+        (&names.blue, replay.end_reasons.blue),
+    ] {
+        if let Some(reason) = reason {
+            writeln!(out, "{}'s game ended: {}", name, reason.as_str())?;
+        }
+    }
+
+    if let Some(times) = &replay.response_times {
+        for (name, times) in [(&names.red, &times.red), (&names.blue, &times.blue)] {
+            match times.max_ms {
+                Some(max_ms) if times.moves > 0 => writeln!(
+                    out,
+                    "{} replied in {:.2}ms on average, {:.2}ms at most, over {} moves",
+                    name,
+                    times.total_ms / times.moves as f64,
+                    max_ms,
+                    times.moves
+                )?,
+                _ => writeln!(out, "{} made no moves", name)?,
+            }
+        }
+    }
+
+    let positions = positions(replay);
+    writeln!(out, "\nTerritory, as cells each player reaches first:")?;
+    writeln!(
+        out,
+        "{:>6} {:>6} {:>6} {:>8}",
+        "turn", "red", "blue", "balance"
+    )?;
+    for position in sampled(&positions) {
+        let territory = position.territory;
+        writeln!(
+            out,
+            "{:>6} {:>6} {:>6} {:>+8}",
+            position.turn,
+            territory.red,
+            territory.blue,
+            territory.red as i64 - territory.blue as i64
+        )?;
+    }
+
+    let loser = match replay.result {
+        Some(WinState::Win) => Player::Blue,
+        Some(WinState::Loss) => Player::Red,
+        _ => return Ok(out),
+    };
+    let (loser_name, winner_name) = match loser {
+        Player::Red => (&names.red, &names.blue),
+        Player::Blue => (&names.blue, &names.red),
+    };
+    writeln!(out)?;
+    match fell_behind(&positions, loser) {
+        Some(position) => {
+            let areas = position.reachable;
+            let (behind, ahead) = match loser {
+                Player::Red => (areas.red, areas.blue),
+                Player::Blue => (areas.blue, areas.red),
+            };
+            writeln!(
+                out,
+                "{}'s reachable area first fell below {}'s on turn {}, {} cells to {}",
+                loser_name, winner_name, position.turn, behind, ahead
+            )?;
+        }
+        None => writeln!(
+            out,
+            "{}'s reachable area never fell below {}'s",
+            loser_name, winner_name
+        )?,
+    }
+    Ok(out)
+}
+
+// Every position a move was played from. The board after the last turn is
+// left out, since a player that crashed has no head on it.
+pub fn positions(replay: &Replay) -> Vec<Position> {
+    let boards = match replay.boards.len() {
+        1 => &replay.boards[..],
+        len => &replay.boards[..len - 1],
+    };
+    boards
+        .iter()
+        .map(|board| {
+            let bits = Bitboard::new(&board.cells, replay.width, replay.wrap);
+            Position {
+                turn: board.turn,
+                territory: bits.territory(board.heads),
+                reachable: RedBlue {
+                    red: bits.reachable(board.heads.red),
+                    blue: bits.reachable(board.heads.blue),
+                },
+            }
+        })
+        .collect()
+}
+
+// Evenly spaced rows for a table, the last included, however many there are
+pub fn sampled<T>(items: &[T]) -> impl Iterator<Item = &T> {
+    let every = items.len().div_ceil(ROWS).max(1);
+    items
+        .iter()
+        .enumerate()
+        .filter(move |(i, _)| i % every == 0 || i + 1 == items.len())
+        .map(|(_, item)| item)
+}
+
+// the first position where the loser could reach less of the board than the
+// winner
+fn fell_behind(positions: &[Position], loser: Player) -> Option<&Position> {
+    positions.iter().find(|position| {
+        let areas = position.reachable;
+        match loser {
+            Player::Red => areas.red < areas.blue,
+            Player::Blue => areas.blue < areas.red,
+        }
+    })
+}
+
+#[test]
+fn falling_behind() {
+    let position = |turn, red, blue| Position {
+        turn,
+        territory: RedBlue { red: 0, blue: 0 },
+        reachable: RedBlue { red, blue },
+    };
+    let positions = [position(0, 10, 10), position(1, 9, 8), position(2, 5, 7)];
+    assert_eq!(
+        Some(2),
+        fell_behind(&positions, Player::Red).map(|p| p.turn)
+    );
+    assert_eq!(
+        Some(1),
+        fell_behind(&positions, Player::Blue).map(|p| p.turn)
+    );
+    assert!(fell_behind(&positions[..1], Player::Red).is_none());
+}
+
+#[test]
+fn sampling() {
+    let few: Vec<_> = (0..5).collect();
+    assert_eq!(few, sampled(&few).copied().collect::<Vec<_>>());
+    // every third, and the last
+    let many: Vec<_> = (0..41).collect();
+    let rows: Vec<_> = sampled(&many).copied().collect();
+    assert_eq!(15, rows.len());
+    assert_eq!((&[0, 3][..], &[39, 40][..]), (&rows[..2], &rows[13..]));
+}
@@ -0,0 +1,107 @@
+// Strips what identifies the players from a game recorded with --record, for
+// the `anonymize` subcommand, so replays can be published as datasets. Names
+// become "red" and "blue", and addresses are dropped. Every other line is
+// copied as it is, so the moves, boards and result are kept. That breaks the
+// replay's seal, so the copy gets a new one, signed with --signing-key if
+// there is one.
+use crate::replay::Event;
+use crate::seal::Chain;
+use crate::{replay, EventRecord, GameEvent, CLI_OPTIONS};
+use serde::{Deserialize, Serialize};
+use snake_ai_battle::Player;
+use std::io::Write;
+use std::path::Path;
+
+// the fields of a Named or Connected event worth keeping
+#[derive(Deserialize, Serialize)]
+struct PlayerEvent {
+    timestamp_ms: u64,
+    match_id: Option<String>,
+    event: String,
+    player: Player,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+
+pub fn run(path: &Path, output: &Path) -> Result<(), anyhow::Error> {
+    // only replays, whose events are all about one game
+    let (header, mut records) = replay::records(path)?;
+    // never write over another file, least of all the replay itself
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(output)
+        .map_err(|e| anyhow::anyhow!("can't create {}: {}", output.display(), e))?;
+    let mut out = std::io::BufWriter::new(file);
+    let mut stripped = 0;
+    let mut chain = Chain::new();
+    chain.add(records.line().as_bytes());
+    writeln!(out, "{}", records.line())?;
+    while let Some(record) = records.next() {
+        if let Event::Sealed(_) = record?.event {
+            continue;
+        }
+        let anonymized = anonymize(records.line())?;
+        if anonymized.is_some() {
+            stripped += 1;
+        }
+        let line = anonymized.as_deref().unwrap_or(records.line());
+        chain.add(line.as_bytes());
+        writeln!(out, "{}", line)?;
+    }
+    let seal = EventRecord {
+        timestamp_ms: crate::unix_ms(),
+        match_id: Some(&header.match_id),
+        event: GameEvent::Sealed {
+            seal: chain.seal(CLI_OPTIONS.signing_key.as_ref()),
+        },
+    };
+    serde_json::to_writer(&mut out, &seal)?;
+    writeln!(out)?;
+    out.flush()?;
+    println!(
+        "{}: wrote {}, with the players stripped from {} events",
+        header.match_id,
+        output.display(),
+        stripped
+    );
+    Ok(())
+}
+
+// The line without the player's name or address, if it has either
+fn anonymize(line: &str) -> Result<Option<String>, serde_json::Error> {
+    if !line.contains(r#""event":"Named""#) && !line.contains(r#""event":"Connected""#) {
+        return Ok(None);
+    }
+    let mut event: PlayerEvent = serde_json::from_str(line)?;
+    event.name = match event.event.as_str() {
+        "Named" => Some(
+            match event.player {
+                Player::Red => "red",
+                Player::Blue => "blue",
+            }
+            .to_owned(),
+        ),
+        // only the address is dropped
+        _ => None,
+    };
+    serde_json::to_string(&event).map(Some)
+}
+
+#[test]
+fn stripping() {
+    let named =
+        r#"{"timestamp_ms":1,"match_id":"x","event":"Named","player":"Blue","name":"alice_bot"}"#;
+    assert_eq!(
+        Some(r#"{"timestamp_ms":1,"match_id":"x","event":"Named","player":"Blue","name":"blue"}"#),
+        anonymize(named).unwrap().as_deref()
+    );
+    let connected = r#"{"timestamp_ms":1,"match_id":"x","event":"Connected","player":"Red","peer":"10.0.0.7:5000"}"#;
+    assert_eq!(
+        Some(r#"{"timestamp_ms":1,"match_id":"x","event":"Connected","player":"Red"}"#),
+        anonymize(connected).unwrap().as_deref()
+    );
+    let turn =
+        r#"{"timestamp_ms":1,"match_id":"x","event":"Turn","turn":0,"red":"Up","blue":"Up"}"#;
+    assert_eq!(None, anonymize(turn).unwrap());
+}
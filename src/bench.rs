@@ -0,0 +1,134 @@
+// Measures how fast the engine plays and how long a turn's round trip takes
+// over loopback, for catching performance regressions.
+use super::{Client, ResponseTimes, CLI_OPTIONS};
+use rand::Rng;
+use snake_ai_battle::{Direction, Game, RedBlue, ToClientMessage, TronGame};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+const DIRECTIONS: [Direction; 4] = [
+    Direction::Up,
+    Direction::Right,
+    Direction::Down,
+    Direction::Left,
+];
+
+pub async fn run(games: usize, positions: usize, round_trips: usize) -> Result<(), anyhow::Error> {
+    engine(games);
+    flood_fill(positions);
+    loopback(round_trips).await
+}
+
+// Plays games between two bots that move randomly but never crash unless
+// they have to, so games run long enough to be dominated by observe.
+fn engine(games: usize) {
+    let mut rng = rand::thread_rng();
+    let mut turns = 0;
+    let start = Instant::now();
+    for _ in 0..games {
+        let mut game = TronGame::new(CLI_OPTIONS.width(), CLI_OPTIONS.height());
+        while !game.game_over() {
+            let offset = rng.gen_range(0, DIRECTIONS.len());
+            let moves = RedBlue {
+                red: safe_move(&game, game.positions().red, offset),
+                blue: safe_move(&game, game.positions().blue, offset),
+            };
+            game.observe(moves);
+            turns += 1;
+        }
+    }
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "engine: {} games, {} turns in {:.3}s ({:.0} turns/s)",
+        games,
+        turns,
+        secs,
+        turns as f64 / secs
+    );
+}
+
+// Times territory, the flood fill that decides games at --max-turns and
+// that analyze runs on every position, on positions from the same games
+fn flood_fill(positions: usize) {
+    let mut rng = rand::thread_rng();
+    let mut measured = 0;
+    let mut cells = 0;
+    let mut spent = Duration::ZERO;
+    while measured < positions {
+        let mut game = TronGame::new(CLI_OPTIONS.width(), CLI_OPTIONS.height());
+        while !game.game_over() && measured < positions {
+            let start = Instant::now();
+            let territory = game.territory();
+            spent += start.elapsed();
+            cells += territory.red + territory.blue;
+            measured += 1;
+            let offset = rng.gen_range(0, DIRECTIONS.len());
+            let moves = RedBlue {
+                red: safe_move(&game, game.positions().red, offset),
+                blue: safe_move(&game, game.positions().blue, offset),
+            };
+            game.observe(moves);
+        }
+    }
+    let secs = spent.as_secs_f64();
+    println!(
+        "flood fill: {} positions in {:.3}s ({:.0} positions/s), {:.0} cells claimed on average",
+        measured,
+        secs,
+        measured as f64 / secs,
+        cells as f64 / measured.max(1) as f64
+    );
+}
+
+fn safe_move(game: &TronGame, pos: usize, offset: usize) -> Direction {
+    (0..DIRECTIONS.len())
+        .map(|i| DIRECTIONS[(i + offset) % DIRECTIONS.len()])
+        .find(|&d| {
+            !game.boundary_collision(pos, d) && !game.board()[game.advance(pos, d)].occupied()
+        })
+        .unwrap_or(Direction::Up)
+}
+
+// Exchanges updates and moves with a dummy client that always goes up
+async fn loopback(round_trips: usize) -> Result<(), anyhow::Error> {
+    let mut listener =
+        tokio::net::TcpListener::bind(std::net::SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+    let addr = listener.local_addr()?;
+    let dummy = tokio::spawn(async move {
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        let mut stream = tokio::io::BufReader::new(stream);
+        let mut line = String::new();
+        while stream.read_line(&mut line).await? != 0 {
+            stream.get_mut().write_all(b"u\n").await?;
+            line.clear();
+        }
+        Ok::<_, std::io::Error>(())
+    });
+    let (stream, _) = listener.accept().await?;
+    let mut client = Client::new(stream)?;
+    let mut times = ResponseTimes::default();
+    let last = CLI_OPTIONS.width() * CLI_OPTIONS.height() - 1;
+    for i in 0..round_trips {
+        let start = Instant::now();
+        client
+            .send_update(ToClientMessage::Update(i % last, last - i % last))
+            .await?;
+        let res = client
+            .read_direction(Instant::now() + Duration::from_secs(1))
+            .await?;
+        times.record(start.elapsed().as_secs_f64() * 1000.0, &res);
+    }
+    drop(client);
+    dummy.await??;
+    let stats = times.stats();
+    println!(
+        "loopback: {} round trips, {} timed out, min {:.3}ms, median {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+        stats.moves,
+        stats.timeouts,
+        stats.min_ms.unwrap_or(0.0),
+        stats.median_ms.unwrap_or(0.0),
+        stats.p99_ms.unwrap_or(0.0),
+        stats.max_ms.unwrap_or(0.0),
+    );
+    Ok(())
+}
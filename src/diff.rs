@@ -0,0 +1,150 @@
+// Compares two games recorded with --record under the same setup, seed
+// included, for the `diff` subcommand: say, a bot's games against the same
+// opponent before and after a change to it. Reports the first turn the moves
+// differ on, and how the games went from there.
+use crate::analyze::{positions, sampled};
+use crate::replay::Replay;
+use snake_ai_battle::{Direction, RedBlue, WinState};
+use std::fmt::Write as _;
+use std::path::Path;
+
+pub fn run(first: &Path, second: &Path) -> Result<(), anyhow::Error> {
+    let games = [Replay::load(first)?, Replay::load(second)?];
+    let mut setups = Vec::new();
+    for game in &games {
+        match &game.setup {
+            Some(setup) => setups.push(serde_json::to_value(setup)?),
+            None => anyhow::bail!(
+                "{} has no Setup event, so its moves can't be compared",
+                game.match_id
+            ),
+        }
+    }
+    if setups[0] != setups[1] {
+        anyhow::bail!(
+            "{} and {} weren't played with the same setup and seed, so their moves can't be compared",
+            games[0].match_id,
+            games[1].match_id
+        );
+    }
+    print!("{}", report(&games)?);
+    Ok(())
+}
+
+fn report([first, second]: &[Replay; 2]) -> Result<String, std::fmt::Error> {
+    let mut out = String::new();
+    for game in [first, second] {
+        writeln!(
+            out,
+            "{}: {} (red) vs {} (blue), {}",
+            game.match_id,
+            game.names.red,
+            game.names.blue,
+            ending(game)
+        )?;
+    }
+    let turn = match diverged(&first.moves, &second.moves) {
+        Some(turn) => turn,
+        None => {
+            writeln!(out, "The games were played move for move the same")?;
+            return Ok(out);
+        }
+    };
+    match (first.moves.get(turn), second.moves.get(turn)) {
+        (Some(a), Some(b)) => {
+            for (color, a, b) in [("Red", a.red, b.red), ("Blue", a.blue, b.blue)] {
+                if a != b {
+                    writeln!(
+                        out,
+                        "{} played {:?} on turn {} in the first game, but {:?} in the second",
+                        color, a, turn, b
+                    )?;
+                }
+            }
+        }
+        _ => writeln!(
+            out,
+            "The moves are the same until one game ends, after {} turns",
+            turn
+        )?,
+    }
+
+    let (first, second) = (positions(first), positions(second));
+    let pairs: Vec<_> = first
+        .iter()
+        .filter(|position| position.turn >= turn)
+        .filter_map(|a| {
+            let b = second.iter().find(|b| b.turn == a.turn)?;
+            Some((a.turn, a.territory, b.territory))
+        })
+        .collect();
+    if pairs.is_empty() {
+        return Ok(out);
+    }
+    writeln!(
+        out,
+        "\nTerritory from there, as cells each player reaches first:"
+    )?;
+    writeln!(out, "{:>6} {:>13} {:>13}", "", "first", "second")?;
+    writeln!(
+        out,
+        "{:>6} {:>6} {:>6} {:>6} {:>6}",
+        "turn", "red", "blue", "red", "blue"
+    )?;
+    for (turn, a, b) in sampled(&pairs) {
+        writeln!(
+            out,
+            "{:>6} {:>6} {:>6} {:>6} {:>6}",
+            turn, a.red, a.blue, b.red, b.blue
+        )?;
+    }
+    Ok(out)
+}
+
+// how the game came out, and what ended the loser's game
+fn ending(game: &Replay) -> String {
+    let names = &game.names;
+    let (ending, reason) = match game.result {
+        Some(WinState::Win) => (format!("{} won", names.red), game.end_reasons.blue),
+        Some(WinState::Loss) => (format!("{} won", names.blue), game.end_reasons.red),
+        Some(WinState::Tie) => ("tied".to_owned(), game.end_reasons.red),
+        None => ("unfinished".to_owned(), None),
+    };
+    let turns = game.turns.max(game.moves.len());
+    match reason {
+        Some(reason) => format!("{} after {} turns ({})", ending, turns, reason.as_str()),
+        None => format!("{} after {} turns", ending, turns),
+    }
+}
+
+// The first turn either player moved differently on, or that only one game
+// was played to. None if the games were played the same.
+fn diverged(first: &[RedBlue<Direction>], second: &[RedBlue<Direction>]) -> Option<usize> {
+    let same = first
+        .iter()
+        .zip(second)
+        .take_while(|(a, b)| a.red == b.red && a.blue == b.blue)
+        .count();
+    if same == first.len() && same == second.len() {
+        None
+    } else {
+        Some(same)
+    }
+}
+
+#[test]
+fn divergence() {
+    use Direction::*;
+    let moves = |moves: &[(Direction, Direction)]| -> Vec<_> {
+        moves
+            .iter()
+            .map(|&(red, blue)| RedBlue { red, blue })
+            .collect()
+    };
+    let played = moves(&[(Up, Down), (Left, Down), (Left, Right)]);
+    assert_eq!(None, diverged(&played, &played));
+    let changed = moves(&[(Up, Down), (Left, Up), (Left, Right)]);
+    assert_eq!(Some(1), diverged(&played, &changed));
+    assert_eq!(Some(2), diverged(&played, &played[..2]));
+    assert_eq!(Some(0), diverged(&[], &played));
+}
@@ -0,0 +1,338 @@
+// The JSON protocol, for clients that send "PROTOCOL JSON" before their name:
+// one object per line, with positions as coordinates from the client's own
+// side of the board, and the cells that changed since its last update. Plain
+// text clients that ask for DELTAS are sent the same changes.
+use super::Config;
+use serde::Serialize;
+use snake_ai_battle::{
+    invert_pos, Occupancy, Player, PowerUp, RenderData, Rule, ToClientMessage, WinState,
+};
+
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Coord {
+    x: usize,
+    y: usize,
+}
+
+// a cell as the client sees it
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cell {
+    Free,
+    You,
+    Opponent,
+    Wall,
+    Food,
+    Boost,
+    Bomb,
+}
+
+impl Cell {
+    // as in the plain text protocol
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Cell::Free => "FREE",
+            Cell::You => "YOU",
+            Cell::Opponent => "OPPONENT",
+            Cell::Wall => "WALL",
+            Cell::Food => "FOOD",
+            Cell::Boost => "BOOST",
+            Cell::Bomb => "BOMB",
+        }
+    }
+
+    // for board dumps
+    pub fn as_char(self) -> char {
+        match self {
+            Cell::Free => '.',
+            Cell::You => 'Y',
+            Cell::Opponent => 'O',
+            Cell::Wall => '#',
+            Cell::Food => 'F',
+            Cell::Boost => 'B',
+            Cell::Bomb => 'X',
+        }
+    }
+
+    fn new(occupancy: Occupancy, seat: Player) -> Self {
+        match occupancy {
+            Occupancy::Free => Cell::Free,
+            Occupancy::Occupied(player) if player == seat => Cell::You,
+            Occupancy::Occupied(_) => Cell::Opponent,
+            Occupancy::Wall => Cell::Wall,
+            Occupancy::Food => Cell::Food,
+            Occupancy::PowerUp(PowerUp::Boost) => Cell::Boost,
+            Occupancy::PowerUp(PowerUp::Bomb) => Cell::Bomb,
+        }
+    }
+}
+
+// A copy of the board as of a client's last update, for "board" queries,
+// which only few clients make
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    seat: Option<Player>,
+    width: usize,
+    cells: Vec<Occupancy>,
+}
+
+impl Snapshot {
+    pub fn update(&mut self, seat: Player, board: &RenderData) {
+        self.seat = Some(seat);
+        self.width = board.width;
+        self.cells.clear();
+        self.cells.extend_from_slice(&board.data);
+    }
+
+    pub fn clear(&mut self) {
+        *self = Snapshot::default();
+    }
+
+    // The whole board as the player in its seat sees it, one row to a string
+    pub fn rows(&self) -> Vec<String> {
+        let seat = match self.seat {
+            Some(seat) => seat,
+            None => return Vec::new(),
+        };
+        let cells = self.cells.len();
+        let row = |y: usize| {
+            (y * self.width..(y + 1) * self.width)
+                .map(|pos| {
+                    // blue sees the board rotated half a turn
+                    let pos = match seat {
+                        Player::Red => pos,
+                        Player::Blue => invert_pos(pos, cells),
+                    };
+                    Cell::new(self.cells[pos], seat).as_char()
+                })
+                .collect()
+        };
+        (0..cells / self.width).map(row).collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Change {
+    #[serde(flatten)]
+    at: Coord,
+    cell: Cell,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JsonMessage {
+    // answered with "PONG", as in the plain text protocol
+    Ping,
+    // the seat a client that asked for one got, "RED" or "BLUE"
+    Color {
+        color: &'static str,
+    },
+    Opponent {
+        name: String,
+    },
+    Config {
+        width: usize,
+        height: usize,
+        timeout: u64,
+        you: Coord,
+        opponent: Coord,
+        flags: Vec<&'static str>,
+    },
+    // as in the plain text protocol, like "WRAP"
+    Rule {
+        rule: String,
+    },
+    Update {
+        turn: usize,
+        you: Coord,
+        opponent: Coord,
+        food: Option<Coord>,
+        changes: Vec<Change>,
+    },
+    PowerUp {
+        kind: &'static str,
+        at: Coord,
+    },
+    // a timeout that was let off with --timeout-strikes
+    Warning {
+        reason: &'static str,
+        strikes_left: usize,
+    },
+    // milliseconds left on each player's clock, with --time-bank
+    Time {
+        you: u128,
+        opponent: u128,
+    },
+    // in reply to a "board" query, with the cells as in the plain text
+    // protocol
+    Board {
+        width: usize,
+        height: usize,
+        rows: Vec<String>,
+    },
+    End {
+        result: &'static str,
+        reason: Option<&'static str>,
+    },
+    // with --best-of, between games: the next game's number and the score,
+    // by the colors the players will have
+    NewGame {
+        game: usize,
+        red: usize,
+        blue: usize,
+    },
+    // the end of a --best-of series, with the games each player won
+    Series {
+        result: &'static str,
+        you: usize,
+        opponent: usize,
+    },
+}
+
+// as in the plain text protocol
+pub fn result(win: WinState) -> &'static str {
+    match win {
+        WinState::Win => "WIN",
+        WinState::Loss => "LOSS",
+        WinState::Tie => "TIE",
+    }
+}
+
+pub fn config(config: &Config) -> JsonMessage {
+    let coord = |pos| Coord {
+        x: pos % config.width,
+        y: pos / config.width,
+    };
+    JsonMessage::Config {
+        width: config.width,
+        height: config.height,
+        timeout: config.timeout,
+        you: coord(config.start),
+        opponent: coord(config.opponent_start),
+        flags: config.flags.clone(),
+    }
+}
+
+pub fn rule(rule: &Rule) -> JsonMessage {
+    JsonMessage::Rule {
+        rule: rule.to_string(),
+    }
+}
+
+// The board as a client was last told about it
+#[derive(Debug, Default)]
+pub struct BoardView {
+    cells: Vec<Occupancy>,
+}
+
+impl BoardView {
+    // Turns a message for the player in this seat into JSON. Updates carry
+    // the cells that changed on the board since the last one.
+    pub fn message(
+        &mut self,
+        msg: ToClientMessage,
+        turn: usize,
+        seat: Player,
+        board: Option<&RenderData>,
+    ) -> JsonMessage {
+        let width = board.map_or(1, |board| board.width);
+        let coord = |pos| Coord {
+            x: pos % width,
+            y: pos / width,
+        };
+        let (you, opponent, food) = match msg {
+            ToClientMessage::End(win, reason) => {
+                return JsonMessage::End {
+                    result: result(win),
+                    reason: reason.map(|reason| reason.as_str()),
+                }
+            }
+            ToClientMessage::PowerUp(kind, pos) => {
+                return JsonMessage::PowerUp {
+                    kind: match kind {
+                        PowerUp::Boost => "BOOST",
+                        PowerUp::Bomb => "BOMB",
+                    },
+                    at: coord(pos),
+                }
+            }
+            ToClientMessage::Update(you, opponent) => (you, opponent, None),
+            ToClientMessage::FoodUpdate(you, opponent, food) => (you, opponent, Some(food)),
+        };
+        let changes = match board {
+            Some(board) => self
+                .changes(seat, board)
+                .into_iter()
+                .map(|(pos, cell)| Change {
+                    at: coord(pos),
+                    cell,
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        JsonMessage::Update {
+            turn,
+            you: coord(you),
+            opponent: coord(opponent),
+            food: food.map(coord),
+            changes,
+        }
+    }
+
+    // The cells that changed since the last call, and what's in them now, as
+    // the player in this seat sees the board
+    pub fn changes(&mut self, seat: Player, board: &RenderData) -> Vec<(usize, Cell)> {
+        let cells = board.data.len();
+        self.cells.resize(cells, Occupancy::Free);
+        let mut changes = Vec::new();
+        for (pos, (&now, seen)) in board.data.iter().zip(self.cells.iter_mut()).enumerate() {
+            if now == *seen {
+                continue;
+            }
+            *seen = now;
+            // blue sees the board rotated half a turn
+            let pos = match seat {
+                Player::Red => pos,
+                Player::Blue => invert_pos(pos, cells),
+            };
+            changes.push((pos, Cell::new(now, seat)));
+        }
+        changes
+    }
+}
+
+#[test]
+fn changes() {
+    use snake_ai_battle::RedBlue;
+    use std::borrow::Cow;
+    let mut data = vec![Occupancy::Free; 6];
+    data[1] = Occupancy::Occupied(Player::Red);
+    data[5] = Occupancy::Wall;
+    let board = RenderData {
+        width: 3,
+        height: 2,
+        data: Cow::Owned(data),
+        wrap: false,
+        result: None,
+        end_reasons: RedBlue {
+            red: None,
+            blue: None,
+        },
+    };
+    let mut view = BoardView::default();
+    let msg = view.message(ToClientMessage::Update(4, 0), 0, Player::Blue, Some(&board));
+    // blue sees red's cell at the bottom, and the wall in the top left
+    assert_eq!(
+        r#"{"type":"update","turn":0,"you":{"x":1,"y":1},"opponent":{"x":0,"y":0},"food":null,"changes":[{"x":1,"y":1,"cell":"opponent"},{"x":0,"y":0,"cell":"wall"}]}"#,
+        serde_json::to_string(&msg).unwrap()
+    );
+    // and only what changes after that
+    let msg = view.message(ToClientMessage::Update(4, 0), 1, Player::Blue, Some(&board));
+    assert!(serde_json::to_string(&msg)
+        .unwrap()
+        .ends_with(r#""changes":[]}"#));
+    let mut snapshot = Snapshot::default();
+    assert!(snapshot.rows().is_empty());
+    snapshot.update(Player::Blue, &board);
+    assert_eq!(vec!["#..", ".O."], snapshot.rows());
+}
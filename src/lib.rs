@@ -0,0 +1,1525 @@
+//! The game engines behind the snake-ai-battle server, Tron light cycles and
+//! classic snake, along with the protocol's message types, for reuse in bots
+//! written in Rust.
+//!
+//! Boards are stored row by row from the top left, so position `i` is at
+//! column `i % width` of row `i / width`. The engines play from red's point
+//! of view; blue sees the board rotated by half a turn, which
+//! [`invert_pos`], [`invert_direction`] and [`invert_update`] convert to and
+//! from.
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use thiserror::Error;
+
+mod map;
+mod snake;
+
+pub use map::{Map, MapError};
+pub use snake::SnakeGame;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Player {
+    Red,
+    Blue,
+}
+
+/// What's in a cell of the board. In Tron, trails never go away, so a cell
+/// that's been occupied stays occupied.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Occupancy {
+    /// An apple, in snake mode
+    Food,
+    /// An obstacle from a map
+    Wall,
+    #[serde(untagged)]
+    PowerUp(PowerUp),
+    #[serde(untagged)]
+    Occupied(Player),
+    #[serde(untagged)]
+    Free,
+}
+
+impl Occupancy {
+    /// Whether moving here crashes.
+    pub fn occupied(self) -> bool {
+        use Occupancy::*;
+        match self {
+            Free | Food | PowerUp(_) => false,
+            Occupied(_) | Wall => true,
+        }
+    }
+}
+
+/// A snapshot of the board, as sent to the visualizer.
+#[derive(Debug, Serialize)]
+pub struct RenderData<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub data: Cow<'a, [Occupancy]>,
+    /// Whether players wrap around the edges
+    pub wrap: bool,
+    /// The result for red, once the game is over
+    pub result: Option<WinState>,
+    /// What ended each player's game
+    pub end_reasons: RedBlue<Option<EndReason>>,
+}
+
+impl RenderData<'_> {
+    /// A placeholder pattern to show before the game starts.
+    pub fn game_start(width: usize, height: usize) -> RenderData<'static> {
+        let mut data = Vec::new();
+        for i in 0..(width * height) {
+            data.push(
+                [
+                    Occupancy::Occupied(Player::Red),
+                    Occupancy::Occupied(Player::Blue),
+                    Occupancy::Free,
+                ][i % 3],
+            )
+        }
+        RenderData {
+            width,
+            height,
+            data: data.into(),
+            wrap: false,
+            result: None,
+            end_reasons: RedBlue {
+                red: None,
+                blue: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WinState {
+    Win,
+    Loss,
+    Tie,
+}
+
+impl WinState {
+    /// The result for the other player.
+    pub fn inverse(self) -> Self {
+        use WinState::*;
+        match self {
+            Win => Loss,
+            Loss => Win,
+            Tie => Tie,
+        }
+    }
+}
+
+/// A rule variant the server announces before sending the first positions.
+/// None are sent for the standard rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+    /// `WRAP`: moving off an edge comes back in on the opposite one, rather
+    /// than crashing.
+    Wrap,
+    /// `WALLS` followed by the positions of the walls on the map, separated
+    /// by spaces.
+    Walls(Vec<usize>),
+    /// `SHRINK` followed by when the arena starts shrinking and how often,
+    /// as in `SHRINK 200 10`.
+    Shrink(ShrinkSchedule),
+}
+
+/// When the arena starts shrinking for sudden death, and how often. Each
+/// time, the outermost ring of cells that's still open turns into walls.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShrinkSchedule {
+    /// The outermost ring closes after this many turns
+    pub after: usize,
+    /// and the next one in after every this many more. Must not be 0.
+    pub every: usize,
+}
+
+impl ShrinkSchedule {
+    /// The ring, counting in from the edge from 0, that closes after this
+    /// many turns, if any.
+    pub fn ring_closing(&self, turns: usize) -> Option<usize> {
+        let since = turns.checked_sub(self.after)?;
+        if since.is_multiple_of(self.every) {
+            Some(since / self.every)
+        } else {
+            None
+        }
+    }
+}
+
+// What a player moving onto this cell landed on, or crashed into
+fn crash(cell: Occupancy, head_on: bool) -> Result<Occupancy, EndReason> {
+    match cell {
+        Occupancy::Wall => Err(EndReason::Wall),
+        _ if head_on => Err(EndReason::HeadOn),
+        Occupancy::Occupied(_) => Err(EndReason::Trail),
+        cell => Ok(cell),
+    }
+}
+
+// Turns a ring of cells, counting in from the edge, into walls
+fn close_ring(board: &mut [Occupancy], width: usize, ring: usize) {
+    let height = board.len() / width;
+    for (pos, cell) in board.iter_mut().enumerate() {
+        let (x, y) = (pos % width, pos / width);
+        if x.min(y).min(width - 1 - x).min(height - 1 - y) == ring {
+            *cell = Occupancy::Wall;
+        }
+    }
+}
+
+impl Rule {
+    /// Parses a line from the server, with or without its line feed.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut words = line.trim_end_matches('\n').split(' ');
+        match words.next()? {
+            "WRAP" => Some(Rule::Wrap),
+            "WALLS" => Some(Rule::Walls(
+                words.map(|w| w.parse().ok()).collect::<Option<_>>()?,
+            )),
+            "SHRINK" => {
+                let after = words.next()?.parse().ok()?;
+                // rings can't close more than once a turn
+                let every = words.next()?.parse().ok().filter(|&every| every > 0)?;
+                match words.next() {
+                    None => Some(Rule::Shrink(ShrinkSchedule { after, every })),
+                    Some(_) => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Formats the rule as sent, without its line feed.
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rule::Wrap => f.write_str("WRAP"),
+            Rule::Walls(walls) => {
+                f.write_str("WALLS")?;
+                walls.iter().try_for_each(|w| write!(f, " {}", w))
+            }
+            Rule::Shrink(shrink) => write!(f, "SHRINK {} {}", shrink.after, shrink.every),
+        }
+    }
+}
+
+/// Something a player picks up by moving onto it, with `--power-ups`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PowerUp {
+    /// Carries the player one more cell the same way, that same turn.
+    Boost,
+    /// Clears the trails from the 3x3 square around it, other than where
+    /// the players are.
+    Bomb,
+}
+
+/// What ended a player's game.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EndReason {
+    /// `TURN_LIMIT`: the game ran out of turns, and was decided by territory
+    /// in Tron or by length in snake mode.
+    TurnLimit,
+    /// `WALL`: the player ran into a wall or the edge of the board.
+    Wall,
+    /// `TRAIL`: the player ran into a trail, or a snake.
+    Trail,
+    /// `HEAD_ON`: both players moved into the same cell.
+    HeadOn,
+    /// The player forfeited: `TIMEOUT`, `PARSE_ERROR`, `DISCONNECTED`,
+    /// `WRITE_FAILURE`, `RESIGNED` or `ILLEGAL_MOVE`.
+    #[serde(untagged)]
+    Forfeit(ForfeitReason),
+}
+
+impl EndReason {
+    /// The code clients are sent, like `TURN_LIMIT`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EndReason::TurnLimit => "TURN_LIMIT",
+            EndReason::Wall => "WALL",
+            EndReason::Trail => "TRAIL",
+            EndReason::HeadOn => "HEAD_ON",
+            EndReason::Forfeit(ForfeitReason::Timeout) => "TIMEOUT",
+            EndReason::Forfeit(ForfeitReason::ParseError) => "PARSE_ERROR",
+            EndReason::Forfeit(ForfeitReason::Eof) => "DISCONNECTED",
+            EndReason::Forfeit(ForfeitReason::WriteFailure) => "WRITE_FAILURE",
+            EndReason::Forfeit(ForfeitReason::Resign) => "RESIGNED",
+            EndReason::Forfeit(ForfeitReason::RuleViolation) => "ILLEGAL_MOVE",
+        }
+    }
+
+    fn parse(word: &str) -> Option<Self> {
+        Some(match word {
+            "TURN_LIMIT" => EndReason::TurnLimit,
+            "WALL" => EndReason::Wall,
+            "TRAIL" => EndReason::Trail,
+            "HEAD_ON" => EndReason::HeadOn,
+            "TIMEOUT" => EndReason::Forfeit(ForfeitReason::Timeout),
+            "PARSE_ERROR" => EndReason::Forfeit(ForfeitReason::ParseError),
+            "DISCONNECTED" => EndReason::Forfeit(ForfeitReason::Eof),
+            "WRITE_FAILURE" => EndReason::Forfeit(ForfeitReason::WriteFailure),
+            "RESIGNED" => EndReason::Forfeit(ForfeitReason::Resign),
+            "ILLEGAL_MOVE" => EndReason::Forfeit(ForfeitReason::RuleViolation),
+            _ => return None,
+        })
+    }
+}
+
+// The result as told to each player, with what ended their own game, or
+// their opponent's if they won
+fn end_messages(win: WinState, reasons: RedBlue<Option<EndReason>>) -> RedBlue<ToClientMessage> {
+    RedBlue {
+        red: ToClientMessage::End(win, reasons.red.or(reasons.blue)),
+        blue: ToClientMessage::End(win.inverse(), reasons.blue.or(reasons.red)),
+    }
+}
+
+/// A line the server sends a client.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ToClientMessage {
+    /// The game is over: `WIN`, `LOSS` or `TIE`, followed by a space and
+    /// what ended the game if it's known, as in `WIN TURN_LIMIT`. That's what
+    /// ended the client's own game, or its opponent's if it won.
+    End(WinState, Option<EndReason>),
+    /// The client's position, then its opponent's.
+    Update(usize, usize),
+    /// The client's position, its opponent's, then the apple's, in snake
+    /// mode.
+    FoodUpdate(usize, usize, usize),
+    /// `BOOST` or `BOMB` and where it appeared, sent before the positions on
+    /// the turn it appears.
+    PowerUp(PowerUp, usize),
+}
+
+impl ToClientMessage {
+    /// Parses a line from the server, with or without its line feed.
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end_matches('\n');
+        let (first, reason) = match line.split_once(' ') {
+            Some((first, reason)) => match EndReason::parse(reason) {
+                Some(reason) => (first, Some(reason)),
+                None => (line, None),
+            },
+            None => (line, None),
+        };
+        Some(match first {
+            "WIN" => ToClientMessage::End(WinState::Win, reason),
+            "LOSS" => ToClientMessage::End(WinState::Loss, reason),
+            "TIE" => ToClientMessage::End(WinState::Tie, reason),
+            _ if line.starts_with("BOOST ") => {
+                ToClientMessage::PowerUp(PowerUp::Boost, line[6..].parse().ok()?)
+            }
+            _ if line.starts_with("BOMB ") => {
+                ToClientMessage::PowerUp(PowerUp::Bomb, line[5..].parse().ok()?)
+            }
+            _ => {
+                let mut positions = line.split(' ').map(|p| p.parse::<usize>().ok());
+                let mine = positions.next()??;
+                let theirs = positions.next()??;
+                match (positions.next(), positions.next()) {
+                    (None, _) => ToClientMessage::Update(mine, theirs),
+                    (Some(food), None) => ToClientMessage::FoodUpdate(mine, theirs, food?),
+                    (Some(_), Some(_)) => return None,
+                }
+            }
+        })
+    }
+}
+
+/// Formats the message as sent, without its line feed.
+impl fmt::Display for ToClientMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ToClientMessage::End(win, reason) => {
+                f.write_str(match win {
+                    WinState::Win => "WIN",
+                    WinState::Loss => "LOSS",
+                    WinState::Tie => "TIE",
+                })?;
+                match reason {
+                    Some(reason) => write!(f, " {}", reason.as_str()),
+                    None => Ok(()),
+                }
+            }
+            ToClientMessage::Update(mine, theirs) => write!(f, "{} {}", mine, theirs),
+            ToClientMessage::FoodUpdate(mine, theirs, food) => {
+                write!(f, "{} {} {}", mine, theirs, food)
+            }
+            ToClientMessage::PowerUp(PowerUp::Boost, pos) => write!(f, "BOOST {}", pos),
+            ToClientMessage::PowerUp(PowerUp::Bomb, pos) => write!(f, "BOMB {}", pos),
+        }
+    }
+}
+
+/// A message as sent to clients that speak version 2 of the protocol: the
+/// turn number goes in front of positions, as in `12 484 539`, and other
+/// messages are sent as they are.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Numbered(pub usize, pub ToClientMessage);
+
+impl fmt::Display for Numbered {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            ToClientMessage::Update(..) | ToClientMessage::FoodUpdate(..) => {
+                write!(f, "{} {}", self.0, self.1)
+            }
+            msg => msg.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    /// Parses the character a client sends for a move.
+    pub fn from_char(c: char) -> Option<Self> {
+        Some(match c {
+            'u' => Direction::Up,
+            'd' => Direction::Down,
+            'l' => Direction::Left,
+            'r' => Direction::Right,
+            _ => return None,
+        })
+    }
+
+    /// The character a client sends for this move.
+    pub fn to_char(self) -> char {
+        match self {
+            Direction::Up => 'u',
+            Direction::Down => 'd',
+            Direction::Left => 'l',
+            Direction::Right => 'r',
+        }
+    }
+}
+
+/// Every way a player can lose other than by crashing. Used for the engine's
+/// adjudication, the event log, and server logs alike.
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ForfeitReason {
+    #[error("client took too long to respond")]
+    Timeout,
+    #[error("client sent a malformed message")]
+    ParseError,
+    #[error("the client closed the connection")]
+    Eof,
+    #[error("the connection to the client failed")]
+    WriteFailure,
+    #[error("the client resigned")]
+    Resign,
+    /// A well-formed move that the rules don't allow, like turning straight
+    /// back the way the player came
+    #[error("the client made an illegal move")]
+    RuleViolation,
+}
+
+/// Rotates a position on a board of this many cells by half a turn.
+pub fn invert_pos(idx: usize, cells: usize) -> usize {
+    cells - idx - 1
+}
+
+/// Rotates a direction by half a turn.
+pub fn invert_direction(d: Direction) -> Direction {
+    use Direction::*;
+    match d {
+        Up => Down,
+        Down => Up,
+        Left => Right,
+        Right => Left,
+    }
+}
+
+/// Turns a message for red into the same message for blue, on a board of
+/// this many cells.
+pub fn invert_update(u: ToClientMessage, cells: usize) -> ToClientMessage {
+    match u {
+        ToClientMessage::End(x, reason) => ToClientMessage::End(x.inverse(), reason),
+        ToClientMessage::Update(mypos, theirpos) => {
+            ToClientMessage::Update(invert_pos(theirpos, cells), invert_pos(mypos, cells))
+        }
+        ToClientMessage::FoodUpdate(mypos, theirpos, food) => ToClientMessage::FoodUpdate(
+            invert_pos(theirpos, cells),
+            invert_pos(mypos, cells),
+            invert_pos(food, cells),
+        ),
+        ToClientMessage::PowerUp(kind, pos) => {
+            ToClientMessage::PowerUp(kind, invert_pos(pos, cells))
+        }
+    }
+}
+
+/// How many free cells each player can reach before the other, from the
+/// heads at `pos`. Cells both reach at the same time belong to neither.
+pub fn territory(
+    board: &[Occupancy],
+    width: usize,
+    wrap: bool,
+    pos: RedBlue<usize>,
+) -> RedBlue<usize> {
+    Bitboard::new(board, width, wrap).territory(pos)
+}
+
+/// How many free cells a player at `from` could reach, were the other player
+/// not in the way.
+pub fn reachable(board: &[Occupancy], width: usize, wrap: bool, from: usize) -> usize {
+    Bitboard::new(board, width, wrap).reachable(from)
+}
+
+/// The free cells of a board as bits, one per cell in the board's order, for
+/// flood fills that spread a whole frontier a word at a time rather than a
+/// cell at a time.
+#[derive(Debug, Clone)]
+pub struct Bitboard {
+    width: usize,
+    cells: usize,
+    wrap: bool,
+    free: Vec<u64>,
+    first_column: Vec<u64>,
+    last_column: Vec<u64>,
+}
+
+impl Bitboard {
+    pub fn new(board: &[Occupancy], width: usize, wrap: bool) -> Self {
+        let cells = board.len();
+        let words = cells.div_ceil(64);
+        let mut bitboard = Bitboard {
+            width,
+            cells,
+            wrap,
+            free: vec![0; words],
+            first_column: vec![0; words],
+            last_column: vec![0; words],
+        };
+        for (at, cell) in board.iter().enumerate() {
+            if !cell.occupied() {
+                set(&mut bitboard.free, at);
+            }
+            if at % width == 0 {
+                set(&mut bitboard.first_column, at);
+            }
+            if at % width == width - 1 {
+                set(&mut bitboard.last_column, at);
+            }
+        }
+        bitboard
+    }
+
+    /// How many free cells each player can reach before the other, as
+    /// [`territory`].
+    pub fn territory(&self, pos: RedBlue<usize>) -> RedBlue<usize> {
+        let words = self.free.len();
+        let mut seen = vec![0; words];
+        set(&mut seen, pos.red);
+        set(&mut seen, pos.blue);
+        let mut red = vec![0; words];
+        set(&mut red, pos.red);
+        let mut blue = vec![0; words];
+        set(&mut blue, pos.blue);
+        let (mut next_red, mut next_blue) = (vec![0; words], vec![0; words]);
+        let mut scratch = vec![0; words];
+        let mut counts = RedBlue { red: 0, blue: 0 };
+        loop {
+            self.spread(&red, &mut next_red, &mut scratch);
+            self.spread(&blue, &mut next_blue, &mut scratch);
+            let mut spreading = false;
+            for i in 0..words {
+                let (r, b) = (next_red[i] & !seen[i], next_blue[i] & !seen[i]);
+                // cells both reach at once belong to neither, and stop there
+                let both = r & b;
+                red[i] = r & !both;
+                blue[i] = b & !both;
+                seen[i] |= r | b;
+                counts.red += red[i].count_ones() as usize;
+                counts.blue += blue[i].count_ones() as usize;
+                spreading |= red[i] | blue[i] != 0;
+            }
+            if !spreading {
+                return counts;
+            }
+        }
+    }
+
+    /// How many free cells a player at `from` could reach, as [`reachable`].
+    pub fn reachable(&self, from: usize) -> usize {
+        let words = self.free.len();
+        let mut seen = vec![0; words];
+        set(&mut seen, from);
+        let mut frontier = seen.clone();
+        let mut next = vec![0; words];
+        let mut scratch = vec![0; words];
+        let mut count = 0;
+        loop {
+            self.spread(&frontier, &mut next, &mut scratch);
+            let mut spreading = false;
+            for i in 0..words {
+                next[i] &= !seen[i];
+                seen[i] |= next[i];
+                count += next[i].count_ones() as usize;
+                spreading |= next[i] != 0;
+            }
+            if !spreading {
+                return count;
+            }
+            std::mem::swap(&mut frontier, &mut next);
+        }
+    }
+
+    // the free cells one step from any in `from`
+    fn spread(&self, from: &[u64], to: &mut [u64], scratch: &mut [u64]) {
+        let (width, cells) = (self.width, self.cells);
+        to.fill(0);
+        or_forward(to, from, width);
+        or_back(to, from, width);
+        if self.wrap {
+            // off the bottom onto the top, and off the top onto the bottom
+            or_back(to, from, cells - width);
+            or_forward(to, from, cells - width);
+        }
+        for (s, (f, edge)) in scratch.iter_mut().zip(from.iter().zip(&self.last_column)) {
+            *s = f & !edge;
+        }
+        or_forward(to, scratch, 1);
+        if self.wrap {
+            for (s, (f, edge)) in scratch.iter_mut().zip(from.iter().zip(&self.last_column)) {
+                *s = f & edge;
+            }
+            or_back(to, scratch, width - 1);
+        }
+        for (s, (f, edge)) in scratch.iter_mut().zip(from.iter().zip(&self.first_column)) {
+            *s = f & !edge;
+        }
+        or_back(to, scratch, 1);
+        if self.wrap {
+            for (s, (f, edge)) in scratch.iter_mut().zip(from.iter().zip(&self.first_column)) {
+                *s = f & edge;
+            }
+            or_forward(to, scratch, width - 1);
+        }
+        // which also drops anything moved past the last cell
+        for (t, free) in to.iter_mut().zip(&self.free) {
+            *t &= free;
+        }
+    }
+}
+
+fn set(bits: &mut [u64], at: usize) {
+    bits[at / 64] |= 1 << (at % 64);
+}
+
+// adds the bits moved n cells later in the board's order to `to`
+fn or_forward(to: &mut [u64], bits: &[u64], n: usize) {
+    let (words, shift) = (n / 64, n % 64);
+    for (i, word) in to.iter_mut().enumerate().skip(words) {
+        let j = i - words;
+        *word |= bits[j] << shift;
+        if shift > 0 && j > 0 {
+            *word |= bits[j - 1] >> (64 - shift);
+        }
+    }
+}
+
+// adds the bits moved n cells earlier in the board's order to `to`
+fn or_back(to: &mut [u64], bits: &[u64], n: usize) {
+    let (words, shift) = (n / 64, n % 64);
+    for (i, word) in to.iter_mut().enumerate() {
+        let j = i + words;
+        if j >= bits.len() {
+            break;
+        }
+        *word |= bits[j] >> shift;
+        if shift > 0 && j + 1 < bits.len() {
+            *word |= bits[j + 1] << (64 - shift);
+        }
+    }
+}
+
+/// One step from `pos` in direction `d`, wrapping around the edges of the
+/// board.
+pub fn step_pos(width: usize, cells: usize, pos: usize, d: Direction) -> usize {
+    use Direction::*;
+    match d {
+        Up => (pos + cells - width) % cells,
+        Down => (pos + width) % cells,
+        Left if pos.is_multiple_of(width) => pos + width - 1,
+        Left => pos - 1,
+        Right if pos % width == width - 1 => pos + 1 - width,
+        Right => pos + 1,
+    }
+}
+
+fn hits_wall(width: usize, cells: usize, pos: usize, d: Direction) -> bool {
+    use Direction::*;
+    match d {
+        Up => pos < width,
+        Down => pos >= cells - width,
+        Left => pos.is_multiple_of(width),
+        Right => pos % width == width - 1,
+    }
+}
+
+/// Where red and blue start on a board this size: just above the middle row,
+/// an eighth of the way in from the left for red, and the same from the
+/// bottom right for blue.
+pub fn start_positions(width: usize, height: usize) -> RedBlue<usize> {
+    let red = (height / 2 - 1) * width + width / 8;
+    RedBlue {
+        red,
+        blue: invert_pos(red, width * height),
+    }
+}
+
+fn announced_rules(
+    wrap: bool,
+    walls: &[usize],
+    shrink: Option<ShrinkSchedule>,
+    cells: usize,
+) -> RedBlue<Vec<Rule>> {
+    let mut rules = RedBlue {
+        red: Vec::new(),
+        blue: Vec::new(),
+    };
+    if wrap {
+        rules.red.push(Rule::Wrap);
+        rules.blue.push(Rule::Wrap);
+    }
+    if !walls.is_empty() {
+        rules.red.push(Rule::Walls(walls.to_vec()));
+        let inverted = walls.iter().rev().map(|&w| invert_pos(w, cells));
+        rules.blue.push(Rule::Walls(inverted.collect()));
+    }
+    if let Some(shrink) = shrink {
+        rules.red.push(Rule::Shrink(shrink));
+        rules.blue.push(Rule::Shrink(shrink));
+    }
+    rules
+}
+
+// The result for red if either player forfeited
+fn forfeit_result(reasons: RedBlue<Option<ForfeitReason>>) -> Option<WinState> {
+    match (reasons.red.is_some(), reasons.blue.is_some()) {
+        (true, true) => Some(WinState::Tie),
+        (false, false) => None,
+        (true, false) => Some(WinState::Loss),
+        (false, true) => Some(WinState::Win),
+    }
+}
+#[test]
+fn inversions() {
+    for i in 0..(32 * 32) {
+        assert_eq!(i, invert_pos(invert_pos(i, 1024), 1024));
+    }
+    assert_eq!(1023, invert_pos(0, 1024));
+    assert_eq!(992, invert_pos(31, 1024));
+    assert_eq!(34, invert_pos(989, 1024));
+    assert_eq!(539, invert_pos(484, 1024));
+    // 12 wide, 5 high: the top right corner goes to the bottom left
+    assert_eq!(48, invert_pos(11, 60));
+    assert_eq!(23, invert_pos(36, 60));
+}
+
+#[test]
+fn boundaries() {
+    use Direction::*;
+    let game = TronGame::new(32, 32);
+    let last = 32 * 32 - 1;
+    for (pos, d) in [
+        (0, Up),
+        (0, Left),
+        (last, Down),
+        (last, Right),
+        (last - 31, Down),
+    ]
+    .iter()
+    {
+        assert!(game.boundary_collision(*pos, *d), "{} {:?}", pos, d);
+    }
+    for (pos, d) in [(0, Down), (0, Right), (last, Up), (last - 32, Down)].iter() {
+        assert!(!game.boundary_collision(*pos, *d), "{} {:?}", pos, d);
+    }
+    // 12 wide, 5 high
+    let game = TronGame::new(12, 5);
+    for (pos, d) in [(11, Up), (11, Right), (12, Left), (48, Down), (59, Right)].iter() {
+        assert!(game.boundary_collision(*pos, *d), "{} {:?}", pos, d);
+    }
+    for (pos, d) in [(11, Down), (12, Right), (47, Down), (48, Up), (5, Left)].iter() {
+        assert!(!game.boundary_collision(*pos, *d), "{} {:?}", pos, d);
+    }
+    // and wrapping around its edges
+    let mut game = TronGame::new(12, 5);
+    game.set_wrap(true);
+    for (pos, d, to) in [(11, Up, 59), (11, Right, 0), (12, Left, 23), (48, Down, 0)].iter() {
+        assert!(!game.boundary_collision(*pos, *d), "{} {:?}", pos, d);
+        assert_eq!(*to, game.advance(*pos, *d), "{} {:?}", pos, d);
+    }
+}
+
+#[test]
+fn head_swaps() {
+    use Direction::*;
+    // red starts at 0 and blue at 13, and they meet in the middle
+    let mut game = TronGame::new(7, 2);
+    for &(red, blue) in [(Down, Left), (Right, Left), (Right, Left)].iter() {
+        game.observe(RedBlue { red, blue });
+    }
+    assert_eq!((9, 10), (game.pos.red, game.pos.blue));
+    assert!(!game.game_over());
+    // passing through each other crashes them both
+    let msg = game.observe(RedBlue {
+        red: Right,
+        blue: Left,
+    });
+    assert_eq!(
+        ToClientMessage::End(WinState::Tie, Some(EndReason::Trail)),
+        msg
+    );
+    // and meeting in the same cell is head on. Red starts at 1 and blue at 14
+    let mut game = TronGame::new(8, 2);
+    for &(red, blue) in [(Down, Left), (Right, Left)].iter() {
+        game.observe(RedBlue { red, blue });
+    }
+    let msg = game.observe(RedBlue {
+        red: Right,
+        blue: Left,
+    });
+    assert_eq!(
+        ToClientMessage::End(WinState::Tie, Some(EndReason::HeadOn)),
+        msg
+    );
+    // but not when one of them went off the edge, into the cell the other
+    // moved to on the far side
+    let mut game = TronGame::new(8, 2);
+    game.observe(RedBlue {
+        red: Left,
+        blue: Right,
+    });
+    game.observe(RedBlue {
+        red: Left,
+        blue: Up,
+    });
+    assert_eq!(Some(WinState::Loss), game.win_state());
+    let reasons = game.end_reasons();
+    assert_eq!((Some(EndReason::Wall), None), (reasons.red, reasons.blue));
+}
+
+#[test]
+fn sudden_death() {
+    use Direction::*;
+    let mut game = TronGame::new(8, 8);
+    game.set_shrink(Some(ShrinkSchedule { after: 2, every: 2 }));
+    let closing: Vec<_> = (0..7)
+        .map(|t| game.shrink.unwrap().ring_closing(t))
+        .collect();
+    assert_eq!(
+        vec![None, None, Some(0), None, Some(1), None, Some(2)],
+        closing
+    );
+    // red starts at 25, and blue at 38
+    for &red in [Left, Up].iter() {
+        game.observe(RedBlue { red, blue: Left });
+    }
+    for &wall in [0, 7, 16, 56, 63].iter() {
+        assert_eq!(Occupancy::Wall, game.board[wall], "{}", wall);
+    }
+    assert_eq!(Occupancy::Free, game.board[9]);
+    assert!(!game.game_over());
+    // red is on the edge when it closes, and can't keep going along it
+    game.observe(RedBlue {
+        red: Up,
+        blue: Left,
+    });
+    assert_eq!(Some(WinState::Loss), game.win_state());
+}
+
+#[test]
+fn power_ups() {
+    use Direction::*;
+    // red starts at 25, and blue at 38
+    let mut game = TronGame::new(8, 8);
+    game.board[26] = Occupancy::PowerUp(PowerUp::Boost);
+    game.board[37] = Occupancy::PowerUp(PowerUp::Bomb);
+    game.board[44] = Occupancy::Occupied(Player::Red);
+    game.observe(RedBlue {
+        red: Right,
+        blue: Left,
+    });
+    assert_eq!(27, game.pos.red);
+    assert_eq!(Occupancy::Occupied(Player::Red), game.board[26]);
+    // the bomb cleared the trails around it, but not blue
+    for &pos in [28, 29, 38, 44, 45].iter() {
+        assert_eq!(Occupancy::Free, game.board[pos], "{}", pos);
+    }
+    assert_eq!(Occupancy::Occupied(Player::Blue), game.board[37]);
+    game.board[28] = Occupancy::PowerUp(PowerUp::Boost);
+    for &to in [29, 30].iter() {
+        game.observe(RedBlue {
+            red: Right,
+            blue: Down,
+        });
+        assert_eq!(to, game.pos.red);
+        assert!(!game.game_over());
+    }
+    // a boost doesn't get you through a wall
+    game.board[31] = Occupancy::PowerUp(PowerUp::Boost);
+    game.observe(RedBlue {
+        red: Right,
+        blue: Down,
+    });
+    assert_eq!(Some(WinState::Loss), game.win_state());
+}
+
+#[test]
+fn turn_limit() {
+    use Direction::*;
+    // red starts at 25, and blue at 38
+    let mut game = TronGame::new(8, 8);
+    // red's side is walled off into the left three columns
+    for pos in (2..64).step_by(8) {
+        game.board[pos] = Occupancy::Wall;
+    }
+    let territory = game.territory();
+    assert_eq!((15, 39), (territory.red, territory.blue));
+    assert_eq!(15, reachable(&game.board, 8, false, 25));
+    assert_eq!(39, reachable(&game.board, 8, false, 38));
+    game.set_max_turns(Some(2));
+    game.observe(RedBlue {
+        red: Up,
+        blue: Left,
+    });
+    assert!(!game.game_over());
+    let msg = game.observe(RedBlue {
+        red: Up,
+        blue: Left,
+    });
+    assert_eq!(
+        ToClientMessage::End(WinState::Loss, Some(EndReason::TurnLimit)),
+        msg
+    );
+    // a neck and neck race for the middle
+    let game = TronGame::new(7, 4);
+    let territory = game.territory();
+    assert_eq!(territory.red, territory.blue);
+}
+
+#[test]
+fn flood_fills() {
+    // 0 1 2 3
+    // 4 5 6 7
+    let board = [Occupancy::Free; 8];
+    let pos = RedBlue { red: 0, blue: 2 };
+    // 1 and 5 are reached by both at once
+    let areas = territory(&board, 4, false, pos);
+    assert_eq!((1, 3), (areas.red, areas.blue));
+    // and 3 and 7 too, around the edges
+    let areas = territory(&board, 4, true, pos);
+    assert_eq!((1, 1), (areas.red, areas.blue));
+    assert_eq!(7, reachable(&board, 4, false, 0));
+
+    // frontiers that cross words, and walls that split the board
+    let mut board = vec![Occupancy::Free; 20 * 10];
+    for row in 0..10 {
+        board[row * 20 + 9] = Occupancy::Wall;
+    }
+    assert_eq!(89, reachable(&board, 20, false, 0));
+    assert_eq!(189, reachable(&board, 20, true, 0));
+    let areas = territory(&board, 20, false, RedBlue { red: 0, blue: 199 });
+    assert_eq!((89, 99), (areas.red, areas.blue));
+}
+
+#[test]
+fn messages() {
+    for msg in [
+        ToClientMessage::Update(484, 539),
+        ToClientMessage::FoodUpdate(484, 539, 0),
+        ToClientMessage::PowerUp(PowerUp::Boost, 17),
+        ToClientMessage::PowerUp(PowerUp::Bomb, 0),
+        ToClientMessage::End(WinState::Win, None),
+        ToClientMessage::End(WinState::Loss, None),
+        ToClientMessage::End(WinState::Tie, None),
+        ToClientMessage::End(WinState::Win, Some(EndReason::TurnLimit)),
+        ToClientMessage::End(WinState::Loss, Some(EndReason::HeadOn)),
+        ToClientMessage::End(WinState::Win, Some(EndReason::Forfeit(ForfeitReason::Eof))),
+    ]
+    .iter()
+    {
+        assert_eq!(Some(*msg), ToClientMessage::parse(&format!("{}\n", msg)));
+    }
+    assert_eq!(None, ToClientMessage::parse("484\n"));
+    assert_eq!(None, ToClientMessage::parse("484 539 0 1\n"));
+    let numbered = |msg| Numbered(12, msg).to_string();
+    assert_eq!("12 484 539", numbered(ToClientMessage::Update(484, 539)));
+    assert_eq!(
+        "12 484 539 0",
+        numbered(ToClientMessage::FoodUpdate(484, 539, 0))
+    );
+    assert_eq!("TIE", numbered(ToClientMessage::End(WinState::Tie, None)));
+    let shrink = ShrinkSchedule {
+        after: 200,
+        every: 10,
+    };
+    for rule in [Rule::Wrap, Rule::Walls(vec![0, 1023]), Rule::Shrink(shrink)].iter() {
+        assert_eq!(Some(rule.clone()), Rule::parse(&format!("{}\n", rule)));
+    }
+    assert_eq!(None, Rule::parse("WALLS 1 x\n"));
+    assert_eq!(None, Rule::parse("SHRINK 100 0\n"));
+    assert_eq!(None, ToClientMessage::parse("win\n"));
+}
+
+/// One value for each player.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct RedBlue<T> {
+    pub red: T,
+    pub blue: T,
+}
+
+impl<T> RedBlue<T> {
+    pub fn map<U, F>(self, mut op: F) -> RedBlue<U>
+    where
+        F: FnMut(T) -> U,
+    {
+        RedBlue {
+            red: op(self.red),
+            blue: op(self.blue),
+        }
+    }
+
+    pub fn as_ref(&self) -> RedBlue<&T> {
+        RedBlue {
+            red: &self.red,
+            blue: &self.blue,
+        }
+    }
+}
+
+/// A two-player game the server can host. Players take their turns at the
+/// same time, each sending a [`Direction`] and being sent a
+/// [`ToClientMessage`], so every game shares the same protocol. Results are
+/// from red's point of view.
+pub trait Game {
+    /// Turns moves as the players sent them into moves as seen on the board.
+    fn board_moves(&self, moves: RedBlue<Direction>) -> RedBlue<Direction>;
+
+    /// Plays one turn, with moves as seen on the board. Does nothing once
+    /// the game is over.
+    fn step(&mut self, moves: RedBlue<Direction>);
+
+    /// What to send each player about the game as it stands.
+    fn messages(&self) -> RedBlue<ToClientMessage>;
+
+    /// The board, for the visualizer.
+    fn render_data(&self) -> RenderData<'_>;
+
+    /// The result, once the game is over.
+    fn win_state(&self) -> Option<WinState>;
+
+    /// Ends the game with this result.
+    fn set_win_state(&mut self, w: WinState);
+
+    /// Ends the game if either player forfeited.
+    fn forfeit(&mut self, reasons: RedBlue<Option<ForfeitReason>>);
+
+    fn forfeits(&self) -> RedBlue<Option<ForfeitReason>>;
+
+    /// What ended each player's game: both players' on a tie, or only the
+    /// loser's. None for an unfinished game, or one the server called off.
+    fn end_reasons(&self) -> RedBlue<Option<EndReason>>;
+
+    /// Each player's move on the last turn as they sent it, from their own
+    /// side of the board. None before the first turn.
+    fn previous_moves(&self) -> RedBlue<Option<Direction>>;
+
+    /// How many turns have been played.
+    fn turns(&self) -> usize;
+
+    /// Anything to tell each player before this turn's update, like
+    /// power-ups that appeared.
+    fn notices(&self) -> RedBlue<Vec<ToClientMessage>> {
+        RedBlue {
+            red: Vec::new(),
+            blue: Vec::new(),
+        }
+    }
+
+    /// The rule variants to announce to each player before the game starts.
+    fn rules(&self) -> RedBlue<Vec<Rule>> {
+        RedBlue {
+            red: Vec::new(),
+            blue: Vec::new(),
+        }
+    }
+
+    fn game_over(&self) -> bool {
+        self.win_state().is_some()
+    }
+}
+
+// lets the server pick a game at runtime
+impl<G: Game + ?Sized> Game for Box<G> {
+    fn board_moves(&self, moves: RedBlue<Direction>) -> RedBlue<Direction> {
+        (**self).board_moves(moves)
+    }
+
+    fn step(&mut self, moves: RedBlue<Direction>) {
+        (**self).step(moves)
+    }
+
+    fn messages(&self) -> RedBlue<ToClientMessage> {
+        (**self).messages()
+    }
+
+    fn render_data(&self) -> RenderData<'_> {
+        (**self).render_data()
+    }
+
+    fn win_state(&self) -> Option<WinState> {
+        (**self).win_state()
+    }
+
+    fn set_win_state(&mut self, w: WinState) {
+        (**self).set_win_state(w)
+    }
+
+    fn forfeit(&mut self, reasons: RedBlue<Option<ForfeitReason>>) {
+        (**self).forfeit(reasons)
+    }
+
+    fn forfeits(&self) -> RedBlue<Option<ForfeitReason>> {
+        (**self).forfeits()
+    }
+
+    fn end_reasons(&self) -> RedBlue<Option<EndReason>> {
+        (**self).end_reasons()
+    }
+
+    fn previous_moves(&self) -> RedBlue<Option<Direction>> {
+        (**self).previous_moves()
+    }
+
+    fn turns(&self) -> usize {
+        (**self).turns()
+    }
+
+    fn notices(&self) -> RedBlue<Vec<ToClientMessage>> {
+        (**self).notices()
+    }
+
+    fn rules(&self) -> RedBlue<Vec<Rule>> {
+        (**self).rules()
+    }
+}
+
+/// A game of Tron light cycles. Red is always the "main" player: positions,
+/// directions and results are all from red's point of view.
+pub struct TronGame {
+    width: usize,
+    height: usize,
+    board: Vec<Occupancy>,
+    pos: RedBlue<usize>,
+    wrap: bool,
+    walls: Vec<usize>,
+    shrink: Option<ShrinkSchedule>,
+    // turns between power-ups appearing
+    power_ups: Option<usize>,
+    // the ones that appeared on the last turn
+    spawned: Vec<(PowerUp, usize)>,
+    // where and which power-ups appear
+    rng: StdRng,
+    turn: usize,
+    max_turns: Option<usize>,
+    // as seen on the board
+    last_moves: RedBlue<Option<Direction>>,
+    endgame: Option<WinState>,
+    end_reasons: RedBlue<Option<EndReason>>,
+    forfeits: RedBlue<Option<ForfeitReason>>,
+}
+
+impl TronGame {
+    /// Starts a game on an empty board. Both dimensions must be at least 2.
+    pub fn new(width: usize, height: usize) -> Self {
+        Self::seeded(width, height, rand::random())
+    }
+
+    /// Starts a game whose power-ups appear just as they did in another game
+    /// with the same seed and moves.
+    pub fn seeded(width: usize, height: usize, seed: u64) -> Self {
+        let mut board = vec![Occupancy::Free; width * height];
+        let pos = start_positions(width, height);
+        board[pos.red] = Occupancy::Occupied(Player::Red);
+        board[pos.blue] = Occupancy::Occupied(Player::Blue);
+        Self {
+            width,
+            height,
+            pos,
+            wrap: false,
+            walls: Vec::new(),
+            shrink: None,
+            power_ups: None,
+            spawned: Vec::new(),
+            rng: StdRng::seed_from_u64(seed),
+            turn: 0,
+            max_turns: None,
+            last_moves: RedBlue {
+                red: None,
+                blue: None,
+            },
+            endgame: None,
+            end_reasons: RedBlue {
+                red: None,
+                blue: None,
+            },
+            forfeits: RedBlue {
+                red: None,
+                blue: None,
+            },
+            board,
+        }
+    }
+
+    /// Plays one turn, and returns the message for red. Blue's move must
+    /// already be inverted to red's point of view.
+    pub fn observe(&mut self, moves: RedBlue<Direction>) -> ToClientMessage {
+        if self.endgame.is_some() {
+            return self.messages().red;
+        }
+
+        self.spawned.clear();
+        self.last_moves = moves.map(Some);
+        let first = self.move_players(moves.map(Some));
+        // a boost carries a player one more cell the same way
+        let boosted = |landed| landed == Ok(Occupancy::PowerUp(PowerUp::Boost));
+        let mut bombs = self.bombs(first);
+        let second = if boosted(first.red) || boosted(first.blue) {
+            let second = self.move_players(RedBlue {
+                red: Some(moves.red).filter(|_| boosted(first.red)),
+                blue: Some(moves.blue).filter(|_| boosted(first.blue)),
+            });
+            bombs.extend(self.bombs(second));
+            second
+        } else {
+            first
+        };
+        for bomb in bombs {
+            self.detonate(bomb);
+        }
+
+        self.end_reasons = RedBlue {
+            red: first.red.err().or(second.red.err()),
+            blue: first.blue.err().or(second.blue.err()),
+        };
+        self.endgame = match (self.end_reasons.red, self.end_reasons.blue) {
+            (Some(_), Some(_)) => Some(WinState::Tie),
+            (None, None) => None,
+            (Some(_), None) => Some(WinState::Loss),
+            (None, Some(_)) => Some(WinState::Win),
+        };
+        self.turn += 1;
+        if let Some(ring) = self.shrink.and_then(|s| s.ring_closing(self.turn)) {
+            close_ring(&mut self.board, self.width, ring);
+        }
+        if let Some(every) = self.power_ups {
+            if self.endgame.is_none() && self.turn.is_multiple_of(every) {
+                self.spawn_power_ups();
+            }
+        }
+        if self.endgame.is_none() && Some(self.turn) == self.max_turns {
+            self.endgame = Some(self.by_territory());
+            self.end_reasons = RedBlue {
+                red: Some(EndReason::TurnLimit),
+                blue: Some(EndReason::TurnLimit),
+            };
+        }
+        self.messages().red
+    }
+
+    // Moves the players that have a move, and returns what each landed on, or
+    // what it crashed into. Players without a move stay put.
+    fn move_players(
+        &mut self,
+        moves: RedBlue<Option<Direction>>,
+    ) -> RedBlue<Result<Occupancy, EndReason>> {
+        let red_boundary = moves
+            .red
+            .is_some_and(|d| self.boundary_collision(self.pos.red, d));
+        let blue_boundary = moves
+            .blue
+            .is_some_and(|d| self.boundary_collision(self.pos.blue, d));
+
+        if let Some(d) = moves.red {
+            self.pos.red = self.advance(self.pos.red, d);
+        }
+        if let Some(d) = moves.blue {
+            self.pos.blue = self.advance(self.pos.blue, d);
+        }
+
+        // if we didnt check this, who won would depend on update order.
+        // Players that swap cells don't need checking: each lands on the
+        // other's trail, so both crash. A player that went off the edge was
+        // never in the cell its position wrapped around to.
+        let head_on = moves.red.is_some()
+            && moves.blue.is_some()
+            && !red_boundary
+            && !blue_boundary
+            && self.pos.red == self.pos.blue;
+
+        let mut landed = RedBlue {
+            red: Ok(Occupancy::Free),
+            blue: Ok(Occupancy::Free),
+        };
+        if moves.red.is_some() {
+            landed.red = Err(EndReason::Wall);
+            if !red_boundary {
+                landed.red = crash(self.board[self.pos.red], head_on);
+                self.board[self.pos.red] = Occupancy::Occupied(Player::Red);
+            }
+        }
+        if moves.blue.is_some() {
+            landed.blue = Err(EndReason::Wall);
+            if !blue_boundary {
+                landed.blue = crash(self.board[self.pos.blue], head_on);
+                self.board[self.pos.blue] = Occupancy::Occupied(Player::Blue);
+            }
+        }
+        landed
+    }
+
+    // Where the players picked up bombs, given what they landed on
+    fn bombs(&self, landed: RedBlue<Result<Occupancy, EndReason>>) -> Vec<usize> {
+        let bomb = Ok(Occupancy::PowerUp(PowerUp::Bomb));
+        let mut bombs = Vec::new();
+        if landed.red == bomb {
+            bombs.push(self.pos.red);
+        }
+        if landed.blue == bomb {
+            bombs.push(self.pos.blue);
+        }
+        bombs
+    }
+
+    // Clears the trails around a bomb, leaving the players' heads
+    fn detonate(&mut self, bomb: usize) {
+        let (x, y) = (bomb % self.width, bomb / self.width);
+        for cy in y.saturating_sub(1)..(y + 2).min(self.height) {
+            for cx in x.saturating_sub(1)..(x + 2).min(self.width) {
+                let pos = cy * self.width + cx;
+                if pos != self.pos.red
+                    && pos != self.pos.blue
+                    && matches!(self.board[pos], Occupancy::Occupied(_))
+                {
+                    self.board[pos] = Occupancy::Free;
+                }
+            }
+        }
+    }
+
+    // Puts a random power-up on a random free cell, and the same on the cell
+    // opposite it, so neither player is favored
+    fn spawn_power_ups(&mut self) {
+        let cells = self.board.len();
+        let free = |pos: usize| self.board[pos] == Occupancy::Free;
+        let spots: Vec<usize> = (0..cells / 2)
+            .filter(|&pos| free(pos) && free(invert_pos(pos, cells)))
+            .collect();
+        let pos = match spots.choose(&mut self.rng) {
+            Some(&pos) => pos,
+            None => return,
+        };
+        let kind = *[PowerUp::Boost, PowerUp::Bomb]
+            .choose(&mut self.rng)
+            .unwrap();
+        for pos in [pos, invert_pos(pos, cells)] {
+            self.board[pos] = Occupancy::PowerUp(kind);
+            self.spawned.push((kind, pos));
+        }
+    }
+
+    /// The current positions, as a message for red.
+    pub fn position_update(&self) -> ToClientMessage {
+        ToClientMessage::Update(self.pos.red, self.pos.blue)
+    }
+
+    /// Lets players wrap around the edges of the board instead of crashing
+    /// into them.
+    pub fn set_wrap(&mut self, wrap: bool) {
+        self.wrap = wrap;
+    }
+
+    /// Puts the walls from a map on the board. The map must be the same size
+    /// as the board.
+    pub fn add_map(&mut self, map: &Map) {
+        for &wall in &map.walls {
+            self.board[wall] = Occupancy::Wall;
+        }
+        self.walls.extend_from_slice(&map.walls);
+    }
+
+    /// Shrinks the arena on this schedule, for sudden death.
+    pub fn set_shrink(&mut self, shrink: Option<ShrinkSchedule>) {
+        self.shrink = shrink;
+    }
+
+    /// Puts a pair of power-ups on the board every this many turns.
+    pub fn set_power_ups(&mut self, every: Option<usize>) {
+        self.power_ups = every;
+    }
+
+    /// Ends the game after this many turns, and gives it to whoever has more
+    /// [`territory`](Self::territory).
+    pub fn set_max_turns(&mut self, max_turns: Option<usize>) {
+        self.max_turns = max_turns;
+    }
+
+    /// How many free cells each player can reach before the other.
+    pub fn territory(&self) -> RedBlue<usize> {
+        territory(&self.board, self.width, self.wrap, self.pos)
+    }
+
+    // whoever has more territory wins
+    fn by_territory(&self) -> WinState {
+        let territory = self.territory();
+        if territory.red > territory.blue {
+            WinState::Win
+        } else if territory.red < territory.blue {
+            WinState::Loss
+        } else {
+            WinState::Tie
+        }
+    }
+
+    /// The position one step from `pos`, wrapping around the edges. Only
+    /// meaningful if moving that way isn't a
+    /// [`boundary_collision`](Self::boundary_collision).
+    pub fn advance(&self, pos: usize, d: Direction) -> usize {
+        step_pos(self.width, self.board.len(), pos, d)
+    }
+
+    /// Whether moving from `pos` in direction `d` runs into a wall.
+    pub fn boundary_collision(&self, pos: usize, d: Direction) -> bool {
+        !self.wrap && hits_wall(self.width, self.board.len(), pos, d)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The number of cells on the board.
+    pub fn cells(&self) -> usize {
+        self.board.len()
+    }
+
+    pub fn board(&self) -> &[Occupancy] {
+        &self.board
+    }
+
+    pub fn positions(&self) -> RedBlue<usize> {
+        self.pos
+    }
+}
+
+impl Game for TronGame {
+    fn board_moves(&self, moves: RedBlue<Direction>) -> RedBlue<Direction> {
+        RedBlue {
+            red: moves.red,
+            blue: invert_direction(moves.blue),
+        }
+    }
+
+    fn step(&mut self, moves: RedBlue<Direction>) {
+        self.observe(moves);
+    }
+
+    fn messages(&self) -> RedBlue<ToClientMessage> {
+        if let Some(win) = self.endgame {
+            return end_messages(win, self.end_reasons);
+        }
+        let red = self.position_update();
+        RedBlue {
+            red,
+            blue: invert_update(red, self.cells()),
+        }
+    }
+
+    fn render_data(&self) -> RenderData<'_> {
+        RenderData {
+            width: self.width,
+            height: self.height,
+            data: Cow::Borrowed(&self.board),
+            wrap: self.wrap,
+            result: self.endgame,
+            end_reasons: self.end_reasons,
+        }
+    }
+
+    fn win_state(&self) -> Option<WinState> {
+        self.endgame
+    }
+
+    fn set_win_state(&mut self, w: WinState) {
+        self.endgame = Some(w);
+        self.end_reasons = RedBlue {
+            red: None,
+            blue: None,
+        };
+    }
+
+    fn forfeit(&mut self, reasons: RedBlue<Option<ForfeitReason>>) {
+        if let Some(win) = forfeit_result(reasons) {
+            self.set_win_state(win);
+            self.end_reasons = reasons.map(|reason| reason.map(EndReason::Forfeit));
+            self.forfeits = reasons;
+        }
+    }
+
+    fn forfeits(&self) -> RedBlue<Option<ForfeitReason>> {
+        self.forfeits
+    }
+
+    fn end_reasons(&self) -> RedBlue<Option<EndReason>> {
+        self.end_reasons
+    }
+
+    fn previous_moves(&self) -> RedBlue<Option<Direction>> {
+        RedBlue {
+            red: self.last_moves.red,
+            blue: self.last_moves.blue.map(invert_direction),
+        }
+    }
+
+    fn turns(&self) -> usize {
+        self.turn
+    }
+
+    fn notices(&self) -> RedBlue<Vec<ToClientMessage>> {
+        let red: Vec<_> = self
+            .spawned
+            .iter()
+            .map(|&(kind, pos)| ToClientMessage::PowerUp(kind, pos))
+            .collect();
+        RedBlue {
+            blue: red
+                .iter()
+                .map(|&m| invert_update(m, self.cells()))
+                .collect(),
+            red,
+        }
+    }
+
+    fn rules(&self) -> RedBlue<Vec<Rule>> {
+        announced_rules(self.wrap, &self.walls, self.shrink, self.board.len())
+    }
+}
@@ -1,155 +1,851 @@
-use futures::{Stream, StreamExt};
+use futures::{FutureExt, Stream, StreamExt};
 use lazy_static::lazy_static;
-use serde::Serialize;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::convert::Infallible;
 use std::fmt::Write as _;
+use std::fs::File;
+use std::future::Future;
 use std::io;
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
-use std::thread;
+use std::net::{IpAddr, TcpListener};
+use std::panic;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 use std::time;
 use std::time::Duration;
 use structopt::StructOpt;
-use thiserror::Error;
-use tokio;
-use tokio::sync::watch;
-use warp;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::Layer;
+
+mod analyze;
+mod anonymize;
+mod bench;
+mod diff;
+mod error_report;
+mod json_protocol;
+mod openings;
+#[cfg(feature = "otel")]
+mod otel;
+mod quiz;
+mod ratings;
+mod replay;
+mod seal;
+mod tokens;
+mod verify;
+
+use error_report::ErrorReporter;
+use json_protocol::{BoardView, JsonMessage, Snapshot};
+use ratings::{Conduct, Ratings};
+use snake_ai_battle::{
+    invert_direction, Direction, EndReason, ForfeitReason, Game, Map, Numbered, Occupancy, Player,
+    RedBlue, RenderData, Rule, ShrinkSchedule, SnakeGame, ToClientMessage, TronGame, WinState,
+};
+use tokens::{Scope, Tokens};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
 use warp::sse::ServerSentEvent;
 use warp::Filter;
 
-#[derive(Debug, Copy, Clone, Serialize)]
-pub enum Player {
-    Red,
-    Blue,
+// A RenderData serialized to JSON once, and shared by every spectator and
+// /state request
+#[derive(Debug, Clone)]
+pub struct RenderFrame(Bytes);
+
+impl std::fmt::Display for RenderFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(std::str::from_utf8(&self.0).map_err(|_| std::fmt::Error)?)
+    }
+}
+
+impl RenderFrame {
+    pub fn new(data: &RenderData) -> Self {
+        Self::with_series(data, None)
+    }
+
+    // with the score of the --best-of series the game is part of
+    pub fn with_series(data: &RenderData, series: Option<SeriesScore>) -> Self {
+        #[derive(Serialize)]
+        struct Frame<'a, 'b> {
+            #[serde(flatten)]
+            data: &'a RenderData<'b>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            series: Option<SeriesScore>,
+        }
+        let json = serde_json::to_vec(&Frame { data, series })
+            .expect("render data is always serializable");
+        RenderFrame(json.into())
+    }
 }
 
+// Where a --best-of or --games series stands: the game being played, and the
+// games won by the players in the colors they play it in
 #[derive(Debug, Copy, Clone, Serialize)]
-#[serde(untagged)]
-pub enum Occupancy {
-    Occupied(Player),
-    Free,
+pub struct SeriesScore {
+    game: usize,
+    best_of: usize,
+    // with --games, every game is played even once the series is won
+    play_all: bool,
+    red: usize,
+    blue: usize,
+    ties: usize,
 }
 
-impl Occupancy {
-    pub fn occupied(self) -> bool {
-        use Occupancy::*;
-        match self {
-            Free => false,
-            Occupied(_) => true,
+impl SeriesScore {
+    pub fn new(best_of: usize) -> Self {
+        SeriesScore {
+            game: 1,
+            best_of,
+            play_all: false,
+            red: 0,
+            blue: 0,
+            ties: 0,
+        }
+    }
+
+    pub fn all(games: usize) -> Self {
+        SeriesScore {
+            play_all: true,
+            ..Self::new(games)
+        }
+    }
+
+    // the score once the game has this result, from red's point of view
+    pub fn after(mut self, result: WinState) -> Self {
+        match result {
+            WinState::Win => self.red += 1,
+            WinState::Loss => self.blue += 1,
+            WinState::Tie => self.ties += 1,
+        }
+        self
+    }
+
+    // once a player has won most of the games, or they've all been played
+    pub fn decided(&self) -> bool {
+        let won = !self.play_all && self.red.max(self.blue) > self.best_of / 2;
+        won || self.game == self.best_of
+    }
+
+    // the score going into the next game, where the players swap colors
+    pub fn next(self) -> Self {
+        SeriesScore {
+            game: self.game + 1,
+            red: self.blue,
+            blue: self.red,
+            ..self
         }
     }
 }
 
+#[test]
+fn series() {
+    let score = SeriesScore::new(3).after(WinState::Win);
+    assert!(!score.decided());
+    let score = score.next().after(WinState::Loss);
+    assert_eq!((2, 0), (score.blue, score.red));
+    assert!(score.decided());
+    // ties count for nobody
+    let score = SeriesScore::new(2).after(WinState::Tie);
+    assert!(!score.decided());
+    assert!(score.next().after(WinState::Tie).decided());
+    // and with --games, winning doesn't end it early
+    let score = SeriesScore::all(3).after(WinState::Win).next();
+    assert!(!score.after(WinState::Loss).decided());
+}
+
+// A match being played, as listed by the visualizer
 #[derive(Debug, Clone, Serialize)]
-pub struct RenderData {
-    width: usize,
-    height: usize,
-    data: Vec<Occupancy>,
-}
-
-impl RenderData {
-    pub fn game_start() -> Self {
-        let mut data = Vec::new();
-        for i in 0..(BOARD_SIZE * BOARD_SIZE) {
-            data.push(
-                [
-                    Occupancy::Occupied(Player::Red),
-                    Occupancy::Occupied(Player::Blue),
-                    Occupancy::Free,
-                ][i % 3],
-            )
+struct GameInfo {
+    id: String,
+    red: String,
+    blue: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    series: Option<SeriesScore>,
+}
+
+// The matches being played, for the visualizer. It follows the newest by
+// default, or the last to finish while none are being played.
+#[derive(Clone)]
+struct Games(Arc<Mutex<GameList>>);
+
+struct GameList {
+    active: Vec<(GameInfo, watch::Receiver<RenderFrame>)>,
+    latest: watch::Receiver<RenderFrame>,
+    // an empty board for before the first match, closed when it starts so
+    // that spectators move on to it
+    idle: Option<watch::Sender<RenderFrame>>,
+    // with --debug-controls, for pausing and stepping each active game
+    steppers: HashMap<String, mpsc::UnboundedSender<DebugControl>>,
+}
+
+impl Games {
+    pub fn new(width: usize, height: usize) -> Self {
+        let (idle, latest) =
+            watch::channel(RenderFrame::new(&RenderData::game_start(width, height)));
+        Games(Arc::new(Mutex::new(GameList {
+            active: Vec::new(),
+            latest,
+            idle: Some(idle),
+            steppers: HashMap::new(),
+        })))
+    }
+
+    pub fn start(&self, info: GameInfo, recv: watch::Receiver<RenderFrame>) {
+        let mut games = self.0.lock().unwrap();
+        games.latest = recv.clone();
+        games.idle = None;
+        games.active.push((info, recv));
+    }
+
+    pub fn finish(&self, id: &str) {
+        let mut games = self.0.lock().unwrap();
+        games.active.retain(|(info, _)| info.id != id);
+        games.steppers.remove(id);
+    }
+
+    // Lets the debugging endpoints pause and step this game
+    pub fn stepper(&self, id: &str) -> Stepper {
+        let (send, controls) = mpsc::unbounded_channel();
+        let mut games = self.0.lock().unwrap();
+        games.steppers.insert(id.to_owned(), send);
+        Stepper {
+            controls,
+            paused: false,
+            steps: 0,
         }
-        Self {
-            width: BOARD_SIZE,
-            height: BOARD_SIZE,
-            data,
+    }
+
+    // Passes a control on to a game with a stepper, if it's still going
+    pub fn debug(&self, id: &str, control: DebugControl) -> bool {
+        let games = self.0.lock().unwrap();
+        match games.steppers.get(id) {
+            Some(stepper) => stepper.send(control).is_ok(),
+            None => false,
         }
     }
+
+    pub fn get(&self, id: &str) -> Option<watch::Receiver<RenderFrame>> {
+        let games = self.0.lock().unwrap();
+        let found = games.active.iter().find(|(info, _)| info.id == id);
+        found.map(|(_, recv)| recv.clone())
+    }
+
+    pub fn latest(&self) -> watch::Receiver<RenderFrame> {
+        self.0.lock().unwrap().latest.clone()
+    }
+
+    pub fn list(&self) -> Vec<GameInfo> {
+        let games = self.0.lock().unwrap();
+        games.active.iter().map(|(info, _)| info.clone()).collect()
+    }
+}
+
+fn frame_response(frame: RenderFrame) -> warp::http::Response<Bytes> {
+    warp::http::Response::builder()
+        .header(warp::http::header::CONTENT_TYPE, "application/json")
+        .body(frame.0)
+        .expect("the response is always valid")
 }
 
 fn receive_updates(
-    recv: watch::Receiver<RenderData>,
+    recv: watch::Receiver<RenderFrame>,
 ) -> impl Stream<Item = Result<impl ServerSentEvent, Infallible>> {
-    recv.map(|v| Ok((warp::sse::json(v), warp::sse::event("render"))))
+    // spectators reconnect quickly when a match ends, to follow the next
+    let retry = Duration::from_millis(500);
+    recv.map(move |v| {
+        Ok((
+            warp::sse::data(v),
+            warp::sse::event("render"),
+            warp::sse::retry(retry),
+        ))
+    })
 }
 
-fn start_webserver(recv: watch::Receiver<RenderData>, bind_addr: std::net::SocketAddr) {
-    thread::spawn(move || {
-        let mut rt = tokio::runtime::Builder::new()
-            .basic_scheduler()
-            .enable_all()
-            .build()
-            .unwrap();
-        rt.block_on(async {
-            let index = warp::path!("index.html")
-                .or(warp::path::end())
-                .map(|_| warp::reply::html(include_str!("public/index.html")));
-            let js = warp::path!("script.js").map(|| include_str!("public/script.js"));
-
-            let clone_state_watcher = warp::any().map(move || recv.clone());
-            let sse_watcher = warp::path("watch").and(clone_state_watcher).map(|recv| {
-                let stream = receive_updates(recv);
-                warp::sse::reply(warp::sse::keep_alive().stream(stream))
-            });
+// Binds the visualizer, then serves it in the background. Without a game
+// id, /watch and /state follow the newest match; a spectator's stream ends
+// with its match, and the browser reconnects to the next.
+async fn start_webserver(
+    games: Games,
+    ratings: Ratings,
+    tokens: Tokens,
+    bind_addr: std::net::SocketAddr,
+    tls: Option<TlsAcceptor>,
+    controls: Option<replay::Controls>,
+) -> Result<(), io::Error> {
+    let index = warp::path!("index.html")
+        .or(warp::path::end())
+        .map(|_| warp::reply::html(include_str!("public/index.html")));
+    // the same page, following one match
+    let game_page =
+        warp::path!("game" / String).map(|_| warp::reply::html(include_str!("public/index.html")));
+    let js = warp::path!("script.js").map(|| include_str!("public/script.js"));
+    let with_games = warp::any().map(move || games.clone());
+
+    let list = warp::path!("games")
+        .and(with_games.clone())
+        .map(|games: Games| warp::reply::json(&games.list()));
+    let ratings = warp::path!("ratings").map(move || warp::reply::json(&ratings.list()));
 
-            let routes = warp::get().and(index.or(js).or(sse_watcher));
-            println!("Running visualizer on http://{}/", bind_addr);
-            warp::serve(routes).run(bind_addr).await;
+    // the latest frame as JSON, for dashboards that poll
+    let state = warp::path!("state")
+        .and(with_games.clone())
+        .map(|games: Games| frame_response(games.latest().borrow().clone()));
+    let game_state = warp::path!("state" / String)
+        .and(with_games.clone())
+        .and_then(|id: String, games: Games| async move {
+            match games.get(&id) {
+                Some(recv) => Ok(frame_response(recv.borrow().clone())),
+                None => Err(warp::reject::not_found()),
+            }
         });
-    });
+
+    let sse_watcher = warp::path!("watch")
+        .and(with_games.clone())
+        .map(|games: Games| {
+            let stream = receive_updates(games.latest());
+            warp::sse::reply(warp::sse::keep_alive().stream(stream))
+        });
+    let game_watcher = warp::path!("watch" / String)
+        .and(with_games.clone())
+        .and_then(|id: String, games: Games| async move {
+            match games.get(&id) {
+                Some(recv) => {
+                    let stream = receive_updates(recv);
+                    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+                }
+                None => Err(warp::reject::not_found()),
+            }
+        });
+
+    // POSTs that change what's played need a control token. One that's made
+    // up is kept out of the info log, which is often shared more widely than
+    // the controls should be.
+    if (controls.is_some() || CLI_OPTIONS.debug_controls) && !tokens.has_control() {
+        let token = tokens.issue("generated", Some(Scope::Control), true)?;
+        info!(
+            "Controls need a token, and none was given, so one was made up. Run with -v to see it"
+        );
+        debug!(
+            "Controls need the header \"Authorization: Bearer {}\"",
+            token
+        );
+    }
+    let with_tokens = warp::any().map(move || tokens.clone());
+    let scope = warp::header::optional::<String>("authorization")
+        .and(with_tokens.clone())
+        .map(|header: Option<String>, tokens: Tokens| tokens.scope(header.as_deref()));
+    let authorized = scope.clone().map(|scope| scope == Some(Scope::Control));
+
+    // with --tokens, managing the tokens themselves
+    let token_list = warp::path!("tokens")
+        .and(scope)
+        .and(with_tokens.clone())
+        .map(|scope: Option<Scope>, tokens: Tokens| match scope {
+            Some(_) => warp::reply::with_status(warp::reply::json(&tokens.list()), StatusCode::OK),
+            None => warp::reply::with_status(
+                warp::reply::json(&"send a token as \"Authorization: Bearer <token>\""),
+                StatusCode::UNAUTHORIZED,
+            ),
+        });
+    let issue = warp::path!("tokens" / String)
+        .and(authorized.clone())
+        .and(with_tokens.clone())
+        .and(warp::query::<TokenQuery>())
+        .map(
+            |name: String, authorized, tokens: Tokens, query: TokenQuery| {
+                issue_token(authorized, &name, &tokens, query.scope)
+            },
+        );
+    let revoke = warp::path!("tokens" / String)
+        .and(authorized.clone())
+        .and(with_tokens)
+        .map(|name: String, authorized, tokens: Tokens| revoke_token(authorized, &name, &tokens));
+
+    // with `replay`, playback controls for a timeline
+    let with_controls = warp::any().map(move || controls.clone());
+    let playback = warp::path!("control")
+        .and(warp::get())
+        .and(with_controls.clone())
+        .map(|controls: Option<replay::Controls>| match controls {
+            Some(controls) => {
+                warp::reply::with_status(warp::reply::json(&controls.status()), StatusCode::OK)
+            }
+            None => {
+                warp::reply::with_status(warp::reply::json(&NOT_A_REPLAY), StatusCode::NOT_FOUND)
+            }
+        });
+    let seek = warp::path!("control" / "seek")
+        .and(authorized.clone())
+        .and(with_controls.clone())
+        .and(warp::query::<SeekQuery>())
+        .map(|authorized, controls, query: SeekQuery| {
+            control(authorized, controls, Ok(replay::Control::Seek(query.turn)))
+        });
+    let pause = warp::path!("control" / "pause")
+        .and(authorized.clone())
+        .and(with_controls.clone())
+        .and(warp::query::<PauseQuery>())
+        .map(|authorized, controls, query: PauseQuery| {
+            control(
+                authorized,
+                controls,
+                Ok(replay::Control::Pause(query.paused)),
+            )
+        });
+    // with --debug-controls, pausing and stepping live games
+    let debug = warp::path!("debug" / String / String)
+        .and(authorized.clone())
+        .and(with_games)
+        .map(
+            |id: String, action: String, authorized: bool, games: Games| {
+                if !authorized {
+                    return StatusCode::UNAUTHORIZED;
+                }
+                let control = match action.as_str() {
+                    "pause" => DebugControl::Pause,
+                    "step" => DebugControl::Step,
+                    "resume" => DebugControl::Resume,
+                    _ => return StatusCode::NOT_FOUND,
+                };
+                if games.debug(&id, control) {
+                    StatusCode::NO_CONTENT
+                } else {
+                    StatusCode::NOT_FOUND
+                }
+            },
+        );
+    let speed = warp::path!("control" / "speed")
+        .and(authorized)
+        .and(with_controls)
+        .and(warp::query::<SpeedQuery>())
+        .map(|authorized, controls, query: SpeedQuery| {
+            control(
+                authorized,
+                controls,
+                parse_speed(&query.speed).map(replay::Control::Speed),
+            )
+        });
+
+    let routes = warp::get()
+        .and(
+            index
+                .or(game_page)
+                .or(js)
+                .or(list)
+                .or(ratings)
+                .or(token_list)
+                .or(state)
+                .or(game_state)
+                .or(sse_watcher)
+                .or(game_watcher),
+        )
+        .or(playback)
+        .or(warp::post().and(seek.or(pause).or(speed).or(debug).or(issue)))
+        .or(warp::delete().and(revoke));
+    let listener = tokio::net::TcpListener::bind(bind_addr).await?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("Running visualizer on {}://{}/", scheme, bind_addr);
+    tokio::spawn(warp::serve(routes).run_incoming(visualizer_connections(listener, tls)));
+    Ok(())
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum WinState {
-    Win,
-    Loss,
-    Tie,
+const NOT_A_REPLAY: &str = "playback controls are only for replays";
+const UNAUTHORIZED: &str = "send a control token as \"Authorization: Bearer <token>\"";
+
+// Passes a control on to a replay's playback, if it's a valid one from
+// someone with the control token
+fn control(
+    authorized: bool,
+    controls: Option<replay::Controls>,
+    control: Result<replay::Control, String>,
+) -> warp::reply::WithStatus<String> {
+    match (controls, control) {
+        _ if !authorized => {
+            warp::reply::with_status(UNAUTHORIZED.to_owned(), StatusCode::UNAUTHORIZED)
+        }
+        (None, _) => warp::reply::with_status(NOT_A_REPLAY.to_owned(), StatusCode::NOT_FOUND),
+        (Some(_), Err(e)) => warp::reply::with_status(e, StatusCode::BAD_REQUEST),
+        (Some(controls), Ok(control)) => {
+            controls.send(control);
+            warp::reply::with_status(String::new(), StatusCode::NO_CONTENT)
+        }
+    }
 }
 
-impl WinState {
-    pub fn inverse(self) -> Self {
-        use WinState::*;
-        match self {
-            Win => Loss,
-            Loss => Win,
-            Tie => Tie,
+// Makes up a token, or a new one in place of the old, for someone with a
+// control token. It's the reply's body, the only time it's ever shown.
+fn issue_token(
+    authorized: bool,
+    name: &str,
+    tokens: &Tokens,
+    scope: Option<Scope>,
+) -> warp::reply::WithStatus<String> {
+    if let Err(refused) = check_token_name(authorized, name) {
+        return refused;
+    }
+    match tokens.issue(name, scope, false) {
+        Ok(token) => {
+            info!(name, "Issued a token");
+            warp::reply::with_status(token, StatusCode::OK)
         }
+        Err(e) => token_save_failed(e),
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum ToClientMessage {
-    End(WinState),
-    Update(usize, usize),
+fn revoke_token(authorized: bool, name: &str, tokens: &Tokens) -> warp::reply::WithStatus<String> {
+    if let Err(refused) = check_token_name(authorized, name) {
+        return refused;
+    }
+    match tokens.revoke(name) {
+        Ok(true) => {
+            info!(name, "Revoked a token");
+            warp::reply::with_status(String::new(), StatusCode::NO_CONTENT)
+        }
+        Ok(false) => warp::reply::with_status(
+            format!("there's no token called {}", name),
+            StatusCode::NOT_FOUND,
+        ),
+        Err(e) => token_save_failed(e),
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum Direction {
-    Up,
-    Down,
-    Left,
-    Right,
-}
-
-#[derive(Error, Debug)]
-pub enum ClientRecvFailure {
-    #[error("client took too long to respond")]
-    ClientTimeoutReached,
-    #[error("the underlying socket errored")]
-    ParseError,
-    #[error("the client closed the connection")]
-    Eof,
+fn check_token_name(authorized: bool, name: &str) -> Result<(), warp::reply::WithStatus<String>> {
+    if !authorized {
+        return Err(warp::reply::with_status(
+            UNAUTHORIZED.to_owned(),
+            StatusCode::UNAUTHORIZED,
+        ));
+    }
+    if name.is_empty() || sanitize_name(name) != name {
+        return Err(warp::reply::with_status(
+            "token names are letters, digits, '-', '_' and '.'".to_owned(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    Ok(())
+}
+
+fn token_save_failed(e: io::Error) -> warp::reply::WithStatus<String> {
+    error!(%e, "Saving the tokens failed");
+    warp::reply::with_status(e.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+// /tokens/<name>?scope=control, or without scope to keep the one it has
+#[derive(Deserialize)]
+struct TokenQuery {
+    scope: Option<Scope>,
+}
+
+// /control/seek?turn=40
+#[derive(Deserialize)]
+struct SeekQuery {
+    turn: usize,
+}
+
+// /control/pause?paused=true, or without paused to toggle
+#[derive(Deserialize)]
+struct PauseQuery {
+    paused: Option<bool>,
+}
+
+// /control/speed?speed=2x
+#[derive(Deserialize)]
+struct SpeedQuery {
+    speed: String,
+}
+
+// A client that hasn't sent anything this long after connecting is dropped,
+// as is one that hasn't finished its TLS handshake.
+const HTTP_FIRST_READ_DEADLINE: Duration = Duration::from_secs(10);
+
+fn load_tls_config(
+    cert: &std::path::Path,
+    key: &std::path::Path,
+) -> Result<TlsAcceptor, anyhow::Error> {
+    use tokio_rustls::rustls::internal::pemfile;
+    let open = |path: &std::path::Path| -> Result<_, anyhow::Error> {
+        Ok(io::BufReader::new(File::open(path).map_err(|e| {
+            anyhow::anyhow!("can't open {}: {}", path.display(), e)
+        })?))
+    };
+    let certs = pemfile::certs(&mut open(cert)?)
+        .map_err(|_| anyhow::anyhow!("{} is not a PEM certificate chain", cert.display()))?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key)?).unwrap_or_default();
+    }
+    let key = keys
+        .pop()
+        .ok_or_else(|| anyhow::anyhow!("{} contains no PEM private key", key.display()))?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Anything hyper can serve a connection over
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+// Accepted visualizer connections, minus any over the connection limits,
+// after finishing their TLS handshake if TLS is enabled.
+fn visualizer_connections(
+    listener: tokio::net::TcpListener,
+    tls: Option<TlsAcceptor>,
+) -> impl Stream<Item = Result<Box<dyn Connection>, Infallible>> {
+    let counts = Arc::new(Mutex::new(ConnectionCounts::default()));
+    futures::stream::unfold(listener, |mut listener| async {
+        let accepted = listener.accept().await;
+        Some((accepted, listener))
+    })
+    .filter_map(move |accepted| {
+        let counts = counts.clone();
+        async move {
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Visualizer failed to accept connection: {}", e);
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                    return None;
+                }
+            };
+            let slot = ConnectionSlot::try_new(
+                &counts,
+                peer.ip(),
+                CLI_OPTIONS.max_visualizer_connections,
+                CLI_OPTIONS.max_connections_per_ip,
+            );
+            match slot {
+                Some(slot) => Some(VisualizerConn {
+                    stream,
+                    _slot: slot,
+                    first_read: Some(Box::pin(tokio::time::delay_for(HTTP_FIRST_READ_DEADLINE))),
+                }),
+                None => {
+                    debug!("Rejecting visualizer connection from {}", peer);
+                    None
+                }
+            }
+        }
+    })
+    .map(move |conn| {
+        let tls = tls.clone();
+        async move {
+            let tls = match tls {
+                Some(tls) => tls,
+                None => return Some(Box::new(conn) as Box<dyn Connection>),
+            };
+            match tokio::time::timeout(HTTP_FIRST_READ_DEADLINE, tls.accept(conn)).await {
+                Ok(Ok(conn)) => Some(Box::new(conn) as Box<dyn Connection>),
+                Ok(Err(e)) => {
+                    debug!("TLS handshake failed: {}", e);
+                    None
+                }
+                Err(_) => {
+                    debug!("TLS handshake timed out");
+                    None
+                }
+            }
+        }
+    })
+    // handshakes run concurrently, so a slow one doesn't hold up the rest
+    .buffer_unordered(CLI_OPTIONS.max_visualizer_connections.max(1))
+    .filter_map(|conn| async { conn.map(Ok) })
+}
+
+// Holds its place in the visualizer's connection counts, and is closed if
+// the client doesn't send anything soon after connecting.
+struct VisualizerConn {
+    stream: tokio::net::TcpStream,
+    _slot: ConnectionSlot,
+    first_read: Option<Pin<Box<tokio::time::Delay>>>,
+}
+
+impl AsyncRead for VisualizerConn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if res.is_ready() {
+            this.first_read = None;
+            return res;
+        }
+        if let Some(deadline) = &mut this.first_read {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for VisualizerConn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
 }
 
 #[derive(Debug)]
 struct Client {
-    stream: io::BufReader<TcpStream>,
+    stream: tokio::io::BufReader<tokio::net::TcpStream>,
+    peer: std::net::SocketAddr,
     name: String,
     read_line: String,
+    // the start of a line that was still coming in at the last deadline
+    unfinished: Vec<u8>,
+    // whether that line is too long, and is being passed over to its end
+    skipping: bool,
     write_buffer: String,
+    // reset for every read and write rather than registering a new timer
+    timer: tokio::time::Delay,
+    // the last few lines exchanged, for error reports
+    transcript: VecDeque<String>,
+    // when the last update finished writing, and the same as unix time
+    sent_at: time::Instant,
+    sent_ms: u128,
+    // moves that timed out but may still arrive
+    late_moves: usize,
+    protocol: Protocol,
+    // positions as "x y" pairs rather than numbers
+    coords: bool,
+    // the cells that changed, before each set of positions
+    deltas: bool,
+    // what a JSON or DELTAS client knows of the board
+    view: BoardView,
+    // the board as of the last update, for "board" queries
+    board: Snapshot,
+    // time left to move in, with --time-bank
+    clock: Option<Duration>,
+    // answers pings, for --latency-compensation
+    ping: bool,
+    // the round trip time last measured, added to the client's deadlines
+    latency: Duration,
+    // where it's matched with an opponent; the empty room is everyone's
+    room: String,
+    // the seat it asked for with "COLOR", if it minds which
+    color: Option<Player>,
+    // whether it sent "COLOR" at all, and so is told the seat it got
+    tell_color: bool,
+}
+
+// What a client asks for with "PROTOCOL 2" or "PROTOCOL JSON" before its
+// name. Version 1 is the default.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+enum Protocol {
+    #[serde(rename = "1")]
+    V1,
+    // turn numbers on positions, and what ended the game
+    #[serde(rename = "2")]
+    V2,
+    #[serde(rename = "JSON")]
+    Json,
+}
+
+// the newest version, announced with --banner
+const PROTOCOL_VERSION: u32 = 2;
+
+// With --latency-compensation, clients that ask for PING are pinged this many
+// times before the game and then every PING_EVERY turns, and the fastest
+// round trip counts
+const PINGS: usize = 3;
+const PING_EVERY: usize = 50;
+
+// The game's settings, as a player sees them: "CONFIG", then the board's
+// width and height, the timeout, both starting positions, and the names of
+// the rule variants in play, as in "CONFIG 32 32 200 484 539 WRAP"
+#[derive(Debug)]
+pub struct Config {
+    width: usize,
+    height: usize,
+    timeout: u64,
+    start: usize,
+    opponent_start: usize,
+    flags: Vec<&'static str>,
+}
+
+impl Config {
+    fn new(board: &RenderData, positions: ToClientMessage, rules: &[Rule]) -> Self {
+        let (start, opponent_start) = match positions {
+            ToClientMessage::Update(mine, theirs)
+            | ToClientMessage::FoodUpdate(mine, theirs, _) => (mine, theirs),
+            _ => (0, 0),
+        };
+        let mut flags = Vec::new();
+        if CLI_OPTIONS.mode == Mode::Snake {
+            flags.push("SNAKE");
+        }
+        for rule in rules {
+            flags.push(match rule {
+                Rule::Wrap => "WRAP",
+                Rule::Walls(_) => "WALLS",
+                Rule::Shrink(_) => "SHRINK",
+            });
+        }
+        if CLI_OPTIONS.power_ups.is_some() && CLI_OPTIONS.mode == Mode::Tron {
+            flags.push("POWER_UPS");
+        }
+        Config {
+            width: board.width,
+            height: board.height,
+            timeout: CLI_OPTIONS.timeout,
+            start,
+            opponent_start,
+            flags,
+        }
+    }
+}
+
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "CONFIG {} {} {} {} {}",
+            self.width, self.height, self.timeout, self.start, self.opponent_start
+        )?;
+        for flag in &self.flags {
+            write!(f, " {}", flag)?;
+        }
+        Ok(())
+    }
+}
+
+impl Protocol {
+    fn parse(version: &str) -> Option<Self> {
+        match version {
+            "1" => Some(Protocol::V1),
+            "2" => Some(Protocol::V2),
+            "JSON" => Some(Protocol::Json),
+            _ => None,
+        }
+    }
 }
 
-type ClientResult<T> = Result<Result<T, ClientRecvFailure>, io::Error>;
+const TRANSCRIPT_LEN: usize = 10;
+
+type ClientResult<T> = Result<Result<T, ForfeitReason>, io::Error>;
 macro_rules! double_try {
     ($e:expr) => {
         let e = $e;
@@ -162,259 +858,655 @@ macro_rules! double_try {
 }
 
 impl Client {
-    pub fn new(stream: TcpStream) -> Result<Self, io::Error> {
-        stream.set_nonblocking(true)?;
+    pub fn new(stream: tokio::net::TcpStream) -> Result<Self, io::Error> {
+        // each update and move is one tiny line, which Nagle would hold back
+        stream.set_nodelay(!CLI_OPTIONS.nagle)?;
+        if let Some(size) = CLI_OPTIONS.socket_recv_buffer {
+            stream.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = CLI_OPTIONS.socket_send_buffer {
+            stream.set_send_buffer_size(size)?;
+        }
+        if let Some(secs) = CLI_OPTIONS.tcp_keepalive {
+            stream.set_keepalive(Some(Duration::from_secs(secs)))?;
+        }
         Ok(Self {
-            stream: io::BufReader::new(stream),
+            peer: stream.peer_addr()?,
+            stream: tokio::io::BufReader::new(stream),
             name: String::new(),
             read_line: String::new(),
+            unfinished: Vec::new(),
+            skipping: false,
             write_buffer: String::new(),
+            timer: tokio::time::delay_until(time::Instant::now().into()),
+            transcript: VecDeque::with_capacity(TRANSCRIPT_LEN),
+            sent_at: time::Instant::now(),
+            sent_ms: 0,
+            late_moves: 0,
+            protocol: Protocol::V1,
+            coords: false,
+            deltas: false,
+            board: Snapshot::default(),
+            clock: CLI_OPTIONS.time_bank.map(Duration::from_millis),
+            ping: false,
+            latency: Duration::default(),
+            room: String::new(),
+            color: None,
+            tell_color: false,
+            view: BoardView::default(),
         })
     }
 
-    pub fn recv_name(&mut self, deadline: time::Instant) -> ClientResult<()> {
-        double_try!(self.read_line_deadline(deadline));
-        dbg!(&self.read_line);
-        self.name = self.read_line.trim().to_owned();
-        Ok(Ok(()))
+    pub fn transcript(&self) -> Vec<String> {
+        self.transcript.iter().cloned().collect()
     }
 
-    pub fn send_update(&mut self, upd: ToClientMessage) -> Result<(), io::Error> {
-        self.write_buffer.clear();
-        match upd {
-            ToClientMessage::End(state) => {
-                writeln!(&mut self.write_buffer, "{:?}", state).unwrap();
-                self.write_buffer.make_ascii_uppercase();
+    // direction is "<" for lines sent to the client, ">" for received
+    fn remember(&mut self, direction: &str, line: &str) {
+        let mut entry = if self.transcript.len() == TRANSCRIPT_LEN {
+            self.transcript.pop_front().unwrap()
+        } else {
+            String::new()
+        };
+        entry.clear();
+        entry.push_str(direction);
+        entry.push(' ');
+        entry.push_str(line.trim_end());
+        self.transcript.push_back(entry);
+    }
+
+    pub async fn recv_name(&mut self, deadline: time::Instant) -> ClientResult<()> {
+        if CLI_OPTIONS.banner {
+            let banner = format!(
+                "TRON {} {} {} {}",
+                PROTOCOL_VERSION,
+                CLI_OPTIONS.width(),
+                CLI_OPTIONS.height(),
+                CLI_OPTIONS.timeout
+            );
+            if self.send_update(banner).await.is_err() {
+                return Ok(Err(ForfeitReason::WriteFailure));
             }
-            ToClientMessage::Update(this, theirs) => {
-                writeln!(&mut self.write_buffer, "{} {}", this, theirs).unwrap();
+        }
+        double_try!(self.read_line_deadline(deadline).await);
+        // Before its name, a client may ask for a newer protocol along with
+        // any options, a room to wait in for an opponent, and a seat, in any
+        // order
+        loop {
+            let line = self.read_line.trim();
+            if let Some(request) = line.strip_prefix("PROTOCOL ") {
+                let mut words = request.split(' ');
+                self.protocol = match words.next().and_then(Protocol::parse) {
+                    Some(protocol) => protocol,
+                    None => return Ok(Err(ForfeitReason::ParseError)),
+                };
+                for option in words {
+                    match option {
+                        "COORDS" => self.coords = true,
+                        "DELTAS" => self.deltas = true,
+                        "PING" => self.ping = true,
+                        _ => return Ok(Err(ForfeitReason::ParseError)),
+                    }
+                }
+                trace!(
+                    protocol = ?self.protocol,
+                    coords = self.coords,
+                    deltas = self.deltas,
+                    ping = self.ping,
+                    "received protocol version"
+                );
+            } else if let Some(room) = line.strip_prefix("JOIN ") {
+                self.room = sanitize_name(room.trim());
+                trace!(room = %self.room, "received room");
+            } else if let Some(color) = line.strip_prefix("COLOR ") {
+                self.color = match color.trim().to_ascii_lowercase().as_str() {
+                    "red" => Some(Player::Red),
+                    "blue" => Some(Player::Blue),
+                    "any" => None,
+                    _ => return Ok(Err(ForfeitReason::ParseError)),
+                };
+                self.tell_color = true;
+                trace!(color = ?self.color, "received color");
+            } else {
+                break;
             }
+            double_try!(self.read_line_deadline(deadline).await);
         }
-        // this could theoretically error with WoudBlock, if that ever happens we will deal with it
-        self.stream
-            .get_mut()
-            .write_all(self.write_buffer.as_bytes())
+        trace!(line = ?self.read_line, "received name");
+        self.name = sanitize_name(self.read_line.trim());
+        Ok(Ok(()))
     }
 
-    pub fn read_direction(&mut self, deadline: std::time::Instant) -> ClientResult<Direction> {
-        double_try!(self.read_line_deadline(deadline));
-        dbg!(&self.read_line);
-        if self.read_line.len() != 2 || !self.read_line.is_ascii() {
-            return Ok(Err(ClientRecvFailure::ParseError));
-        }
-        Ok(Ok(match self.read_line.chars().next().unwrap() {
-            'u' => Direction::Up,
-            'd' => Direction::Down,
-            'l' => Direction::Left,
-            'r' => Direction::Right,
-            _ => return Ok(Err(ClientRecvFailure::ParseError)),
-        }))
+    pub async fn send_update(&mut self, upd: impl std::fmt::Display) -> Result<(), io::Error> {
+        self.write_buffer.clear();
+        writeln!(&mut self.write_buffer, "{}", upd).unwrap();
+        // a client that stops reading shouldn't be able to stall the server
+        let timeout = time::Duration::from_millis(CLI_OPTIONS.timeout.max(1));
+        let write = self
+            .stream
+            .get_mut()
+            .write_all(self.write_buffer.as_bytes());
+        match before(&mut self.timer, time::Instant::now() + timeout, write).await {
+            Some(res) => res?,
+            None => return Err(io::ErrorKind::TimedOut.into()),
+        }
+        self.sent_at = time::Instant::now();
+        self.sent_ms = unix_ms();
+        let line = std::mem::take(&mut self.write_buffer);
+        self.remember("<", &line);
+        self.write_buffer = line;
+        Ok(())
     }
 
-    fn read_line_deadline(&mut self, deadline: time::Instant) -> ClientResult<()> {
-        self.read_line.clear();
-        loop {
-            match self.stream.read_line(&mut self.read_line) {
-                Ok(0) => return Ok(Err(ClientRecvFailure::Eof)),
-                Ok(_) => return Ok(Ok(())),
-                Err(err) => match err.kind() {
-                    io::ErrorKind::WouldBlock => (),
-                    _ => return Err(err),
-                },
+    // Sends a game message in the protocol the client asked for, to the
+    // player in this seat. JSON and DELTAS clients are also sent the cells
+    // that changed on the board since their last update.
+    pub async fn send_message(
+        &mut self,
+        msg: ToClientMessage,
+        turn: usize,
+        seat: Player,
+        board: Option<&RenderData<'_>>,
+    ) -> Result<(), io::Error> {
+        let positions = match msg {
+            ToClientMessage::Update(..) | ToClientMessage::FoodUpdate(..) => 0..3,
+            ToClientMessage::PowerUp(..) => 1..2,
+            ToClientMessage::End(..) => 0..0,
+        };
+        let update = matches!(
+            msg,
+            ToClientMessage::Update(..) | ToClientMessage::FoodUpdate(..)
+        );
+        if let Some(board) = board.filter(|_| update) {
+            self.board.update(seat, board);
+            if self.deltas && self.protocol != Protocol::Json {
+                self.send_changes(seat, board).await?;
+            }
+        }
+        match self.protocol {
+            Protocol::V1 => {
+                // version 1 only ever says what ended a game when it hit the
+                // turn limit
+                let msg = match msg {
+                    ToClientMessage::End(win, Some(reason)) if reason != EndReason::TurnLimit => {
+                        ToClientMessage::End(win, None)
+                    }
+                    msg => msg,
+                };
+                self.send_positions(msg, positions).await
+            }
+            Protocol::V2 => {
+                // after the turn number
+                let positions = match msg {
+                    ToClientMessage::Update(..) | ToClientMessage::FoodUpdate(..) => 1..4,
+                    _ => positions,
+                };
+                self.send_positions(Numbered(turn, msg), positions).await
             }
-            if time::Instant::now() > deadline {
-                return Ok(Err(ClientRecvFailure::ClientTimeoutReached));
+            Protocol::Json => {
+                let msg = self.view.message(msg, turn, seat, board);
+                self.send_json(&msg).await
             }
         }
     }
-}
-
-const BOARD_SIZE: usize = 32;
 
-fn invert_pos(idx: usize) -> usize {
-    BOARD_SIZE * BOARD_SIZE - idx - 1
-}
-fn invert_direction(d: Direction) -> Direction {
-    use Direction::*;
-    match d {
-        Up => Down,
-        Down => Up,
-        Left => Right,
-        Right => Left,
-    }
-}
-fn invert_update(u: ToClientMessage) -> ToClientMessage {
-    match u {
-        ToClientMessage::End(x) => ToClientMessage::End(x.inverse()),
-        ToClientMessage::Update(mypos, theirpos) => {
-            ToClientMessage::Update(invert_pos(theirpos), invert_pos(mypos))
+    // "CHANGES" and then each changed cell's position and what's in it now,
+    // as in "CHANGES 485 YOU 17 BOOST"
+    async fn send_changes(
+        &mut self,
+        seat: Player,
+        board: &RenderData<'_>,
+    ) -> Result<(), io::Error> {
+        let mut line = String::from("CHANGES");
+        for (pos, cell) in self.view.changes(seat, board) {
+            write!(&mut line, " {} {}", pos, cell.as_str()).unwrap();
         }
+        self.send_positions(line, 1..usize::MAX).await
     }
-}
-#[test]
-fn inversions() {
-    for i in 0..(BOARD_SIZE * BOARD_SIZE) {
-        assert_eq!(i, invert_pos(invert_pos(i)));
-    }
-    assert_eq!(1023, invert_pos(0));
-    assert_eq!(992, invert_pos(31));
-    assert_eq!(34, invert_pos(989));
-    assert_eq!(0, invert_pos(484));
-}
 
-#[derive(Debug, Copy, Clone)]
-struct RedBlue<T> {
-    pub red: T,
-    pub blue: T,
-}
+    // "BOARD", the width and the height, and then a line for each row of the
+    // board as of the last update
+    async fn send_board(&mut self) -> Result<(), io::Error> {
+        let rows = self.board.rows();
+        let width = rows.first().map_or(0, String::len);
+        let height = rows.len();
+        // the client's time still counts from its update
+        let sent = (self.sent_at, self.sent_ms);
+        let res = if self.protocol == Protocol::Json {
+            self.send_json(&JsonMessage::Board {
+                width,
+                height,
+                rows,
+            })
+            .await
+        } else {
+            let mut dump = format!("BOARD {} {}", width, height);
+            for row in &rows {
+                write!(&mut dump, "\n{}", row).unwrap();
+            }
+            self.send_update(dump).await
+        };
+        (self.sent_at, self.sent_ms) = sent;
+        res
+    }
 
-impl<T> RedBlue<T> {
-    pub fn map<U, F>(self, mut op: F) -> RedBlue<U>
-    where
-        F: FnMut(T) -> U,
-    {
-        RedBlue {
-            red: op(self.red),
-            blue: op(self.blue),
+    // "TIME" and the milliseconds left on this client's clock and then its
+    // opponent's
+    pub async fn send_time(&mut self, opponent: Duration) -> Result<(), io::Error> {
+        let you = self.clock.unwrap_or_default().as_millis();
+        let opponent = opponent.as_millis();
+        match self.protocol {
+            Protocol::Json => self.send_json(&JsonMessage::Time { you, opponent }).await,
+            _ => {
+                self.send_update(format_args!("TIME {} {}", you, opponent))
+                    .await
+            }
         }
     }
 
-    pub fn as_ref(&self) -> RedBlue<&T> {
-        RedBlue {
-            red: &self.red,
-            blue: &self.blue,
+    // Measures the round trip time to a client that asked for PING, if the
+    // server compensates for latency. A ping that isn't answered with "PONG"
+    // in time is given up on, and the last measurement kept.
+    pub async fn probe_latency(&mut self) -> Result<(), io::Error> {
+        let max = match CLI_OPTIONS.latency_compensation {
+            Some(max) if self.ping => Duration::from_millis(max),
+            _ => return Ok(()),
+        };
+        let timeout = Duration::from_millis(CLI_OPTIONS.timeout);
+        let mut fastest: Option<Duration> = None;
+        for _ in 0..PINGS {
+            match self.protocol {
+                Protocol::Json => self.send_json(&JsonMessage::Ping).await?,
+                _ => self.send_update("PING").await?,
+            }
+            // a late pong is skipped like a late move
+            match self.read_move_line(self.sent_at + timeout).await? {
+                Ok(()) if self.read_line.trim() == "PONG" => {
+                    let rtt = self.sent_at.elapsed();
+                    fastest = Some(fastest.map_or(rtt, |fastest| fastest.min(rtt)));
+                }
+                _ => break,
+            }
         }
+        if let Some(rtt) = fastest {
+            self.latency = rtt.min(max);
+            debug!(client = %self.name, rtt_ms = as_ms(rtt), "measured latency");
+        }
+        Ok(())
     }
-}
 
-struct TronGame {
-    board: Vec<Occupancy>,
-    pos: RedBlue<usize>,
-    endgame: Option<WinState>,
-}
-
-// Red is always the "main" player
-impl TronGame {
-    pub fn new() -> Self {
-        let mut board = vec![Occupancy::Free; BOARD_SIZE * BOARD_SIZE];
-        let redpos = 15 * 32 + 4;
-        let bluepos = invert_pos(redpos);
-        board[redpos] = Occupancy::Occupied(Player::Red);
-        board[bluepos] = Occupancy::Occupied(Player::Blue);
-        Self {
-            pos: RedBlue {
-                red: redpos,
-                blue: bluepos,
-            },
-            endgame: None,
-            board,
+    // "WARNING", what went wrong, and how many more times it can go wrong
+    // before the client forfeits, as in "WARNING TIMEOUT 2"
+    pub async fn send_warning(
+        &mut self,
+        reason: ForfeitReason,
+        strikes_left: usize,
+    ) -> Result<(), io::Error> {
+        let reason = EndReason::Forfeit(reason).as_str();
+        match self.protocol {
+            Protocol::Json => {
+                self.send_json(&JsonMessage::Warning {
+                    reason,
+                    strikes_left,
+                })
+                .await
+            }
+            _ => {
+                self.send_update(format_args!("WARNING {} {}", reason, strikes_left))
+                    .await
+            }
         }
     }
 
-    // gives the message for red
-    // takes moves that have already been inverted
-    pub fn observe(&mut self, moves: RedBlue<Direction>) -> ToClientMessage {
-        if let Some(win) = self.endgame {
-            return ToClientMessage::End(win);
+    // Waits for the client to send "rematch" after a game, passing over any
+    // moves it sent before it saw the end
+    pub async fn wants_rematch(&mut self, deadline: time::Instant) -> bool {
+        loop {
+            match self.read_line_deadline(deadline).await {
+                Ok(Ok(())) if self.read_line.trim() == "rematch" => return true,
+                Ok(Ok(())) => continue,
+                _ => return false,
+            }
         }
+    }
 
-        let red_boundary = Self::boundary_collision(self.pos.red, moves.red);
-        let blue_boundary = Self::boundary_collision(self.pos.blue, moves.blue);
-
-        self.pos.red = Self::advance(self.pos.red, moves.red);
-        self.pos.blue = Self::advance(self.pos.blue, moves.blue);
+    // Forgets the last game, to play another over the same connection
+    pub fn start_over(&mut self) {
+        self.late_moves = 0;
+        self.view = BoardView::default();
+        self.board.clear();
+        self.clock = CLI_OPTIONS.time_bank.map(Duration::from_millis);
+    }
 
-        let mut red_collides = false;
-        let mut blue_collides = false;
+    // With --best-of, the next game's number and the score going into it, by
+    // the colors the players will have
+    pub async fn send_new_game(&mut self, score: &SeriesScore) -> Result<(), io::Error> {
+        match self.protocol {
+            Protocol::V1 | Protocol::V2 => {
+                let line = format_args!("NEWGAME {} {} {}", score.game, score.red, score.blue);
+                self.send_update(line).await
+            }
+            Protocol::Json => {
+                self.send_json(&JsonMessage::NewGame {
+                    game: score.game,
+                    red: score.red,
+                    blue: score.blue,
+                })
+                .await
+            }
+        }
+    }
 
-        // if we didnt check this, who won would depend on update order
-        if self.pos.red == self.pos.blue {
-            red_collides = true;
-            blue_collides = true;
+    // The result of a --best-of series, and the games won by each player
+    pub async fn send_series(
+        &mut self,
+        result: WinState,
+        you: usize,
+        opponent: usize,
+    ) -> Result<(), io::Error> {
+        let result = json_protocol::result(result);
+        match self.protocol {
+            Protocol::V1 | Protocol::V2 => {
+                let line = format_args!("SERIES {} {} {}", result, you, opponent);
+                self.send_update(line).await
+            }
+            Protocol::Json => {
+                self.send_json(&JsonMessage::Series {
+                    result,
+                    you,
+                    opponent,
+                })
+                .await
+            }
         }
+    }
 
-        if !red_boundary {
-            red_collides |= self.board[self.pos.red].occupied();
-            self.board[self.pos.red] = Occupancy::Occupied(Player::Red);
+    // Only clients that asked for a seat are told which one they got
+    pub async fn send_color(&mut self, seat: Player) -> Result<(), io::Error> {
+        if !self.tell_color {
+            return Ok(());
         }
-        if !blue_boundary {
-            blue_collides |= self.board[self.pos.blue].occupied();
-            self.board[self.pos.blue] = Occupancy::Occupied(Player::Blue);
+        let color = match seat {
+            Player::Red => "RED",
+            Player::Blue => "BLUE",
+        };
+        match self.protocol {
+            Protocol::V1 | Protocol::V2 => self.send_update(format_args!("COLOR {}", color)).await,
+            Protocol::Json => self.send_json(&JsonMessage::Color { color }).await,
         }
+    }
 
-        self.endgame = match (red_collides || red_boundary, blue_collides || blue_boundary) {
-            (true, true) => Some(WinState::Tie),
-            (false, false) => None,
-            (true, false) => Some(WinState::Loss),
-            (false, true) => Some(WinState::Win),
-        };
-        if let Some(win) = self.endgame {
-            ToClientMessage::End(win)
-        } else {
-            ToClientMessage::Update(self.pos.red, self.pos.blue)
+    // Only clients that asked for a newer protocol are told who they're
+    // playing, and sent the config
+    pub async fn send_opponent(&mut self, name: &str) -> Result<(), io::Error> {
+        match self.protocol {
+            Protocol::V1 => Ok(()),
+            Protocol::V2 => self.send_update(format_args!("OPPONENT {}", name)).await,
+            Protocol::Json => {
+                let name = name.to_owned();
+                self.send_json(&JsonMessage::Opponent { name }).await
+            }
         }
     }
 
-    pub fn position_update(&self) -> ToClientMessage {
-        ToClientMessage::Update(self.pos.red, self.pos.blue)
+    pub async fn send_config(&mut self, config: &Config) -> Result<(), io::Error> {
+        match self.protocol {
+            Protocol::V1 => Ok(()),
+            Protocol::V2 => self.send_positions(config, 4..6).await,
+            Protocol::Json => self.send_json(&json_protocol::config(config)).await,
+        }
     }
 
-    fn advance(pos: usize, d: Direction) -> usize {
-        let pos = pos as isize;
-        let bsize = BOARD_SIZE as isize;
-        use Direction::*;
-        (pos + match d {
-            Up => -bsize,
-            Down => bsize,
-            Left => -1,
-            Right => 1,
-        }) as usize
+    pub async fn send_rule(&mut self, rule: &Rule) -> Result<(), io::Error> {
+        match self.protocol {
+            Protocol::Json => self.send_json(&json_protocol::rule(rule)).await,
+            _ => match rule {
+                Rule::Walls(_) => self.send_positions(rule, 1..usize::MAX).await,
+                _ => self.send_update(rule).await,
+            },
+        }
     }
 
-    fn boundary_collision(pos: usize, d: Direction) -> bool {
-        use Direction::*;
-        match d {
-            Up => pos < BOARD_SIZE,
-            Down => pos > BOARD_SIZE * BOARD_SIZE - BOARD_SIZE,
-            Left => pos % BOARD_SIZE == 0,
-            Right => pos % BOARD_SIZE == BOARD_SIZE - 1,
+    // Sends a plain text line with positions in these words, which are
+    // written as "x y" pairs for clients that asked for COORDS
+    async fn send_positions(
+        &mut self,
+        line: impl std::fmt::Display,
+        positions: std::ops::Range<usize>,
+    ) -> Result<(), io::Error> {
+        if !self.coords {
+            return self.send_update(line).await;
         }
+        let width = CLI_OPTIONS.width();
+        let line = line.to_string();
+        let words: Vec<_> = line
+            .split(' ')
+            .enumerate()
+            .map(|(i, word)| match word.parse::<usize>() {
+                Ok(pos) if positions.contains(&i) => format!("{} {}", pos % width, pos / width),
+                _ => word.to_owned(),
+            })
+            .collect();
+        self.send_update(words.join(" ")).await
+    }
+
+    async fn send_json(&mut self, msg: &JsonMessage) -> Result<(), io::Error> {
+        let line = serde_json::to_string(msg).expect("JSON messages are always serializable");
+        self.send_update(line).await
     }
 
-    pub fn render_data(&self) -> RenderData {
-        RenderData {
-            width: BOARD_SIZE,
-            height: BOARD_SIZE,
-            data: self.board.clone(),
+    pub async fn read_direction(
+        &mut self,
+        deadline: std::time::Instant,
+    ) -> ClientResult<Direction> {
+        loop {
+            double_try!(self.read_move_line(deadline).await);
+            // a client can ask to see the board before it moves, as long as
+            // it still moves in time
+            if self.read_line.trim() == "board" {
+                trace!(client = %self.name, "received board query");
+                if self.send_board().await.is_err() {
+                    return Ok(Err(ForfeitReason::WriteFailure));
+                }
+                continue;
+            }
+            // skip moves that came in too late to count, so that a client
+            // that was let off a timeout doesn't stay a turn behind
+            if self.late_moves == 0 {
+                break;
+            }
+            self.late_moves -= 1;
+        }
+        trace!(client = %self.name, line = ?self.read_line, "received move");
+        if self.read_line == "resign\n" {
+            return Ok(Err(ForfeitReason::Resign));
+        }
+        if self.read_line.len() != 2 || !self.read_line.is_ascii() {
+            return Ok(Err(ForfeitReason::ParseError));
+        }
+        match Direction::from_char(self.read_line.chars().next().unwrap()) {
+            Some(d) => Ok(Ok(d)),
+            None => Ok(Err(ForfeitReason::ParseError)),
         }
     }
 
-    pub fn set_win_state(&mut self, w: WinState) {
-        self.endgame = Some(w);
+    async fn read_move_line(&mut self, deadline: time::Instant) -> ClientResult<()> {
+        let res = self.read_line_deadline(deadline).await;
+        if let Ok(Err(ForfeitReason::Timeout)) = res {
+            self.late_moves += 1;
+        }
+        res
     }
 
-    pub fn game_over(&self) -> bool {
-        self.endgame.is_some()
+    async fn read_line_deadline(&mut self, deadline: time::Instant) -> ClientResult<()> {
+        let mut line = std::mem::take(&mut self.read_line).into_bytes();
+        line.clear();
+        line.append(&mut self.unfinished);
+        let max = CLI_OPTIONS.max_line_length;
+        let read = read_line_into(&mut self.stream, max, &mut line, &mut self.skipping);
+        match before(&mut self.timer, deadline, read).await {
+            // a line cut off by the deadline is finished by the next read,
+            // rather than its end being taken for a line of its own
+            None => {
+                self.unfinished = line;
+                return Ok(Err(ForfeitReason::Timeout));
+            }
+            Some(Ok(LineRead::Eof)) => return Ok(Err(ForfeitReason::Eof)),
+            // a line that's too long is neither a move nor a name, and none
+            // of it is left to be read as the next line
+            Some(Ok(LineRead::TooLong)) => return Ok(Err(ForfeitReason::ParseError)),
+            Some(Ok(LineRead::Line)) => (),
+            // a connection that failed, like one the client reset, is as
+            // good as closed, and only costs this client the game
+            Some(Err(err)) => {
+                warn!(client = %self.name, %err, "Reading from client failed");
+                return Ok(Err(ForfeitReason::Eof));
+            }
+        }
+        // nor is a line that isn't text
+        let line = match String::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => return Ok(Err(ForfeitReason::ParseError)),
+        };
+        self.remember(">", &line);
+        self.read_line = line;
+        Ok(Ok(()))
     }
 }
 
-fn create_deadline() -> time::Instant {
-    let timeout = time::Duration::from_millis(CLI_OPTIONS.timeout);
-    time::Instant::now() + timeout
+// What reading a line from a client came to
+#[derive(Debug, PartialEq)]
+enum LineRead {
+    Line,
+    Eof,
+    TooLong,
 }
 
-// Removes losing failures
-fn handle_recv_failures<T>(
-    errs: RedBlue<Result<T, ClientRecvFailure>>,
-    game: &mut TronGame,
-) -> Result<RedBlue<T>, ClientRecvFailure> {
-    let losses = errs.as_ref().map(|res| res.is_err());
-    match (losses.red, losses.blue) {
-        (true, true) => game.set_win_state(WinState::Tie),
-        (false, false) => (),
-        (true, false) => game.set_win_state(WinState::Loss),
-        (false, true) => game.set_win_state(WinState::Win),
-    };
+// Reads up to the end of a line, of at most max bytes, onto what's already in
+// line. A longer line is read to its end and dropped, with skipping set until
+// it ends. Everything read is in line or skipping as soon as it's read, so a
+// read that's cancelled can be picked up again with the same two.
+async fn read_line_into<R: tokio::io::AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max: usize,
+    line: &mut Vec<u8>,
+    skipping: &mut bool,
+) -> io::Result<LineRead> {
+    loop {
+        let room = max.saturating_sub(line.len()) as u64;
+        if reader.take(room).read_until(b'\n', line).await? == 0 {
+            return Ok(LineRead::Eof);
+        }
+        if line.len() >= max && !line.ends_with(b"\n") {
+            line.clear();
+            *skipping = true;
+            continue;
+        }
+        if std::mem::take(skipping) {
+            line.clear();
+            return Ok(LineRead::TooLong);
+        }
+        return Ok(LineRead::Line);
+    }
+}
+
+#[test]
+fn long_lines() {
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        // a long line ending in a move, and then a move
+        let mut reader = tokio::io::BufReader::new(&b"abcdefghijklu\nd\n"[..]);
+        let (mut line, mut skipping) = (Vec::new(), false);
+        let read = read_line_into(&mut reader, 4, &mut line, &mut skipping);
+        assert_eq!(LineRead::TooLong, read.await.unwrap());
+        assert!(!skipping);
+        let read = read_line_into(&mut reader, 4, &mut line, &mut skipping);
+        assert_eq!(LineRead::Line, read.await.unwrap());
+        assert_eq!(b"d\n", &line[..]);
+    });
+}
+
+#[test]
+fn unfinished_lines() {
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let local = std::net::SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut listener = tokio::net::TcpListener::bind(local).await.unwrap();
+        let mut bot = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut server = tokio::io::BufReader::new(listener.accept().await.unwrap().0);
+        let (mut line, mut skipping) = (Vec::new(), false);
+        // the deadline passes halfway through a move
+        bot.write_all(b"u").await.unwrap();
+        let read = read_line_into(&mut server, 8, &mut line, &mut skipping);
+        let late = tokio::time::timeout(Duration::from_millis(50), read).await;
+        assert!(late.is_err());
+        bot.write_all(b"\nd\n").await.unwrap();
+        assert_eq!(
+            LineRead::Line,
+            read_line_into(&mut server, 8, &mut line, &mut skipping)
+                .await
+                .unwrap()
+        );
+        assert_eq!(b"u\n", &line[..]);
+        line.clear();
+        let read = read_line_into(&mut server, 8, &mut line, &mut skipping);
+        read.await.unwrap();
+        assert_eq!(b"d\n", &line[..]);
+    });
+}
+
+// Runs fut to completion, or returns None if the deadline passes first
+async fn before<F: Future>(
+    timer: &mut tokio::time::Delay,
+    deadline: time::Instant,
+    fut: F,
+) -> Option<F::Output> {
+    timer.reset(deadline.into());
+    futures::pin_mut!(fut);
+    match futures::future::select(fut, timer).await {
+        futures::future::Either::Left((out, _)) => Some(out),
+        futures::future::Either::Right(_) => None,
+    }
+}
+
+// Longest name a client is known by; the rest is cut off
+const MAX_NAME_LEN: usize = 32;
+
+// Keeps names safe to put in logs, files and web pages: anything but ASCII
+// letters, digits, '-', '_' and '.' becomes '_'
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .take(MAX_NAME_LEN)
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[test]
+fn names() {
+    assert_eq!("my_super_cool_bot", sanitize_name("my_super_cool_bot"));
+    assert_eq!("_script_alert__", sanitize_name("<script>alert()"));
+    assert_eq!("bot_v1.2", sanitize_name("bot v1.2"));
+    assert_eq!(MAX_NAME_LEN, sanitize_name(&"x".repeat(100)).len());
+}
+
+// for a client to send its name
+fn create_deadline() -> time::Instant {
+    let timeout =
+        time::Duration::from_millis(CLI_OPTIONS.name_timeout.unwrap_or(CLI_OPTIONS.timeout));
+    time::Instant::now() + timeout
+}
+
+// Removes losing failures
+fn handle_forfeits<T>(
+    errs: RedBlue<Result<T, ForfeitReason>>,
+    game: &mut impl Game,
+) -> Result<RedBlue<T>, ForfeitReason> {
+    game.forfeit(errs.as_ref().map(|res| res.as_ref().err().copied()));
     match errs {
         RedBlue {
             red: Ok(red),
@@ -431,139 +1523,2749 @@ fn handle_recv_failures<T>(
     }
 }
 
-#[derive(StructOpt, Debug)]
-#[structopt(name = "server")]
-struct Opt {
-    /// Game listen address and port number.
-    #[structopt(name = "BIND_ADDRESS", default_value = "127.0.0.1:4040")]
-    host: std::net::SocketAddr,
+// Most moves in a row a client can get wrong with --on-bad-move continue; it
+// forfeits on the next
+const MAX_BAD_MOVES: usize = 3;
 
-    /// Specifies the time limit for clients to respond to server messages,
-    /// in milliseconds.
-    #[structopt(long, default_value = "200")]
-    timeout: u64,
+// Lets a client that timed out or sent something unreadable go on in its
+// previous direction, or right on the first turn, unless it's done so too
+// many turns in a row. Other failures still forfeit.
+fn forgive_bad_moves(
+    moves: RedBlue<Result<Direction, ForfeitReason>>,
+    previous: RedBlue<Option<Direction>>,
+    bad_moves: &mut RedBlue<usize>,
+    turn: usize,
+    events: &mut EventLog,
+) -> Result<RedBlue<Result<Direction, ForfeitReason>>, io::Error> {
+    let mut forgive =
+        |player, res, previous: Option<Direction>, bad: &mut usize| -> Result<_, io::Error> {
+            let reason = match res {
+                Ok(d) => {
+                    *bad = 0;
+                    return Ok(Ok(d));
+                }
+                Err(reason @ ForfeitReason::Timeout) | Err(reason @ ForfeitReason::ParseError)
+                    if *bad < MAX_BAD_MOVES =>
+                {
+                    reason
+                }
+                Err(reason) => return Ok(Err(reason)),
+            };
+            *bad += 1;
+            let played = previous.unwrap_or(Direction::Right);
+            warn!(?player, "Playing {:?} after a bad move: {}", played, reason);
+            events.emit(GameEvent::BadMove {
+                turn,
+                player,
+                reason,
+                played,
+            })?;
+            Ok(Ok(played))
+        };
+    Ok(RedBlue {
+        red: forgive(Player::Red, moves.red, previous.red, &mut bad_moves.red)?,
+        blue: forgive(Player::Blue, moves.blue, previous.blue, &mut bad_moves.blue)?,
+    })
+}
 
-    /// Add this many milliseconds of extra delay each game loop. Useful for
-    /// slowing down the visualizer with fast bots.
-    #[structopt(long, default_value = "0")]
-    extra_delay: u64,
+// the strikes each client that was let off a timeout has left
+type Warnings = RedBlue<Option<usize>>;
 
-    /// Visualizer listen address and port number
-    #[structopt(long, default_value = "127.0.0.1:3030")]
-    visualizer_addr: std::net::SocketAddr,
+// With --timeout-strikes, lets a client that timed out go on in its previous
+// direction, or right on the first turn, until its last strike. Returns the
+// strikes each client that was let off has left.
+fn strike_timeouts(
+    moves: RedBlue<Result<Direction, ForfeitReason>>,
+    previous: RedBlue<Option<Direction>>,
+    strikes: &mut RedBlue<usize>,
+    turn: usize,
+    events: &mut EventLog,
+) -> Result<(RedBlue<Result<Direction, ForfeitReason>>, Warnings), io::Error> {
+    let max = CLI_OPTIONS.timeout_strikes.unwrap_or(1);
+    let mut strike = |player, res, previous: Option<Direction>, strikes: &mut usize| match res {
+        Err(reason @ ForfeitReason::Timeout) if *strikes + 1 < max => {
+            *strikes += 1;
+            let played = previous.unwrap_or(Direction::Right);
+            warn!(
+                ?player,
+                strikes = *strikes,
+                "Playing {:?} after a timeout",
+                played
+            );
+            events.emit(GameEvent::BadMove {
+                turn,
+                player,
+                reason,
+                played,
+            })?;
+            Ok((Ok(played), Some(max - *strikes - 1)))
+        }
+        res => Ok::<_, io::Error>((res, None)),
+    };
+    let red = strike(Player::Red, moves.red, previous.red, &mut strikes.red)?;
+    let blue = strike(Player::Blue, moves.blue, previous.blue, &mut strikes.blue)?;
+    Ok((
+        RedBlue {
+            red: red.0,
+            blue: blue.0,
+        },
+        RedBlue {
+            red: red.1,
+            blue: blue.1,
+        },
+    ))
 }
 
-lazy_static! {
-    static ref CLI_OPTIONS: Opt = Opt::from_args();
+// Applies --reversals to moves straight back the way a player came
+fn check_reversals(
+    moves: RedBlue<Result<Direction, ForfeitReason>>,
+    previous: RedBlue<Option<Direction>>,
+) -> RedBlue<Result<Direction, ForfeitReason>> {
+    let check = |res, previous: Option<Direction>| match (res, previous) {
+        (Ok(d), Some(prev)) if d == invert_direction(prev) => match CLI_OPTIONS.reversals {
+            Reversals::Crash => Ok(d),
+            Reversals::Lose => Err(ForfeitReason::RuleViolation),
+            Reversals::Ignore => Ok(prev),
+        },
+        (res, _) => res,
+    };
+    RedBlue {
+        red: check(moves.red, previous.red),
+        blue: check(moves.blue, previous.blue),
+    }
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    let (render_send, render_recv) = watch::channel(RenderData::game_start());
-    start_webserver(render_recv, CLI_OPTIONS.visualizer_addr);
-    thread::sleep(Duration::from_millis(10));
-    let bind_addr: std::net::SocketAddr = ([127, 0, 0, 1], 4040).into();
-    println!("Listening for player connections on {}", bind_addr);
-    let listener = TcpListener::bind(bind_addr)?;
-    println!("Waiting for player 1");
-    let (p1, _addr) = listener.accept()?;
-    println!("Waiting for player 2");
-    let (p2, _addr) = listener.accept()?;
-
-    let red_player = Client::new(p1)?;
-    let blue_player = Client::new(p2)?;
-
-    let game = TronGame::new();
-
-    play_game(red_player, blue_player, game, render_send)?;
-    println!("Game ended normally");
-    Ok(())
+#[derive(Debug, Serialize)]
+#[serde(tag = "event")]
+enum GameEvent<'a> {
+    Connected {
+        player: Player,
+        peer: std::net::SocketAddr,
+    },
+    Named {
+        player: Player,
+        name: &'a str,
+    },
+    // directions are as seen on the board, not as sent by the clients
+    Turn {
+        turn: usize,
+        red: Direction,
+        blue: Direction,
+        red_ms: f64,
+        blue_ms: f64,
+    },
+    // when each update went out and each reply came back, for settling
+    // timeout disputes. Emitted before the turn's Turn or Forfeit events.
+    Timing {
+        turn: usize,
+        red: Exchange,
+        blue: Exchange,
+    },
+    Forfeit {
+        player: Player,
+        reason: ForfeitReason,
+    },
+    // with --on-bad-move continue or --timeout-strikes, the move played for a
+    // client in place of one it failed to send, as the client would have
+    // sent it
+    BadMove {
+        turn: usize,
+        player: Player,
+        reason: ForfeitReason,
+        played: Direction,
+    },
+    // the game's settings, as the players are told them in version 2
+    Settings {
+        mode: Mode,
+        width: usize,
+        height: usize,
+        timeout: u64,
+        flags: &'a [&'static str],
+    },
+    // with --record, in replays only: the cells that changed on the board
+    // since the last Board event, by position, and what's in them now
+    Board {
+        turn: usize,
+        changes: Vec<(usize, Occupancy)>,
+    },
+    // everything besides the moves that the game's course depends on, and
+    // the protocol each player spoke
+    Setup {
+        #[serde(flatten)]
+        setup: &'a Setup,
+        protocols: RedBlue<Protocol>,
+    },
+    // with --resume, the replay the game went on from and the moves played
+    // in it, in its Turn events' terms. Emitted after Setup.
+    Resumed {
+        from: &'a str,
+        moves: &'a [RedBlue<Direction>],
+    },
+    // in replays only, last: the hash chain over every line before it, and
+    // with --signing-key, its signature
+    Sealed {
+        #[serde(flatten)]
+        seal: seal::Seal,
+    },
+    // walls from --obstacles, which this seed reproduces
+    Obstacles {
+        seed: u64,
+        density: f64,
+    },
+    // the server ended the match early; it's scored as a tie
+    Aborted {
+        cause: &'static str,
+    },
+    // the end of a --best-of or --games series, with each game's match_id in
+    // order. Players are named, since they swap colors between games, and
+    // color_wins counts the games won with each color.
+    Series {
+        matches: &'a [String],
+        players: [&'a str; 2],
+        wins: [usize; 2],
+        ties: usize,
+        color_wins: RedBlue<usize>,
+        average_turns: f64,
+        winner: Option<&'a str>,
+    },
+    // winner is None for a tie. end_reasons says what ended the loser's
+    // game, or both players' on a tie
+    Result {
+        winner: Option<Player>,
+        end_reasons: RedBlue<Option<EndReason>>,
+        turns: usize,
+        forfeits: RedBlue<Option<ForfeitReason>>,
+        response_times: RedBlue<ResponseStats>,
+        // with --ratings, what each player gained or lost
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rating_changes: Option<RedBlue<f64>>,
+    },
 }
 
-fn play_game(
-    mut red_player: Client,
-    mut blue_player: Client,
-    mut game: TronGame,
-    renderer: watch::Sender<RenderData>,
-) -> Result<(), anyhow::Error> {
-    println!("Reading names");
-    // start by getting names
-    let name_deadline = create_deadline();
-    let res = handle_recv_failures(
-        RedBlue {
-            red: red_player.recv_name(name_deadline)?,
-            blue: blue_player.recv_name(name_deadline)?,
-        },
-        &mut game,
-    );
-    if let Err(e) = res {
-        // game ends due to client failure of some kind, just inform the clients of that
-        let dummy_move = RedBlue {
-            red: Direction::Up,
-            blue: Direction::Up,
+// The rules a game was played by and the seed for where power-ups and apples
+// appear, which with the moves are enough to play the game over again
+#[derive(Debug, Serialize, Deserialize)]
+struct Setup {
+    mode: Mode,
+    width: usize,
+    height: usize,
+    seed: u64,
+    wrap: bool,
+    walls: Vec<usize>,
+    power_ups: Option<usize>,
+    shrink: Option<ShrinkSchedule>,
+    max_turns: Option<usize>,
+}
+
+impl Setup {
+    // the options' rules, with a fresh seed
+    fn new(width: usize, height: usize, map: Option<&Map>) -> Self {
+        let tron = CLI_OPTIONS.mode == Mode::Tron;
+        Setup {
+            mode: CLI_OPTIONS.mode,
+            width,
+            height,
+            seed: rand::random(),
+            wrap: CLI_OPTIONS.wrap,
+            walls: map.map(|map| map.walls.clone()).unwrap_or_default(),
+            power_ups: CLI_OPTIONS.power_ups.filter(|_| tron),
+            shrink: CLI_OPTIONS.shrink(),
+            max_turns: CLI_OPTIONS.max_turns,
+        }
+    }
+
+    fn game(&self) -> Box<dyn Game> {
+        let map = Map {
+            width: self.width,
+            height: self.height,
+            walls: self.walls.clone(),
         };
-        let msg = game.observe(dummy_move);
-        red_player.send_update(msg)?;
-        blue_player.send_update(invert_update(msg))?;
-        println!("Game ended due to {:?} while getting names", e);
-        return Ok(());
+        if self.mode == Mode::Snake {
+            let mut game = SnakeGame::seeded(self.width, self.height, self.seed);
+            game.set_wrap(self.wrap);
+            game.set_shrink(self.shrink);
+            game.set_max_turns(self.max_turns);
+            game.add_map(&map);
+            Box::new(game)
+        } else {
+            let mut game = TronGame::seeded(self.width, self.height, self.seed);
+            game.set_wrap(self.wrap);
+            game.set_power_ups(self.power_ups);
+            game.set_shrink(self.shrink);
+            game.set_max_turns(self.max_turns);
+            game.add_map(&map);
+            Box::new(game)
+        }
     }
+}
 
-    // initialize the game by sending initial positions
-    let red_update = game.position_update();
-    let blue_update = invert_update(red_update);
-    red_player
-        .send_update(red_update)
-        .and(blue_player.send_update(blue_update))?;
+// unix times in ms. received_ms is when the server read the reply, or gave up
+// waiting for it. Each client's deadline runs from when its own update was sent.
+#[derive(Debug, Serialize)]
+struct Exchange {
+    sent_ms: u128,
+    deadline_ms: u128,
+    received_ms: u128,
+}
 
-    // init renderer
-    renderer.broadcast(game.render_data())?;
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    timestamp_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    match_id: Option<&'a str>,
+    #[serde(flatten)]
+    event: GameEvent<'a>,
+}
 
-    // main game loop
-    while !game.game_over() {
-        println!("Begin loop iter");
-        // get client moves
-        let move_deadline = create_deadline();
-        let res = handle_recv_failures(
-            RedBlue {
-                red: red_player.read_direction(move_deadline)?,
-                blue: blue_player.read_direction(move_deadline)?,
-            },
-            &mut game,
-        );
-        let moves = match res {
-            Ok(mut rb) => {
-                rb.blue = invert_direction(rb.blue);
-                rb
-            }
-            Err(e) => {
-                // game is already over, clients will be notified on the next
-                // update. Give a dummy move to the already-ended game.
-                println!("Game ended due to {:?} while getting moves", e);
-                let dummy_move = RedBlue {
-                    red: Direction::Up,
-                    blue: Direction::Up,
+// Writes every game event as one line of JSON to a file and/or to any
+// consumers connected to the event socket. Matches played at once each
+// write through their own clone.
+#[derive(Clone)]
+struct EventLog {
+    file: Option<Arc<Mutex<io::LineWriter<File>>>>,
+    subscribers: Arc<Mutex<Vec<EventSubscriber>>>,
+    line: Vec<u8>,
+    match_id: Option<String>,
+    // with --record, this match's replay
+    replay: Option<Arc<Mutex<Recording>>>,
+}
+
+// A replay being written, and the hash chain over the lines written so far
+struct Recording {
+    file: io::LineWriter<File>,
+    chain: seal::Chain,
+}
+
+impl Recording {
+    fn write(&mut self, line: &[u8]) -> Result<(), io::Error> {
+        self.chain.add(line.strip_suffix(b"\n").unwrap_or(line));
+        self.file.write_all(line)
+    }
+}
+
+// The version of the replay format written with --record, in each replay's
+// first line. Bumped when a change would break a replay reader.
+const REPLAY_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct ReplayHeader<'a> {
+    format: &'static str,
+    version: u32,
+    match_id: &'a str,
+}
+
+// Where a match's replay is written, with --record
+fn replay_path(match_id: &str) -> Option<PathBuf> {
+    let dir = CLI_OPTIONS.record.as_ref()?;
+    Some(dir.join(format!("{}.jsonl", match_id)))
+}
+
+// Queues lines for one event socket consumer
+type EventSubscriber = mpsc::Sender<Arc<[u8]>>;
+
+// Lines buffered for an event socket consumer before it's dropped
+const EVENT_SUBSCRIBER_BACKLOG: usize = 256;
+
+impl EventLog {
+    pub async fn new(
+        path: Option<&PathBuf>,
+        socket: Option<std::net::SocketAddr>,
+    ) -> Result<Self, io::Error> {
+        let file = match path {
+            Some(path) => Some(Arc::new(Mutex::new(io::LineWriter::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            )))),
+            None => None,
+        };
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+        if let Some(addr) = socket {
+            let mut listener = tokio::net::TcpListener::bind(addr).await?;
+            info!("Streaming game events on {}", addr);
+            let subscribers = subscribers.clone();
+            tokio::spawn(async move {
+                loop {
+                    let mut stream = match listener.accept().await {
+                        Ok((stream, _)) => stream,
+                        Err(_) => continue,
+                    };
+                    let (send, mut recv) = mpsc::channel::<Arc<[u8]>>(EVENT_SUBSCRIBER_BACKLOG);
+                    subscribers.lock().unwrap().push(send);
+                    tokio::spawn(async move {
+                        while let Some(line) = recv.recv().await {
+                            if stream.write_all(&line).await.is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+            });
+        }
+        Ok(Self {
+            file,
+            subscribers,
+            line: Vec::new(),
+            match_id: None,
+            replay: None,
+        })
+    }
+
+    // The same log, with every event marked as part of this match. With
+    // --record, they're also written to the match's replay.
+    pub fn for_match(&self, match_id: &str) -> Result<Self, io::Error> {
+        let replay = match replay_path(match_id) {
+            Some(path) => {
+                // never write over another game's replay
+                let file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .map_err(|e| {
+                        io::Error::new(
+                            e.kind(),
+                            format!("can't create replay {}: {}", path.display(), e),
+                        )
+                    })?;
+                let mut recording = Recording {
+                    file: io::LineWriter::new(file),
+                    chain: seal::Chain::new(),
+                };
+                let header = ReplayHeader {
+                    format: "snake-ai-battle-replay",
+                    version: REPLAY_VERSION,
+                    match_id,
                 };
-                dummy_move
+                let mut line = serde_json::to_vec(&header)?;
+                line.push(b'\n');
+                recording.write(&line)?;
+                Some(Arc::new(Mutex::new(recording)))
             }
+            None => None,
         };
-        // update game state and send client
-        let red_update = game.observe(moves);
-        let blue_update = invert_update(red_update);
-        red_player
-            .send_update(red_update)
-            .and(blue_player.send_update(blue_update))?;
+        Ok(Self {
+            match_id: Some(match_id.to_owned()),
+            replay,
+            ..self.clone()
+        })
+    }
+
+    // whether Board events are wanted, since they're only written to replays
+    pub fn recording(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    pub fn emit(&mut self, event: GameEvent) -> Result<(), io::Error> {
+        let replay_only = matches!(event, GameEvent::Board { .. } | GameEvent::Sealed { .. });
+        let record = EventRecord {
+            timestamp_ms: unix_ms(),
+            match_id: self.match_id.as_deref(),
+            event,
+        };
+        self.line.clear();
+        serde_json::to_writer(&mut self.line, &record)?;
+        self.line.push(b'\n');
+        if let Some(replay) = &self.replay {
+            replay.lock().unwrap().write(&self.line)?;
+        }
+        if replay_only {
+            return Ok(());
+        }
+        if let Some(file) = &self.file {
+            file.lock().unwrap().write_all(&self.line)?;
+        }
+        // a consumer that can't keep up gets dropped rather than stalling the game
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if !subscribers.is_empty() {
+            let line: Arc<[u8]> = self.line.as_slice().into();
+            subscribers.retain_mut(|s| s.try_send(line.clone()).is_ok());
+        }
+        Ok(())
+    }
 
-        // update render state
-        renderer.broadcast(game.render_data())?;
+    // With --record, ends the replay with a seal over everything in it.
+    // Nothing more can be recorded after.
+    pub fn seal(&mut self) -> Result<(), io::Error> {
+        let seal = match &self.replay {
+            Some(replay) => replay
+                .lock()
+                .unwrap()
+                .chain
+                .seal(CLI_OPTIONS.signing_key.as_ref()),
+            None => return Ok(()),
+        };
+        self.emit(GameEvent::Sealed { seal })
+    }
 
-        // sleep if applicable
-        if CLI_OPTIONS.extra_delay > 0 {
-            std::thread::sleep(time::Duration::from_millis(CLI_OPTIONS.extra_delay));
+    // logs and emits an event for each player that forfeited
+    pub fn report_forfeits<T>(
+        &mut self,
+        res: &RedBlue<Result<T, ForfeitReason>>,
+    ) -> Result<(), io::Error> {
+        if let Err(reason) = res.red {
+            warn!(player = ?Player::Red, "Forfeit: {}", reason);
+            self.emit(GameEvent::Forfeit {
+                player: Player::Red,
+                reason,
+            })?;
         }
+        if let Err(reason) = res.blue {
+            warn!(player = ?Player::Blue, "Forfeit: {}", reason);
+            self.emit(GameEvent::Forfeit {
+                player: Player::Blue,
+                reason,
+            })?;
+        }
+        Ok(())
     }
-    // finalize render state
-    renderer.broadcast(game.render_data())?;
-    // hacky but whatever
-    std::thread::sleep(time::Duration::from_millis(10));
-    Ok(())
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+enum Disposition {
+    Played,
+    Rejected,
+    IdleDropped,
+    Disconnected,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp_ms: u128,
+    peer: std::net::SocketAddr,
+    name: Option<&'a str>,
+    seat: Option<Player>,
+    disposition: Disposition,
+}
+
+// Append-only record of every accepted connection and what became of it,
+// one JSON object per line.
+#[derive(Clone)]
+struct AuditLog(Option<Arc<Mutex<File>>>);
+
+impl AuditLog {
+    pub fn open(path: Option<&PathBuf>) -> Result<Self, io::Error> {
+        Ok(Self(match path {
+            Some(path) => Some(Arc::new(Mutex::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ))),
+            None => None,
+        }))
+    }
+
+    pub fn record(
+        &self,
+        peer: std::net::SocketAddr,
+        name: Option<&str>,
+        seat: Option<Player>,
+        disposition: Disposition,
+    ) -> Result<(), io::Error> {
+        let file = match &self.0 {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let mut line = serde_json::to_vec(&AuditRecord {
+            timestamp_ms: unix_ms(),
+            peer,
+            name,
+            seat,
+            disposition,
+        })?;
+        line.push(b'\n');
+        // one write per record so concurrent records never interleave
+        file.lock().unwrap().write_all(&line)
+    }
+
+    // records a client that failed to send its name
+    pub fn record_failed_handshake(
+        &self,
+        peer: std::net::SocketAddr,
+        reason: ForfeitReason,
+    ) -> Result<(), io::Error> {
+        let disposition = match reason {
+            ForfeitReason::Timeout => Disposition::IdleDropped,
+            ForfeitReason::Eof => Disposition::Disconnected,
+            _ => Disposition::Rejected,
+        };
+        self.record(peer, None, None, disposition)
+    }
+}
+
+// Open connections per address, and in total
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+// Holds one connection's place in a ConnectionCounts until dropped.
+struct ConnectionSlot {
+    counts: Arc<Mutex<ConnectionCounts>>,
+    ip: IpAddr,
+}
+
+impl ConnectionSlot {
+    // Returns None if either limit has already been reached
+    pub fn try_new(
+        counts: &Arc<Mutex<ConnectionCounts>>,
+        ip: IpAddr,
+        max_total: usize,
+        max_per_ip: usize,
+    ) -> Option<Self> {
+        let mut c = counts.lock().unwrap();
+        let for_ip = c.per_ip.get(&ip).copied().unwrap_or(0);
+        if c.total >= max_total || for_ip >= max_per_ip {
+            return None;
+        }
+        c.total += 1;
+        c.per_ip.insert(ip, for_ip + 1);
+        Some(Self {
+            counts: counts.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut c = self.counts.lock().unwrap();
+        c.total -= 1;
+        if let Some(n) = c.per_ip.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                c.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// Clients that have sent their names, waiting for a match in the order they
+// did so
+struct Lobby {
+    recv: mpsc::UnboundedReceiver<Client>,
+    // in each room, the next to play red while it waits for an opponent
+    waiting: HashMap<String, Client>,
+    // once set, anyone else who connects is turned away
+    seats_full: Arc<AtomicBool>,
+}
+
+impl Lobby {
+    // The next two clients in the same room to play; the first to have sent
+    // its name plays red. Nobody is lost if this is dropped before it
+    // finishes.
+    pub async fn next_match(&mut self) -> Result<RedBlue<Client>, io::Error> {
+        let gone = || io::Error::other("stopped accepting connections");
+        let (red, mut blue) = loop {
+            let client = self.recv.recv().await.ok_or_else(gone)?;
+            match self.waiting.remove(&client.room) {
+                Some(red) => break (red, client),
+                None => {
+                    info!(room = %client.room, "{} is waiting for an opponent", client.name);
+                    self.waiting.insert(client.room.clone(), client);
+                }
+            }
+        };
+        // so the players can be told apart
+        if blue.name == red.name {
+            // leaving room for the suffix; names are ASCII, so this cuts
+            // between characters
+            blue.name.truncate(MAX_NAME_LEN - 2);
+            blue.name.push_str("_2");
+        }
+        if swap_seats(red.color, blue.color) {
+            return Ok(RedBlue {
+                red: blue,
+                blue: red,
+            });
+        }
+        Ok(RedBlue { red, blue })
+    }
+
+    // Turns away everyone else, for a server that only plays one match
+    pub fn close(self, audit: &AuditLog) {
+        let mut recv = self.recv;
+        self.seats_full.store(true, Ordering::SeqCst);
+        // and those still waiting in other rooms
+        for client in self.waiting.values() {
+            info!("Rejecting {}, game is full", client.name);
+            let _ = audit.record(client.peer, Some(&client.name), None, Disposition::Rejected);
+        }
+        // clients that finished their handshake just as the seats filled up
+        let late_audit = audit.clone();
+        tokio::spawn(async move {
+            while let Some(client) = recv.recv().await {
+                info!("Rejecting {}, game is full", client.name);
+                let _ =
+                    late_audit.record(client.peer, Some(&client.name), None, Disposition::Rejected);
+            }
+        });
+    }
+}
+
+// Whether the second of two clients to arrive should play red, given the seats
+// they asked for. When they both want the same one, the first gets it.
+fn swap_seats(first: Option<Player>, second: Option<Player>) -> bool {
+    match (first, second) {
+        (Some(Player::Blue), Some(Player::Blue)) | (Some(Player::Red), _) => false,
+        (Some(Player::Blue), _) | (_, Some(Player::Red)) => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn seats() {
+    use Player::*;
+    assert!(!swap_seats(None, None));
+    assert!(!swap_seats(Some(Red), Some(Red)));
+    assert!(!swap_seats(Some(Blue), Some(Blue)));
+    assert!(!swap_seats(None, Some(Blue)));
+    assert!(swap_seats(Some(Blue), None));
+    assert!(swap_seats(Some(Blue), Some(Red)));
+    assert!(swap_seats(None, Some(Red)));
+}
+
+// Accepts connections in the background and puts clients in the lobby once
+// they've sent their names. Each handshake runs as its own task against its
+// own deadline, so a client that connects and goes quiet only holds up
+// itself, and the number of unfinished handshakes is capped.
+fn accept_players(
+    mut listener: tokio::net::TcpListener,
+    audit: &AuditLog,
+    ratings: &Ratings,
+) -> Lobby {
+    let counts = Arc::new(Mutex::new(ConnectionCounts::default()));
+    let seats_full = Arc::new(AtomicBool::new(false));
+    let (send, recv) = mpsc::unbounded_channel();
+
+    let acceptor_audit = audit.clone();
+    let acceptor_full = seats_full.clone();
+    let ratings = ratings.clone();
+    tokio::spawn(async move {
+        let audit = acceptor_audit;
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // most likely out of file descriptors, give it a moment
+                    warn!("Failed to accept connection: {}", e);
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let slot = if acceptor_full.load(Ordering::SeqCst) {
+                info!("Rejecting connection from {}, game is full", peer);
+                None
+            } else {
+                let slot = ConnectionSlot::try_new(
+                    &counts,
+                    peer.ip(),
+                    CLI_OPTIONS.max_pending_handshakes,
+                    CLI_OPTIONS.max_connections_per_ip,
+                );
+                if slot.is_none() {
+                    warn!("Rejecting connection from {}, too many pending", peer);
+                }
+                slot
+            };
+            let slot = match slot {
+                Some(slot) => slot,
+                None => {
+                    if let Err(e) = audit.record(peer, None, None, Disposition::Rejected) {
+                        warn!("Failed to write audit log: {}", e);
+                    }
+                    continue;
+                }
+            };
+            let send = send.clone();
+            let audit = audit.clone();
+            let ratings = ratings.clone();
+            let handshake = async move {
+                let _slot = slot;
+                let res = match Client::new(stream) {
+                    Ok(mut client) => client
+                        .recv_name(create_deadline())
+                        .await
+                        .map(|r| r.map(|_| client)),
+                    Err(e) => Err(e),
+                };
+                match res {
+                    Ok(Ok(client)) if suspended(&ratings, &client.name) => {
+                        info!(
+                            "Rejecting {}, suspended for breaking the protocol",
+                            client.name
+                        );
+                        let disposition = Disposition::Rejected;
+                        if let Err(e) = audit.record(peer, Some(&client.name), None, disposition) {
+                            warn!("Failed to write audit log: {}", e);
+                        }
+                    }
+                    Ok(Ok(client)) => {
+                        let _ = send.send(client);
+                    }
+                    Ok(Err(reason)) => {
+                        info!("Dropping connection: {}", reason);
+                        if let Err(e) = audit.record_failed_handshake(peer, reason) {
+                            warn!("Failed to write audit log: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Handshake failed: {}", e),
+                }
+            };
+            tokio::spawn(handshake.instrument(info_span!("handshake", %peer)));
+        }
+    });
+
+    Lobby {
+        recv,
+        waiting: HashMap::new(),
+        seats_full,
+    }
+}
+
+// With --suspend-after, whether a player has broken the protocol too often to
+// play again
+fn suspended(ratings: &Ratings, name: &str) -> bool {
+    CLI_OPTIONS
+        .suspend_after
+        .is_some_and(|limit| ratings.violations(name) >= limit)
+}
+
+fn unix_ms() -> u128 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+// With --record, the cells that changed since the board was last seen
+fn record_board(
+    events: &mut EventLog,
+    seen: &mut Vec<Occupancy>,
+    turn: usize,
+    board: &RenderData,
+) -> Result<(), io::Error> {
+    if !events.recording() {
+        return Ok(());
+    }
+    seen.resize(board.data.len(), Occupancy::Free);
+    let mut changes = Vec::new();
+    for (pos, (&now, seen)) in board.data.iter().zip(seen.iter_mut()).enumerate() {
+        if now != *seen {
+            *seen = now;
+            changes.push((pos, now));
+        }
+    }
+    events.emit(GameEvent::Board { turn, changes })
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// with any change to the players' ratings
+fn result_event(outcome: &Outcome, rating_changes: Option<RedBlue<f64>>) -> GameEvent<'static> {
+    let winner = match outcome.result {
+        WinState::Win => Some(Player::Red),
+        WinState::Loss => Some(Player::Blue),
+        WinState::Tie => None,
+    };
+    GameEvent::Result {
+        winner,
+        end_reasons: outcome.end_reasons,
+        turns: outcome.turns,
+        forfeits: outcome.forfeits,
+        response_times: outcome.response_times,
+        rating_changes,
+    }
+}
+
+// How long a client took to answer each update, in milliseconds
+#[derive(Debug, Default)]
+struct ResponseTimes {
+    samples: Vec<f64>,
+    timeouts: usize,
+    parse_errors: usize,
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+struct ResponseStats {
+    moves: usize,
+    timeouts: usize,
+    // malformed moves, whether or not --on-bad-move let them off
+    parse_errors: usize,
+    // spent on all the moves together
+    total_ms: f64,
+    min_ms: Option<f64>,
+    median_ms: Option<f64>,
+    p99_ms: Option<f64>,
+    max_ms: Option<f64>,
+}
+
+impl ResponseTimes {
+    pub fn record<T>(&mut self, ms: f64, res: &Result<T, ForfeitReason>) {
+        match res {
+            Ok(_) => self.samples.push(ms),
+            Err(ForfeitReason::Timeout) => self.timeouts += 1,
+            Err(ForfeitReason::ParseError) => self.parse_errors += 1,
+            Err(_) => (),
+        }
+    }
+
+    pub fn stats(&self) -> ResponseStats {
+        let mut sorted = self.samples.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        // nearest-rank percentile
+        let percentile = |p: f64| {
+            let rank = (p * sorted.len() as f64).ceil() as usize;
+            sorted.get(rank.max(1) - 1).copied()
+        };
+        ResponseStats {
+            moves: sorted.len(),
+            timeouts: self.timeouts,
+            parse_errors: self.parse_errors,
+            total_ms: sorted.iter().sum(),
+            min_ms: sorted.first().copied(),
+            median_ms: percentile(0.5),
+            p99_ms: percentile(0.99),
+            max_ms: sorted.last().copied(),
+        }
+    }
+}
+
+#[test]
+fn response_stats() {
+    let mut times = ResponseTimes::default();
+    assert_eq!(None, times.stats().median_ms);
+    for i in 1..=100 {
+        times.record(i as f64, &Ok(()));
+    }
+    times.record(200.0, &Err::<(), _>(ForfeitReason::Timeout));
+    let stats = times.stats();
+    assert_eq!(100, stats.moves);
+    assert_eq!(1, stats.timeouts);
+    assert_eq!(5050.0, stats.total_ms);
+    assert_eq!(Some(1.0), stats.min_ms);
+    assert_eq!(Some(50.0), stats.median_ms);
+    assert_eq!(Some(99.0), stats.p99_ms);
+    assert_eq!(Some(100.0), stats.max_ms);
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Validate the options, report any problems, and exit without
+    /// starting a game.
+    CheckConfig,
+    /// Measure engine turns per second and the round trip time of a turn
+    /// against a dummy client over loopback, then exit.
+    Bench {
+        /// Games for the engine to play against itself.
+        #[structopt(long, default_value = "10000")]
+        games: usize,
+
+        /// Positions to time the territory flood fill on.
+        #[structopt(long, default_value = "100000")]
+        positions: usize,
+
+        /// Updates to exchange with the dummy client.
+        #[structopt(long, default_value = "10000")]
+        round_trips: usize,
+    },
+    /// Play back a game recorded with --record in the visualizer. Enter
+    /// pauses and resumes playback.
+    Replay {
+        /// The replay to play back.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// How much faster than it was played to play the game back, like 2x
+        /// or 0.5x.
+        #[structopt(long, default_value = "1x", parse(try_from_str = parse_speed))]
+        speed: f64,
+
+        /// Wait for Enter before playing.
+        #[structopt(long)]
+        paused: bool,
+    },
+    /// Print statistics on a game recorded with --record: its length, what
+    /// ended it, response times, and territory over time.
+    Analyze {
+        /// The replay to analyze.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Play a game recorded with --record over again with the engine, and
+    /// check that every board and the result match the recording.
+    Verify {
+        /// The replay to verify.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Only accept a replay signed with this key, as 64 hex digits.
+        #[structopt(long, parse(try_from_str = seal::parse_public_key))]
+        public_key: Option<String>,
+    },
+    /// Play one side of a game recorded with --record to a bot that connects,
+    /// as if it were live, and score its moves against the recorded ones.
+    /// The game goes on as recorded, whatever the bot answers.
+    Quiz {
+        /// The replay to play.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// The side the bot plays: red or blue.
+        #[structopt(long, default_value = "red", parse(try_from_str = parse_seat))]
+        seat: Player,
+    },
+    /// Compare two games recorded with --record under the same setup and
+    /// seed, as a bot's games before and after a change: the first turn
+    /// their moves differ on, how each game ended, and territory from there.
+    Diff {
+        /// The first replay.
+        #[structopt(parse(from_os_str))]
+        first: PathBuf,
+
+        /// The replay to compare it with.
+        #[structopt(parse(from_os_str))]
+        second: PathBuf,
+    },
+    /// Tally how games recorded with --record opened: the moves of their
+    /// first turns, how often each opening was played, and how its games
+    /// ended, for each set of rules. Prints JSON.
+    Openings {
+        /// The replays to tally.
+        #[structopt(parse(from_os_str), required = true)]
+        files: Vec<PathBuf>,
+
+        /// Turns that make up an opening.
+        #[structopt(long, default_value = "4", parse(try_from_str = parse_turns))]
+        turns: usize,
+    },
+    /// Copy a game recorded with --record without what identifies the
+    /// players, for publishing: their names become "red" and "blue", and
+    /// their addresses are dropped.
+    Anonymize {
+        /// The replay to anonymize.
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Where to write the copy, which mustn't exist yet.
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "server")]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    /// Game listen address and port number. Use 0.0.0.0 to accept clients
+    /// from other machines.
+    #[structopt(name = "BIND_ADDRESS", default_value = "127.0.0.1:4040")]
+    host: std::net::SocketAddr,
+
+    /// Specifies the time limit for clients to respond to server messages,
+    /// in milliseconds.
+    #[structopt(long, default_value = "200")]
+    timeout: u64,
+
+    /// Time limit for a client's first move, in milliseconds, for bots that
+    /// need to warm up. Defaults to --timeout. It isn't taken off a
+    /// --time-bank clock.
+    #[structopt(long)]
+    first_move_timeout: Option<u64>,
+
+    /// Time limit for a client to send its name after connecting, in
+    /// milliseconds. Defaults to --timeout.
+    #[structopt(long)]
+    name_timeout: Option<u64>,
+
+    /// Longest line a client may send, in bytes, including the line feed.
+    /// A client that sends a longer one forfeits as if it sent a bad move.
+    #[structopt(long, default_value = "1024", parse(try_from_str = parse_line_length))]
+    max_line_length: usize,
+
+    /// Give each player this many milliseconds to spend on all their moves,
+    /// like a chess clock, instead of --timeout for each. A player whose
+    /// clock runs out forfeits. Clients are sent "TIME" and both players'
+    /// time left before each set of positions.
+    #[structopt(long)]
+    time_bank: Option<u64>,
+
+    /// Ping clients that ask for it by sending PING in their PROTOCOL line,
+    /// before the game and every 50 turns, and give them their round trip
+    /// time on top of each move's time limit, up to this many milliseconds.
+    #[structopt(long)]
+    latency_compensation: Option<u64>,
+
+    /// Milliseconds added to a player's --time-bank clock for each move it
+    /// makes in time.
+    #[structopt(long, requires = "time-bank")]
+    increment: Option<u64>,
+
+    /// Greet each client as it connects with a line giving the newest
+    /// protocol version, the board's width and height, and the timeout, as
+    /// in "TRON 2 32 32 200". Older bots don't expect it.
+    #[structopt(long)]
+    banner: bool,
+
+    /// Which game to play: "tron", or "snake" for classic snake with apples
+    /// and moving tails.
+    #[structopt(long, default_value = "tron", possible_values = &["tron", "snake"])]
+    mode: Mode,
+
+    /// What happens when a client times out or sends something that isn't a
+    /// move: "lose", or "continue" to play its previous move again for it.
+    /// With "continue", a client forfeits only if it makes more than 3 bad
+    /// moves in a row, disconnects, or resigns.
+    #[structopt(long, default_value = "lose", possible_values = &["lose", "continue"])]
+    on_bad_move: OnBadMove,
+
+    /// Let a client time out this many times in a game before it forfeits.
+    /// Until then, it goes on in the direction it moved last turn, and it's
+    /// sent "WARNING TIMEOUT" and the timeouts it has left.
+    #[structopt(long, parse(try_from_str = parse_strikes))]
+    timeout_strikes: Option<usize>,
+
+    /// What happens when a client turns straight back the way it came:
+    /// "crash" into its own trail or body as usual, "lose" by forfeit for an
+    /// illegal move, or "ignore" the turn and keep going straight.
+    #[structopt(long, default_value = "crash", possible_values = &["crash", "lose", "ignore"])]
+    reversals: Reversals,
+
+    /// Width and height of the board, in cells. Clients are told in the
+    /// CONFIG line of protocol version 2 and later, and in the --banner, but
+    /// version 1 bots have to expect it.
+    #[structopt(long, default_value = "32", parse(try_from_str = parse_board_size))]
+    board_size: usize,
+
+    /// Board width in cells, for a rectangular board. Defaults to
+    /// --board-size.
+    #[structopt(long, parse(try_from_str = parse_board_size))]
+    width: Option<usize>,
+
+    /// Board height in cells, for a rectangular board. Defaults to
+    /// --board-size.
+    #[structopt(long, parse(try_from_str = parse_board_size))]
+    height: Option<usize>,
+
+    /// Play on the map in this file: an ASCII grid, one line per row, with
+    /// walls marked '#' and free cells '.'. The board is the map's size.
+    /// Clients are sent "WALLS" and the walls' positions before the first
+    /// positions.
+    #[structopt(
+        long,
+        parse(try_from_os_str = load_map),
+        conflicts_with_all = &["board-size", "width", "height"]
+    )]
+    map: Option<Map>,
+
+    /// Scatter walls over about this fraction of the board, from 0 to 0.5,
+    /// laid out the same for both players. Sent to clients like --map's.
+    #[structopt(long, parse(try_from_str = parse_density), conflicts_with = "map")]
+    obstacles: Option<f64>,
+
+    /// Go on from where the game in this replay, recorded with --record,
+    /// left off: the last position before it ended, or the one after
+    /// --resume-turn turns. The board, walls and seed are the replay's, and
+    /// the other rules' options have to match the ones it was played with.
+    /// Turns are numbered on from the replay's.
+    #[structopt(
+        long,
+        parse(try_from_os_str = load_resume),
+        conflicts_with_all = &["board-size", "width", "height", "map", "obstacles"]
+    )]
+    resume: Option<replay::Prefix>,
+
+    /// With --resume, the turn of the replay's game to go on from.
+    #[structopt(long, requires = "resume")]
+    resume_turn: Option<usize>,
+
+    /// Seed for --obstacles, to repeat a layout. A random one is used and
+    /// logged if not given.
+    #[structopt(long, requires = "obstacles")]
+    seed: Option<u64>,
+
+    /// Let players wrap around the edges of the board instead of crashing
+    /// into them. Clients are sent "WRAP" before the first positions.
+    #[structopt(long)]
+    wrap: bool,
+
+    /// Sudden death: after this many turns, the outermost ring of the board
+    /// turns into walls, and then the next ring in every --shrink-every
+    /// turns. Clients are sent "SHRINK" and the schedule before the first
+    /// positions.
+    #[structopt(long)]
+    shrink_after: Option<usize>,
+
+    /// Turns between rings closing, with --shrink-after.
+    #[structopt(long, default_value = "10", parse(try_from_str = parse_turns))]
+    shrink_every: usize,
+
+    /// Put a pair of power-ups on the board every this many turns, in tron
+    /// mode: a boost, which carries its player an extra cell that turn, or a
+    /// bomb, which clears the trails around it. Clients are sent "BOOST" or
+    /// "BOMB" and their positions before the positions on the turn they
+    /// appear.
+    #[structopt(long, parse(try_from_str = parse_turns))]
+    power_ups: Option<usize>,
+
+    /// End the game after this many turns. The player who can reach more of
+    /// the board first wins in tron mode, and the longer snake in snake mode,
+    /// which otherwise ends after one turn per cell. Clients are sent their
+    /// result followed by "TURN_LIMIT".
+    #[structopt(long, parse(try_from_str = parse_turns))]
+    max_turns: Option<usize>,
+
+    /// Play at most this many turns per second, however fast the bots
+    /// answer. Useful for slowing down the visualizer with fast bots.
+    #[structopt(long, parse(try_from_str = parse_tick_rate))]
+    tick_rate: Option<f64>,
+
+    /// Report panics and unexpected I/O errors, with the match id and the
+    /// last lines exchanged with each client, to this Sentry-compatible DSN
+    /// (https://<key>@<host>/<project>).
+    #[structopt(long)]
+    error_report_dsn: Option<String>,
+
+    /// POST each game's players, result and length as JSON to this URL when
+    /// it finishes.
+    #[structopt(long)]
+    result_webhook: Option<String>,
+
+    /// Post to this Discord webhook as each game starts and finishes.
+    #[structopt(long)]
+    discord_webhook: Option<String>,
+
+    /// The visualizer's address as others reach it, for links in Discord
+    /// posts, like https://tron.example.com. By default, --visualizer-addr.
+    #[structopt(long)]
+    public_url: Option<String>,
+
+    /// Call a match a tie once it has run for this many seconds.
+    #[structopt(long)]
+    max_match_duration: Option<u64>,
+
+    /// Keep hosting matches one after another instead of exiting after the
+    /// first. Clients that connect during a match wait for the next one.
+    #[structopt(long)]
+    serve_forever: bool,
+
+    /// Most matches to play at once with --serve-forever. The visualizer
+    /// lists them at /games, and shows each at /game/<id>.
+    #[structopt(long, default_value = "1", parse(try_from_str = parse_games))]
+    max_games: usize,
+
+    /// After a match, wait as long as for a name for both players to send
+    /// "rematch", and play them again with colors swapped.
+    #[structopt(long)]
+    rematches: bool,
+
+    /// Play each pair of players this many games, alternating colors, or until
+    /// one has won most of them.
+    #[structopt(long, conflicts_with = "rematches", parse(try_from_str = parse_games))]
+    best_of: Option<usize>,
+
+    /// Keep Elo ratings by player name in this JSON file, updated after every
+    /// game, along with each player's timeouts, malformed moves and
+    /// disconnects. The visualizer lists them at /ratings.
+    #[structopt(long)]
+    ratings: Option<PathBuf>,
+
+    /// Turn away players whose timeouts, malformed moves and disconnects in
+    /// --ratings add up to this many. To let a player back in, take its counts
+    /// out of the file while the server is stopped.
+    #[structopt(long, requires = "ratings")]
+    suspend_after: Option<usize>,
+
+    /// Play each pair of players this many games, alternating colors, and
+    /// print how many each won and how long the games lasted on average.
+    #[structopt(
+        long,
+        conflicts_with_all = &["best-of", "rematches"],
+        parse(try_from_str = parse_games)
+    )]
+    games: Option<usize>,
+
+    /// Visualizer listen address and port number
+    #[structopt(long, default_value = "127.0.0.1:3030")]
+    visualizer_addr: std::net::SocketAddr,
+
+    /// Token that POSTs to the visualizer's controls must send, as
+    /// "Authorization: Bearer <token>". Without one, or a control token in
+    /// --tokens, a random token is made up when the controls are on, and
+    /// logged with -v.
+    #[structopt(long)]
+    control_token: Option<String>,
+
+    /// Keep named tokens for the visualizer in this JSON file, as SHA-256
+    /// hashes with a scope, "read" or "control". Tokens can be issued,
+    /// rotated and revoked at /tokens while games go on.
+    #[structopt(long)]
+    tokens: Option<PathBuf>,
+
+    /// Let live games be paused between turns and stepped a turn at a time,
+    /// by POSTing to /debug/<id>/pause, /debug/<id>/step and
+    /// /debug/<id>/resume on the visualizer with the --control-token.
+    /// Clients' deadlines don't start until they're sent the turn.
+    #[structopt(long, conflicts_with = "no-visualizer")]
+    debug_controls: bool,
+
+    /// Don't run the visualizer, for headless machines.
+    #[structopt(long, conflicts_with_all = &["visualizer-addr", "tls-cert"])]
+    no_visualizer: bool,
+
+    /// Append every game event as newline-delimited JSON to this file.
+    #[structopt(long, parse(from_os_str))]
+    event_log: Option<PathBuf>,
+
+    /// Write a replay of each game to <match id>.jsonl in this directory.
+    #[structopt(long, parse(from_os_str))]
+    record: Option<PathBuf>,
+
+    /// Sign each replay's seal with the Ed25519 key in this file, in PKCS#8
+    /// DER as written by "openssl genpkey -algorithm ed25519 -outform DER".
+    /// `verify` shows the public key a replay was signed with.
+    #[structopt(long, parse(try_from_os_str = seal::load_key))]
+    signing_key: Option<ring::signature::Ed25519KeyPair>,
+
+    /// Stream newline-delimited JSON game events to any client that
+    /// connects to this address.
+    #[structopt(long)]
+    event_socket: Option<std::net::SocketAddr>,
+
+    /// Append a JSON line to this file for every accepted connection, with
+    /// its address, name, seat, and whether it played or was dropped.
+    #[structopt(long, parse(from_os_str))]
+    audit_log: Option<PathBuf>,
+
+    /// Most game connections that may be connected but not yet named at
+    /// once. Further connections are turned away.
+    #[structopt(long, default_value = "16")]
+    max_pending_handshakes: usize,
+
+    /// Most connections a single address may hold open on the visualizer,
+    /// or hold unnamed on the game port.
+    #[structopt(long, default_value = "8")]
+    max_connections_per_ip: usize,
+
+    /// Most connections the visualizer accepts at once.
+    #[structopt(long, default_value = "256")]
+    max_visualizer_connections: usize,
+
+    /// Leave Nagle's algorithm on for game connections. By default
+    /// TCP_NODELAY is set so each line goes out as soon as it's written.
+    #[structopt(long)]
+    nagle: bool,
+
+    /// Kernel receive buffer size for game connections, in bytes.
+    #[structopt(long)]
+    socket_recv_buffer: Option<usize>,
+
+    /// Kernel send buffer size for game connections, in bytes.
+    #[structopt(long)]
+    socket_send_buffer: Option<usize>,
+
+    /// Send TCP keepalive probes on game connections after this many idle
+    /// seconds.
+    #[structopt(long)]
+    tcp_keepalive: Option<u64>,
+
+    /// Serve the visualizer over HTTPS with this PEM certificate chain.
+    /// Requires --tls-key.
+    #[structopt(long, parse(from_os_str), requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key (PKCS#8 or RSA) for --tls-cert.
+    #[structopt(long, parse(from_os_str), requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Log more. Repeat for even more (-vv logs every line received).
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Log less. Repeat to only log errors.
+    #[structopt(short, long, parse(from_occurrences))]
+    quiet: u8,
+
+    /// Extra per-module log filter directives, e.g. "warp=debug". Uses the
+    /// same syntax as RUST_LOG, which is also honored.
+    #[structopt(long)]
+    log_filter: Option<String>,
+
+    /// Log output format: "text" or "json" (one object per line, with span
+    /// fields such as player names and turn number).
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    log_format: LogFormat,
+
+    /// Export match and turn spans to this OTLP/HTTP traces endpoint, e.g.
+    /// http://127.0.0.1:4318/v1/traces.
+    #[cfg(feature = "otel")]
+    #[structopt(long)]
+    otlp_endpoint: Option<String>,
+}
+
+impl Opt {
+    pub fn width(&self) -> usize {
+        match (&self.map, &self.resume) {
+            (Some(map), _) => map.width,
+            (_, Some(prefix)) => prefix.setup.width,
+            _ => self.width.unwrap_or(self.board_size),
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match (&self.map, &self.resume) {
+            (Some(map), _) => map.height,
+            (_, Some(prefix)) => prefix.setup.height,
+            _ => self.height.unwrap_or(self.board_size),
+        }
+    }
+
+    pub fn shrink(&self) -> Option<ShrinkSchedule> {
+        self.shrink_after.map(|after| ShrinkSchedule {
+            after,
+            every: self.shrink_every,
+        })
+    }
+
+    pub fn public_url(&self) -> String {
+        match &self.public_url {
+            Some(url) => url.trim_end_matches('/').to_owned(),
+            None => {
+                let scheme = if self.tls_cert.is_some() {
+                    "https"
+                } else {
+                    "http"
+                };
+                format!("{}://{}", scheme, self.visualizer_addr)
+            }
+        }
+    }
+}
+
+// Which game --mode plays
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    Tron,
+    // classic snake, with apples and moving tails
+    Snake,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "tron" => Ok(Mode::Tron),
+            "snake" => Ok(Mode::Snake),
+            _ => Err("expected tron or snake".to_owned()),
+        }
+    }
+}
+
+// What --on-bad-move does with a timeout or a message that isn't a move
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum OnBadMove {
+    Lose,
+    // play the client's previous move again for it
+    Continue,
+}
+
+impl std::str::FromStr for OnBadMove {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "lose" => Ok(OnBadMove::Lose),
+            "continue" => Ok(OnBadMove::Continue),
+            _ => Err("expected lose or continue".to_owned()),
+        }
+    }
+}
+
+// What --reversals does with a turn straight back the way a player came
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Reversals {
+    // into the player's own trail or body, as usual
+    Crash,
+    // by forfeit, for an illegal move
+    Lose,
+    // the turn, going on straight
+    Ignore,
+}
+
+impl std::str::FromStr for Reversals {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "crash" => Ok(Reversals::Crash),
+            "lose" => Ok(Reversals::Lose),
+            "ignore" => Ok(Reversals::Ignore),
+            _ => Err("expected crash, lose or ignore".to_owned()),
+        }
+    }
+}
+
+// How --log-format writes logs
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    // one object per line
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected text or json".to_owned()),
+        }
+    }
+}
+
+fn parse_board_size(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(size) if (4..=1024).contains(&size) => Ok(size),
+        _ => Err("expected a board size from 4 to 1024".to_owned()),
+    }
+}
+
+fn load_map(path: &std::ffi::OsStr) -> Result<Map, std::ffi::OsString> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("couldn't read {}: {}", path.to_string_lossy(), e))?;
+    Map::parse(&text).map_err(|e| format!("bad map {}: {}", path.to_string_lossy(), e).into())
+}
+
+fn load_resume(path: &std::ffi::OsStr) -> Result<replay::Prefix, std::ffi::OsString> {
+    replay::prefix(std::path::Path::new(path)).map_err(|e| e.to_string().into())
+}
+
+fn parse_density(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(density) if (0.0..=0.5).contains(&density) => Ok(density),
+        _ => Err("expected a density from 0 to 0.5".to_owned()),
+    }
+}
+
+fn parse_turns(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(every) if every > 0 => Ok(every),
+        _ => Err("expected a positive number of turns".to_owned()),
+    }
+}
+
+fn parse_seat(s: &str) -> Result<Player, String> {
+    match s {
+        "red" => Ok(Player::Red),
+        "blue" => Ok(Player::Blue),
+        _ => Err("expected red or blue".to_owned()),
+    }
+}
+
+fn parse_line_length(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        // room for "resign" and the line feed
+        Ok(len) if len >= 8 => Ok(len),
+        _ => Err("expected a line length of at least 8 bytes".to_owned()),
+    }
+}
+
+fn parse_games(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(games) if games > 0 => Ok(games),
+        _ => Err("expected a positive number of games".to_owned()),
+    }
+}
+
+fn parse_strikes(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(strikes) if strikes > 0 => Ok(strikes),
+        _ => Err("expected a positive number of strikes".to_owned()),
+    }
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('x').parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err("expected a speed like 2x or 0.5x".to_owned()),
+    }
+}
+
+fn parse_tick_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(hz) if hz > 0.0 && hz.is_finite() => Ok(hz),
+        _ => Err("expected a positive number of turns per second".to_owned()),
+    }
+}
+
+lazy_static! {
+    static ref CLI_OPTIONS: Opt = Opt::from_args();
+}
+
+// Keeps log exporters alive until the end of main.
+struct LogGuard {
+    #[cfg(feature = "otel")]
+    _otel: otel::Guard,
+}
+
+fn init_logging(opt: &Opt) -> Result<LogGuard, anyhow::Error> {
+    let level = match opt.verbose as i16 - opt.quiet as i16 {
+        i16::MIN..=-2 => "error",
+        -1 => "warn",
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    // other crates stay quiet unless asked for via --log-filter or RUST_LOG
+    let mut filter = tracing_subscriber::EnvFilter::new(format!(
+        "{},server={}",
+        if level == "error" { "error" } else { "warn" },
+        level
+    ));
+    let env = std::env::var("RUST_LOG").unwrap_or_default();
+    for directive in env
+        .split(',')
+        .chain(opt.log_filter.iter().flat_map(|f| f.split(',')))
+    {
+        if !directive.is_empty() {
+            filter = filter.add_directive(directive.parse()?);
+        }
+    }
+    let (text, json) = if opt.log_format == LogFormat::Json {
+        (None, Some(tracing_subscriber::fmt::layer().json()))
+    } else {
+        (Some(tracing_subscriber::fmt::layer()), None)
+    };
+    // Option has its own and_then, so call through the Layer trait
+    let fmt_layer = Layer::with_filter(Layer::and_then(text, json), filter);
+    let registry = tracing_subscriber::registry().with(fmt_layer);
+    #[cfg(feature = "otel")]
+    {
+        let (layer, guard) = otel::layer(opt.otlp_endpoint.as_deref())?;
+        registry.with(layer).init();
+        Ok(LogGuard { _otel: guard })
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        Ok(LogGuard {})
+    }
+}
+
+// Checks that an output file could be opened for appending, without
+// creating it.
+fn check_output_file(path: &std::path::Path) -> Result<(), String> {
+    if path.exists() {
+        return std::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map(|_| ())
+            .map_err(|e| format!("can't open {} for writing: {}", path.display(), e));
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    match std::fs::metadata(dir) {
+        Ok(meta) if meta.is_dir() && !meta.permissions().readonly() => Ok(()),
+        Ok(_) => Err(format!("{} is not a writable directory", dir.display())),
+        Err(e) => Err(format!("can't create {}: {}", path.display(), e)),
+    }
+}
+
+// A directory for output files, which is made, along with any missing
+// parents, if it doesn't exist yet
+fn check_output_dir(dir: &std::path::Path) -> Result<(), String> {
+    match std::fs::metadata(dir) {
+        Ok(meta) if meta.is_dir() && !meta.permissions().readonly() => Ok(()),
+        Ok(_) => Err(format!("{} is not a writable directory", dir.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match dir.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => check_output_dir(parent),
+            _ => check_output_dir(std::path::Path::new(".")),
+        },
+        Err(e) => Err(format!("can't use {}: {}", dir.display(), e)),
+    }
+}
+
+// The clients are told the rules from the options, so they have to be the
+// ones the game --resume goes on from was played by
+fn check_resume(opt: &Opt) -> Result<(), String> {
+    let prefix = match &opt.resume {
+        Some(prefix) => prefix,
+        None => return Ok(()),
+    };
+    let played = prefix.moves.len();
+    if opt.resume_turn.is_some_and(|turn| turn > played) {
+        return Err(format!(
+            "the replay's game doesn't go on past turn {}",
+            played
+        ));
+    }
+    check_rules(opt, &prefix.setup)
+}
+
+// whether the options are the rules of a recorded game, besides its walls
+fn check_rules(opt: &Opt, setup: &Setup) -> Result<(), String> {
+    let mut differ = Vec::new();
+    if (setup.width, setup.height) != (opt.width(), opt.height()) {
+        differ.push("--board-size, --width and --height");
+    }
+    if setup.mode != opt.mode {
+        differ.push("--mode");
+    }
+    if setup.wrap != opt.wrap {
+        differ.push("--wrap");
+    }
+    if setup.power_ups != opt.power_ups.filter(|_| opt.mode == Mode::Tron) {
+        differ.push("--power-ups");
+    }
+    if setup.shrink != opt.shrink() {
+        differ.push("--shrink-after and --shrink-every");
+    }
+    if setup.max_turns != opt.max_turns {
+        differ.push("--max-turns");
+    }
+    if !differ.is_empty() {
+        return Err(format!(
+            "the replay's game was played with other {}",
+            differ.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn check_config(opt: &Opt) -> Result<(), anyhow::Error> {
+    let mut problems = Vec::new();
+    if opt.timeout == 0 {
+        problems.push("--timeout is 0, every client would time out".to_owned());
+    }
+    if opt.socket_recv_buffer == Some(0) || opt.socket_send_buffer == Some(0) {
+        problems.push("socket buffer sizes must be greater than 0".to_owned());
+    }
+    if opt.tcp_keepalive == Some(0) {
+        problems.push("--tcp-keepalive must be at least 1 second".to_owned());
+    }
+    if opt.power_ups.is_some() && opt.mode != Mode::Tron {
+        problems.push("--power-ups only applies in tron mode".to_owned());
+    }
+    if let Err(e) = check_resume(opt) {
+        problems.push(format!("--resume: {}", e));
+    }
+
+    let mut listeners = vec![("game", opt.host)];
+    if !opt.no_visualizer {
+        listeners.push(("visualizer", opt.visualizer_addr));
+    }
+    if let Some(addr) = opt.event_socket {
+        listeners.push(("event socket", addr));
+    }
+    for (i, (what, addr)) in listeners.iter().enumerate() {
+        if let Some((other, _)) = listeners[..i].iter().find(|(_, a)| a == addr) {
+            problems.push(format!("{} and {} both use {}", other, what, addr));
+        } else if let Err(e) = TcpListener::bind(addr) {
+            problems.push(format!("can't listen for {} on {}: {}", what, addr, e));
+        }
+    }
+
+    let outputs = [
+        ("--event-log", &opt.event_log),
+        ("--audit-log", &opt.audit_log),
+    ];
+    for (flag, path) in outputs.iter() {
+        if let Some(path) = path {
+            if let Err(e) = check_output_file(path) {
+                problems.push(format!("{}: {}", flag, e));
+            }
+        }
+    }
+    if let Some(dir) = &opt.record {
+        if let Err(e) = check_output_dir(dir) {
+            problems.push(format!("--record: {}", e));
+        }
+    }
+    // ratings are read at startup, and rewritten after every game
+    if let Some(path) = &opt.ratings {
+        if let Err(e) = Ratings::load(Some(path)) {
+            problems.push(format!("--ratings: {}", e));
+        } else if let Err(e) = check_output_file(path) {
+            problems.push(format!("--ratings: {}", e));
+        }
+    }
+    // and tokens whenever they change
+    if let Some(path) = &opt.tokens {
+        if let Err(e) = Tokens::load(Some(path), None) {
+            problems.push(format!("--tokens: {}", e));
+        } else if let Err(e) = check_output_file(path) {
+            problems.push(format!("--tokens: {}", e));
+        }
+    }
+
+    if let (Some(cert), Some(key)) = (&opt.tls_cert, &opt.tls_key) {
+        if let Err(e) = load_tls_config(cert, key) {
+            problems.push(format!("TLS: {}", e));
+        }
+    }
+
+    if let Some(dsn) = &opt.error_report_dsn {
+        if let Err(e) = ErrorReporter::from_dsn(dsn) {
+            problems.push(format!("--error-report-dsn: {}", e));
+        }
+    }
+
+    for problem in problems.iter() {
+        println!("problem: {}", problem);
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("found {} problem(s)", problems.len());
+    }
+    println!("configuration OK");
+    Ok(())
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let _log_guard = init_logging(&CLI_OPTIONS)?;
+    if let Some(Command::CheckConfig) = CLI_OPTIONS.command {
+        return check_config(&CLI_OPTIONS);
+    }
+    if let Some(Command::Analyze { file }) = &CLI_OPTIONS.command {
+        return analyze::run(file);
+    }
+    if let Some(Command::Verify { file, public_key }) = &CLI_OPTIONS.command {
+        return verify::run(file, public_key.as_deref());
+    }
+    if let Some(Command::Anonymize { file, output }) = &CLI_OPTIONS.command {
+        return anonymize::run(file, output);
+    }
+    if let Some(Command::Diff { first, second }) = &CLI_OPTIONS.command {
+        return diff::run(first, second);
+    }
+    if let Some(Command::Openings { files, turns }) = &CLI_OPTIONS.command {
+        return openings::run(files, *turns);
+    }
+    // one runtime for the game clients, the visualizer, and the event socket
+    let mut rt = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()?;
+    if let Some(Command::Bench {
+        games,
+        positions,
+        round_trips,
+    }) = CLI_OPTIONS.command
+    {
+        return rt.block_on(bench::run(games, positions, round_trips));
+    }
+    if let Some(Command::Replay {
+        file,
+        speed,
+        paused,
+    }) = &CLI_OPTIONS.command
+    {
+        return rt.block_on(replay::run(file, *speed, *paused));
+    }
+    if let Some(Command::Quiz { file, seat }) = &CLI_OPTIONS.command {
+        return rt.block_on(quiz::run(file, *seat));
+    }
+    rt.block_on(serve())
+}
+
+async fn serve() -> Result<(), anyhow::Error> {
+    check_resume(&CLI_OPTIONS).map_err(|e| anyhow::anyhow!("--resume: {}", e))?;
+    let reporter = match &CLI_OPTIONS.error_report_dsn {
+        Some(dsn) => {
+            remember_panic_locations();
+            Some(ErrorReporter::from_dsn(dsn)?)
+        }
+        None => None,
+    };
+    let events = EventLog::new(CLI_OPTIONS.event_log.as_ref(), CLI_OPTIONS.event_socket).await?;
+    let audit = AuditLog::open(CLI_OPTIONS.audit_log.as_ref())?;
+    if let Some(dir) = &CLI_OPTIONS.record {
+        std::fs::create_dir_all(dir)?;
+    }
+    let games = Games::new(CLI_OPTIONS.width(), CLI_OPTIONS.height());
+    let ratings = Ratings::load(CLI_OPTIONS.ratings.as_ref())?;
+    let tokens = Tokens::load(
+        CLI_OPTIONS.tokens.as_ref(),
+        CLI_OPTIONS.control_token.as_deref(),
+    )?;
+    let tls = match (&CLI_OPTIONS.tls_cert, &CLI_OPTIONS.tls_key) {
+        (Some(cert), Some(key)) => Some(load_tls_config(cert, key)?),
+        _ => None,
+    };
+    if !CLI_OPTIONS.no_visualizer {
+        start_webserver(
+            games.clone(),
+            ratings.clone(),
+            tokens,
+            CLI_OPTIONS.visualizer_addr,
+            tls,
+            None,
+        )
+        .await?;
+    }
+    let bind_addr = CLI_OPTIONS.host;
+    info!("Listening for player connections on {}", bind_addr);
+    let listener = tokio::net::TcpListener::bind(bind_addr).await?;
+    let mut lobby = accept_players(listener, &audit, &ratings);
+    let reports = Reports {
+        audit: &audit,
+        games: &games,
+        ratings: &ratings,
+        errors: reporter.as_ref(),
+    };
+    let host = |players| host_match(players, reports, events.clone());
+    if !CLI_OPTIONS.serve_forever {
+        info!("Waiting for players");
+        let players = lobby.next_match().await?;
+        lobby.close(&audit);
+        return host(players).await;
+    }
+    // each match has already reported anything that went wrong in it
+    let mut matches = futures::stream::FuturesUnordered::new();
+    loop {
+        if matches.len() >= CLI_OPTIONS.max_games {
+            matches.next().await;
+            continue;
+        }
+        info!(playing = matches.len(), "Waiting for players");
+        let players = if matches.is_empty() {
+            lobby.next_match().await?
+        } else {
+            let next = lobby.next_match();
+            futures::pin_mut!(next);
+            match futures::future::select(next, matches.next()).await {
+                futures::future::Either::Left((players, _)) => players?,
+                futures::future::Either::Right(_) => continue,
+            }
+        };
+        matches.push(host(players));
+    }
+}
+
+// Plays one match between these players, or a series of them with --best-of
+// or --games, and reports how it went
+async fn host_match(
+    mut players: RedBlue<Client>,
+    reports: Reports<'_>,
+    mut events: EventLog,
+) -> Result<(), anyhow::Error> {
+    let mut series = match (CLI_OPTIONS.best_of, CLI_OPTIONS.games) {
+        (Some(best_of), _) => Some(SeriesScore::new(best_of)),
+        (None, Some(games)) => Some(SeriesScore::all(games)),
+        (None, None) => None,
+    };
+    let mut matches = Vec::new();
+    let mut turns = 0;
+    // games won by whichever player had each color, since starting first or
+    // on one side may be worth something
+    let mut color_wins = RedBlue { red: 0, blue: 0 };
+    loop {
+        let match_id = format!("{:016x}", rand::random::<u64>());
+        let outcome = host_game(match_id.clone(), &mut players, reports, &events, series).await?;
+        matches.push(match_id);
+        // a game cut short by a bug ends the series, or any rematches
+        let outcome = match outcome {
+            Some(outcome) => outcome,
+            None => return Ok(()),
+        };
+        turns += outcome.turns;
+        match outcome.result {
+            WinState::Win => color_wins.red += 1,
+            WinState::Loss => color_wins.blue += 1,
+            WinState::Tie => (),
+        }
+        if let Some(score) = series {
+            let score = score.after(outcome.result);
+            if score.decided() {
+                let RedBlue { red, blue } = &mut players;
+                let result = match score.red.cmp(&score.blue) {
+                    std::cmp::Ordering::Greater => WinState::Win,
+                    std::cmp::Ordering::Less => WinState::Loss,
+                    std::cmp::Ordering::Equal => WinState::Tie,
+                };
+                let average_turns = turns as f64 / score.game as f64;
+                info!(red = score.red, blue = score.blue, "Series over");
+                if CLI_OPTIONS.games.is_some() {
+                    println!(
+                        "{} games: {} won {}, {} won {}, {} tied, {:.1} turns on average",
+                        score.game,
+                        red.name,
+                        score.red,
+                        blue.name,
+                        score.blue,
+                        score.ties,
+                        average_turns
+                    );
+                    println!(
+                        "by color: red won {}, blue won {}",
+                        color_wins.red, color_wins.blue
+                    );
+                }
+                let _ = red.send_series(result, score.red, score.blue).await;
+                let _ = blue
+                    .send_series(result.inverse(), score.blue, score.red)
+                    .await;
+                events.emit(GameEvent::Series {
+                    matches: &matches,
+                    players: [&red.name, &blue.name],
+                    wins: [score.red, score.blue],
+                    ties: score.ties,
+                    color_wins,
+                    average_turns,
+                    winner: match result {
+                        WinState::Win => Some(&red.name),
+                        WinState::Loss => Some(&blue.name),
+                        WinState::Tie => None,
+                    },
+                })?;
+                return Ok(());
+            }
+            let next = score.next();
+            players = RedBlue {
+                red: players.blue,
+                blue: players.red,
+            };
+            players.red.start_over();
+            players.blue.start_over();
+            let _ = players.red.send_new_game(&next).await;
+            let _ = players.blue.send_new_game(&next).await;
+            series = Some(next);
+            continue;
+        }
+        if !CLI_OPTIONS.rematches {
+            return Ok(());
+        }
+        let deadline = create_deadline();
+        let rematch = futures::future::join(
+            players.red.wants_rematch(deadline),
+            players.blue.wants_rematch(deadline),
+        )
+        .await;
+        if rematch != (true, true) {
+            return Ok(());
+        }
+        info!(red = %players.blue.name, blue = %players.red.name, "Rematch");
+        players = RedBlue {
+            red: players.blue,
+            blue: players.red,
+        };
+        players.red.start_over();
+        players.blue.start_over();
+    }
+}
+
+// How a game came out, with the result from red's point of view. It's
+// recorded once the players have been rated.
+#[derive(Debug, Copy, Clone)]
+struct Outcome {
+    result: WinState,
+    turns: usize,
+    duration: Duration,
+    end_reasons: RedBlue<Option<EndReason>>,
+    forfeits: RedBlue<Option<ForfeitReason>>,
+    response_times: RedBlue<ResponseStats>,
+}
+
+// How each player broke the protocol in a game, for --ratings
+fn conduct(outcome: &Outcome) -> RedBlue<Conduct> {
+    let of = |times: &ResponseStats, forfeit| Conduct {
+        timeouts: times.timeouts,
+        parse_errors: times.parse_errors,
+        disconnects: match forfeit {
+            Some(ForfeitReason::Eof) | Some(ForfeitReason::WriteFailure) => 1,
+            _ => 0,
+        },
+    };
+    RedBlue {
+        red: of(&outcome.response_times.red, outcome.forfeits.red),
+        blue: of(&outcome.response_times.blue, outcome.forfeits.blue),
+    }
+}
+
+// Where every match is reported
+#[derive(Clone, Copy)]
+struct Reports<'a> {
+    audit: &'a AuditLog,
+    games: &'a Games,
+    ratings: &'a Ratings,
+    errors: Option<&'a ErrorReporter>,
+}
+
+// Plays one game between these players, and returns how it came out, or None
+// if a bug cut it short
+async fn host_game(
+    match_id: String,
+    players: &mut RedBlue<Client>,
+    reports: Reports<'_>,
+    events: &EventLog,
+    series: Option<SeriesScore>,
+) -> Result<Option<Outcome>, anyhow::Error> {
+    let mut events = events.for_match(&match_id)?;
+    for (player, client) in [(Player::Red, &players.red), (Player::Blue, &players.blue)].iter() {
+        reports.audit.record(
+            client.peer,
+            Some(&client.name),
+            Some(*player),
+            Disposition::Played,
+        )?;
+        events.emit(GameEvent::Connected {
+            player: *player,
+            peer: client.peer,
+        })?;
+    }
+
+    let (width, height) = (CLI_OPTIONS.width(), CLI_OPTIONS.height());
+    let obstacles = CLI_OPTIONS.obstacles.map(|density| {
+        let seed = CLI_OPTIONS.seed.unwrap_or_else(rand::random);
+        info!(seed, density, "Placing obstacles");
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        (seed, density, Map::random(width, height, density, &mut rng))
+    });
+    if let Some((seed, density, _)) = obstacles {
+        events.emit(GameEvent::Obstacles { seed, density })?;
+    }
+    let map = CLI_OPTIONS
+        .map
+        .as_ref()
+        .or(obstacles.as_ref().map(|(_, _, map)| map));
+    if CLI_OPTIONS.mode == Mode::Snake && CLI_OPTIONS.power_ups.is_some() {
+        warn!("--power-ups is ignored in snake mode");
+    }
+    let fresh;
+    let setup = match &CLI_OPTIONS.resume {
+        Some(prefix) => &prefix.setup,
+        None => {
+            fresh = Setup::new(width, height, map);
+            &fresh
+        }
+    };
+    events.emit(GameEvent::Setup {
+        setup,
+        protocols: RedBlue {
+            red: players.red.protocol,
+            blue: players.blue.protocol,
+        },
+    })?;
+    let game = match &CLI_OPTIONS.resume {
+        Some(prefix) => {
+            let turns = CLI_OPTIONS.resume_turn.unwrap_or(prefix.moves.len());
+            events.emit(GameEvent::Resumed {
+                from: &prefix.match_id,
+                moves: &prefix.moves[..turns],
+            })?;
+            prefix.game_after(turns)
+        }
+        None => setup.game(),
+    };
+    let (render_send, render_recv) =
+        watch::channel(RenderFrame::new(&RenderData::game_start(width, height)));
+    reports.games.start(
+        GameInfo {
+            id: match_id.clone(),
+            red: players.red.name.clone(),
+            blue: players.blue.name.clone(),
+            series,
+        },
+        render_recv,
+    );
+    let stepper = if CLI_OPTIONS.debug_controls {
+        Some(reports.games.stepper(&match_id))
+    } else {
+        None
+    };
+    let mut started = format!(
+        "`{}` (red) vs `{}` (blue) has started",
+        players.red.name, players.blue.name
+    );
+    if !CLI_OPTIONS.no_visualizer {
+        let _ = write!(started, ": {}/game/{}", CLI_OPTIONS.public_url(), match_id);
+    }
+    post_discord(started);
+
+    // a bug in one match shouldn't take the whole server down with it
+    let res = panic::AssertUnwindSafe(play_game(
+        &match_id,
+        players,
+        game,
+        &render_send,
+        &mut events,
+        series,
+        stepper,
+    ))
+    .catch_unwind()
+    .await;
+    reports.games.finish(&match_id);
+    let RedBlue {
+        red: red_player,
+        blue: blue_player,
+    } = players;
+    let played = match res {
+        Ok(Ok(outcome)) => Ok(outcome),
+        Ok(Err(e)) => {
+            error!(id = %match_id, red = %red_player.name, blue = %blue_player.name, "Match failed: {}", e);
+            Err(e.to_string())
+        }
+        Err(payload) => {
+            let msg = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            let location = PANIC_LOCATION.with(|l| l.borrow_mut().take());
+            let msg = match location {
+                Some(location) => format!("panicked at {}: {}", location, msg),
+                None => format!("panicked: {}", msg),
+            };
+            error!(id = %match_id, red = %red_player.name, blue = %blue_player.name, "Match {}", msg);
+            Err(msg)
+        }
+    };
+    let outcome = match played {
+        Ok(outcome) => outcome,
+        Err(msg) => {
+            if let Some(reporter) = reports.errors {
+                report_match_error(reporter, &match_id, &msg, red_player, blue_player);
+            }
+            // the state of the game is unknown, so call it a tie
+            let tie = ToClientMessage::End(WinState::Tie, None);
+            let _ = red_player.send_message(tie, 0, Player::Red, None).await;
+            let _ = blue_player.send_message(tie, 0, Player::Blue, None).await;
+            events.emit(GameEvent::Aborted {
+                cause: "internal server error",
+            })?;
+            events.seal()?;
+            return Ok(None);
+        }
+    };
+    let rating_changes = reports.ratings.update(
+        &red_player.name,
+        &blue_player.name,
+        outcome.result,
+        conduct(&outcome),
+    )?;
+    if let Some(changes) = rating_changes {
+        info!(red = changes.red, blue = changes.blue, "Ratings updated");
+    }
+    events.emit(result_event(&outcome, rating_changes))?;
+    events.seal()?;
+    if let Some(url) = &CLI_OPTIONS.result_webhook {
+        let winner = match outcome.result {
+            WinState::Win => Some(red_player.name.as_str()),
+            WinState::Loss => Some(blue_player.name.as_str()),
+            WinState::Tie => None,
+        };
+        // what ended the loser's game, or either player's in a tie
+        let reasons = outcome.end_reasons;
+        let reason = match outcome.result {
+            WinState::Win => reasons.blue,
+            WinState::Loss => reasons.red,
+            WinState::Tie => reasons.red.or(reasons.blue),
+        };
+        let body = serde_json::to_string(&ResultHook {
+            match_id: &match_id,
+            red: &red_player.name,
+            blue: &blue_player.name,
+            winner,
+            reason: reason.map(EndReason::as_str),
+            turns: outcome.turns,
+            duration_ms: as_ms(outcome.duration),
+            replay: replay_path(&match_id),
+            event_log: CLI_OPTIONS.event_log.as_ref(),
+        })?;
+        post_json(url, body);
+    }
+    post_discord(match outcome.result {
+        WinState::Win => format!(
+            "`{}` beat `{}` in {} turns",
+            red_player.name, blue_player.name, outcome.turns
+        ),
+        WinState::Loss => format!(
+            "`{}` beat `{}` in {} turns",
+            blue_player.name, red_player.name, outcome.turns
+        ),
+        WinState::Tie => format!(
+            "`{}` and `{}` tied after {} turns",
+            red_player.name, blue_player.name, outcome.turns
+        ),
+    });
+    info!("Game ended normally");
+    Ok(Some(outcome))
+}
+
+thread_local! {
+    // where the last panic on this thread happened, for error reports
+    static PANIC_LOCATION: std::cell::RefCell<Option<String>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+fn remember_panic_locations() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if let Some(location) = info.location() {
+            PANIC_LOCATION.with(|l| *l.borrow_mut() = Some(location.to_string()));
+        }
+        default_hook(info);
+    }));
+}
+
+// What --result-webhook is sent when a game finishes. With --record, the
+// replay is the game's own file. The event log, if there is one, is shared
+// by every game, each under its match_id.
+#[derive(Serialize)]
+struct ResultHook<'a> {
+    match_id: &'a str,
+    red: &'a str,
+    blue: &'a str,
+    winner: Option<&'a str>,
+    reason: Option<&'static str>,
+    turns: usize,
+    duration_ms: f64,
+    replay: Option<PathBuf>,
+    event_log: Option<&'a PathBuf>,
+}
+
+// In the background, so a slow scoreboard doesn't hold up the next game.
+// Failing to post is only logged.
+fn post_json(url: &'static str, body: String) {
+    tokio::task::spawn_blocking(move || {
+        let res = ureq::post(url)
+            .timeout(Duration::from_secs(5))
+            .set("Content-Type", "application/json")
+            .send_string(&body);
+        if let Err(e) = res {
+            warn!("Failed to post to {}: {}", url, e);
+        }
+    });
+}
+
+// With --discord-webhook, as a message in the channel
+fn post_discord(content: String) {
+    if let Some(url) = &CLI_OPTIONS.discord_webhook {
+        post_json(url, serde_json::json!({ "content": content }).to_string());
+    }
+}
+
+fn report_match_error(
+    reporter: &ErrorReporter,
+    match_id: &str,
+    message: &str,
+    red: &Client,
+    blue: &Client,
+) {
+    // the report blocks, so let the runtime move other tasks off this thread
+    tokio::task::block_in_place(|| {
+        reporter.report(
+            message,
+            &[("match_id", match_id)],
+            serde_json::json!({
+                "red": { "name": red.name, "peer": red.peer, "transcript": red.transcript() },
+                "blue": { "name": blue.name, "peer": blue.peer, "transcript": blue.transcript() },
+            }),
+        )
+    });
+}
+
+// Sends each client its message about the game. A client that can't be
+// written to forfeits, and if that ends the game both clients are sent the
+// result instead. Returns the message red was last sent.
+async fn send_updates(
+    red_player: &mut Client,
+    blue_player: &mut Client,
+    game: &mut impl Game,
+    turn: usize,
+    events: &mut EventLog,
+) -> Result<ToClientMessage, io::Error> {
+    // a client that can't be written to will fail again on its update
+    if !game.game_over() && turn.is_multiple_of(PING_EVERY) {
+        let _ =
+            futures::future::join(red_player.probe_latency(), blue_player.probe_latency()).await;
+    }
+    let board = game.render_data();
+    let board = Some(&board);
+    if !game.game_over() {
+        let notices = game.notices();
+        for msg in notices.red {
+            let _ = red_player.send_message(msg, turn, Player::Red, board).await;
+        }
+        for msg in notices.blue {
+            let _ = blue_player
+                .send_message(msg, turn, Player::Blue, board)
+                .await;
+        }
+        if let (Some(red), Some(blue)) = (red_player.clock, blue_player.clock) {
+            let _ = red_player.send_time(blue).await;
+            let _ = blue_player.send_time(red).await;
+        }
+    }
+    let msgs = game.messages();
+    let failed = |res: io::Result<()>| res.map_err(|_| ForfeitReason::WriteFailure);
+    let res = RedBlue {
+        red: failed(
+            red_player
+                .send_message(msgs.red, turn, Player::Red, board)
+                .await,
+        ),
+        blue: failed(
+            blue_player
+                .send_message(msgs.blue, turn, Player::Blue, board)
+                .await,
+        ),
+    };
+    // a client hanging up after being told the result isn't a forfeit
+    if game.game_over() {
+        return Ok(msgs.red);
+    }
+    events.report_forfeits(&res)?;
+    if handle_forfeits(res, game).is_ok() {
+        return Ok(msgs.red);
+    }
+    let end = game.messages();
+    // the client that failed will most likely fail again
+    let _ = red_player
+        .send_message(end.red, turn, Player::Red, None)
+        .await;
+    let _ = blue_player
+        .send_message(end.blue, turn, Player::Blue, None)
+        .await;
+    Ok(end.red)
+}
+
+// What the debugging endpoints ask of a live game, with --debug-controls
+#[derive(Debug, Copy, Clone)]
+enum DebugControl {
+    Pause,
+    Step,
+    Resume,
+}
+
+// Holds a live game between turns while it's paused with --debug-controls
+struct Stepper {
+    controls: mpsc::UnboundedReceiver<DebugControl>,
+    paused: bool,
+    // turns to play before pausing again
+    steps: usize,
+}
+
+impl Stepper {
+    fn apply(&mut self, control: DebugControl) {
+        match control {
+            DebugControl::Pause => {
+                self.paused = true;
+                self.steps = 0;
+            }
+            // a running game plays this turn and pauses before the next
+            DebugControl::Step => {
+                self.paused = true;
+                self.steps += 1;
+            }
+            DebugControl::Resume => {
+                self.paused = false;
+                self.steps = 0;
+            }
+        }
+    }
+
+    // Waits while the game is paused, until it's stepped or resumed, and
+    // returns how long it waited
+    async fn hold(&mut self, turn: usize) -> Duration {
+        while let Ok(control) = self.controls.try_recv() {
+            self.apply(control);
+        }
+        let start = time::Instant::now();
+        if self.paused && self.steps == 0 {
+            info!(next_turn = turn, "Paused");
+        }
+        while self.paused && self.steps == 0 {
+            match self.controls.recv().await {
+                Some(control) => self.apply(control),
+                // with nothing left to resume it, play on
+                None => self.paused = false,
+            }
+        }
+        if self.paused {
+            self.steps -= 1;
+        }
+        start.elapsed()
+    }
+}
+
+// A move as read from a client, and when it was read
+struct TimedMove {
+    res: Result<Direction, ForfeitReason>,
+    // since the update was sent
+    ms: f64,
+    exchange: Exchange,
+}
+
+// Reads a client's reply to the update it was last sent, allowing it the
+// timeout, or whatever's left on its clock, from when that update went out,
+// plus the time its update and reply spend on the network. The first move
+// may instead get --first-move-timeout, which is free of the clock.
+async fn read_timed_move(client: &mut Client, turn: usize) -> Result<TimedMove, io::Error> {
+    let first = CLI_OPTIONS.first_move_timeout.filter(|_| turn == 0);
+    let timeout = match (first, client.clock) {
+        (Some(first), _) => time::Duration::from_millis(first),
+        (None, Some(clock)) => clock,
+        (None, None) => time::Duration::from_millis(CLI_OPTIONS.timeout),
+    } + client.latency;
+    let res = client.read_direction(client.sent_at + timeout).await?;
+    let elapsed = client.sent_at.elapsed().saturating_sub(client.latency);
+    if let Some(clock) = client.clock.as_mut().filter(|_| first.is_none()) {
+        *clock = clock.saturating_sub(elapsed);
+        // a move made in time earns the increment
+        if res.is_ok() {
+            *clock += Duration::from_millis(CLI_OPTIONS.increment.unwrap_or(0));
+        }
+    }
+    Ok(TimedMove {
+        res,
+        ms: as_ms(elapsed),
+        exchange: Exchange {
+            sent_ms: client.sent_ms,
+            deadline_ms: client.sent_ms + timeout.as_millis(),
+            received_ms: unix_ms(),
+        },
+    })
+}
+
+async fn play_game(
+    match_id: &str,
+    players: &mut RedBlue<Client>,
+    game: impl Game,
+    renderer: &watch::Sender<RenderFrame>,
+    events: &mut EventLog,
+    series: Option<SeriesScore>,
+    stepper: Option<Stepper>,
+) -> Result<Outcome, anyhow::Error> {
+    let match_span = info_span!(
+        "match",
+        id = match_id,
+        red = field::Empty,
+        blue = field::Empty
+    );
+    run_match(
+        &match_span,
+        players,
+        game,
+        renderer,
+        events,
+        series,
+        stepper,
+    )
+    .instrument(match_span.clone())
+    .await
+}
+
+async fn run_match(
+    match_span: &tracing::Span,
+    players: &mut RedBlue<Client>,
+    mut game: impl Game,
+    renderer: &watch::Sender<RenderFrame>,
+    events: &mut EventLog,
+    series: Option<SeriesScore>,
+    mut stepper: Option<Stepper>,
+) -> Result<Outcome, anyhow::Error> {
+    let RedBlue {
+        red: red_player,
+        blue: blue_player,
+    } = players;
+    // names were read while accepting the players
+    events.emit(GameEvent::Named {
+        player: Player::Red,
+        name: &red_player.name,
+    })?;
+    events.emit(GameEvent::Named {
+        player: Player::Blue,
+        name: &blue_player.name,
+    })?;
+    match_span.record("red", red_player.name.as_str());
+    match_span.record("blue", blue_player.name.as_str());
+    let mut times = RedBlue {
+        red: ResponseTimes::default(),
+        blue: ResponseTimes::default(),
+    };
+    // in a row, for --on-bad-move continue
+    let mut bad_moves = RedBlue { red: 0, blue: 0 };
+    // in the whole game, for --timeout-strikes
+    let mut strikes = RedBlue { red: 0, blue: 0 };
+
+    // announce the opponents, the settings and any rule variants. A client that can't be
+    // written to will forfeit when it's sent the initial positions.
+    let rules = game.rules();
+    let starts = game.messages();
+    let config = RedBlue {
+        red: Config::new(&game.render_data(), starts.red, &rules.red),
+        blue: Config::new(&game.render_data(), starts.blue, &rules.blue),
+    };
+    let _ = red_player.send_color(Player::Red).await;
+    let _ = blue_player.send_color(Player::Blue).await;
+    let _ = red_player.send_opponent(&blue_player.name).await;
+    let _ = blue_player.send_opponent(&red_player.name).await;
+    let _ = red_player.send_config(&config.red).await;
+    let _ = blue_player.send_config(&config.blue).await;
+    for rule in &rules.red {
+        let _ = red_player.send_rule(rule).await;
+    }
+    for rule in &rules.blue {
+        let _ = blue_player.send_rule(rule).await;
+    }
+    events.emit(GameEvent::Settings {
+        mode: CLI_OPTIONS.mode,
+        width: config.red.width,
+        height: config.red.height,
+        timeout: config.red.timeout,
+        flags: &config.red.flags,
+    })?;
+
+    // a game resumed with --resume starts some turns in
+    let mut turn = game.turns();
+
+    // initialize the game by sending initial positions
+    let red_update = send_updates(red_player, blue_player, &mut game, turn, events).await?;
+
+    // init renderer
+    renderer.broadcast(RenderFrame::with_series(&game.render_data(), series))?;
+    let mut seen = Vec::new();
+    record_board(events, &mut seen, turn, &game.render_data())?;
+
+    // main game loop
+    let mut last_update = red_update;
+    // moved on by any time spent paused, which doesn't count
+    let mut match_start = time::Instant::now();
+    let tick_period = CLI_OPTIONS
+        .tick_rate
+        .map(|hz| time::Duration::from_secs_f64(1.0 / hz));
+    let mut next_tick = time::Instant::now();
+    while !game.game_over() {
+        let turn_span = info_span!("turn", turn);
+        let play_turn = async {
+            trace!("Begin loop iter");
+            // watchdog: a match that runs too long is called a tie
+            if let Some(max) = CLI_OPTIONS.max_match_duration {
+                if match_start.elapsed() >= Duration::from_secs(max) {
+                    warn!("Aborting match after {} seconds, calling it a tie", max);
+                    events.emit(GameEvent::Aborted {
+                        cause: "wall-clock limit reached",
+                    })?;
+                    game.set_win_state(WinState::Tie);
+                    last_update =
+                        send_updates(red_player, blue_player, &mut game, turn, events).await?;
+                    return Ok(());
+                }
+            }
+            // get client moves, both at once so neither waits on the other
+            let (red_read, blue_read) = futures::future::join(
+                read_timed_move(red_player, turn),
+                read_timed_move(blue_player, turn),
+            )
+            .instrument(info_span!("read_moves"))
+            .await;
+            let (red_read, blue_read) = (red_read?, blue_read?);
+            let (red_ms, blue_ms) = (red_read.ms, blue_read.ms);
+            let moves = RedBlue {
+                red: red_read.res,
+                blue: blue_read.res,
+            };
+            events.emit(GameEvent::Timing {
+                turn,
+                red: red_read.exchange,
+                blue: blue_read.exchange,
+            })?;
+            times.red.record(red_ms, &moves.red);
+            times.blue.record(blue_ms, &moves.blue);
+            let moves = if CLI_OPTIONS.on_bad_move == OnBadMove::Continue {
+                forgive_bad_moves(moves, game.previous_moves(), &mut bad_moves, turn, events)?
+            } else {
+                moves
+            };
+            let (moves, warnings) =
+                strike_timeouts(moves, game.previous_moves(), &mut strikes, turn, events)?;
+            // a client that can't be written to will fail again on its update
+            if let Some(left) = warnings.red {
+                let _ = red_player.send_warning(ForfeitReason::Timeout, left).await;
+            }
+            if let Some(left) = warnings.blue {
+                let _ = blue_player.send_warning(ForfeitReason::Timeout, left).await;
+            }
+            let moves = check_reversals(moves, game.previous_moves());
+            events.report_forfeits(&moves)?;
+            let res = handle_forfeits(moves, &mut game);
+            match res {
+                Ok(moves) => {
+                    let rb = game.board_moves(moves);
+                    events.emit(GameEvent::Turn {
+                        turn,
+                        red: rb.red,
+                        blue: rb.blue,
+                        red_ms,
+                        blue_ms,
+                    })?;
+                    turn += 1;
+                    // update game state
+                    game.step(rb);
+                }
+                Err(e) => {
+                    // game is already over, clients will be notified on the next
+                    // update
+                    debug!("Game ended due to {} while getting moves", e);
+                }
+            };
+            // pace before sending, so the wait isn't counted against the clients
+            if let Some(period) = tick_period {
+                // a slow turn doesn't make the following ones rush to catch up
+                next_tick = (next_tick + period).max(time::Instant::now());
+                tokio::time::delay_until(next_tick.into()).await;
+            }
+            // a paused game waits here, before the clients are sent the turn
+            // and their deadlines start
+            if let Some(stepper) = &mut stepper {
+                if !game.game_over() {
+                    match_start += stepper.hold(turn).await;
+                }
+            }
+            let red_update = send_updates(red_player, blue_player, &mut game, turn, events)
+                .instrument(info_span!("send_updates"))
+                .await?;
+            last_update = red_update;
+
+            // update render state
+            info_span!("broadcast").in_scope(|| {
+                renderer.broadcast(RenderFrame::with_series(&game.render_data(), series))
+            })?;
+            record_board(events, &mut seen, turn, &game.render_data())?;
+            Ok::<_, anyhow::Error>(())
+        };
+        play_turn.instrument(turn_span).await?;
+    }
+    info!(turns = turn, result = ?last_update, "Game over");
+    let stats = times.as_ref().map(ResponseTimes::stats);
+    info!(red = ?stats.red, blue = ?stats.blue, "Response times");
+    if let (Some(red), Some(blue)) = (red_player.clock, blue_player.clock) {
+        info!(red_ms = as_ms(red), blue_ms = as_ms(blue), "Time left");
+    }
+    // finalize render state, with the series score this game leaves
+    let result = game.win_state().unwrap_or(WinState::Tie);
+    let series = series.map(|score| score.after(result));
+    renderer.broadcast(RenderFrame::with_series(&game.render_data(), series))?;
+    // hacky but whatever
+    tokio::time::delay_for(time::Duration::from_millis(10)).await;
+    Ok(Outcome {
+        result,
+        turns: turn,
+        duration: match_start.elapsed(),
+        end_reasons: game.end_reasons(),
+        forfeits: game.forfeits(),
+        response_times: stats,
+    })
 }
@@ -0,0 +1,162 @@
+// Obstacle maps: an ASCII grid, one line per row, with walls marked `#` and
+// free cells `.`.
+use super::{invert_pos, start_positions};
+use rand::Rng;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MapError {
+    #[error("map is empty")]
+    Empty,
+    #[error("line {line} is {len} cells wide, but line 1 is {width}")]
+    Ragged {
+        line: usize,
+        len: usize,
+        width: usize,
+    },
+    #[error("map is {width}x{height}, but each side must be from 4 to 1024 cells")]
+    Size { width: usize, height: usize },
+    #[error("unexpected {found:?} on line {line}; use '#' for walls and '.' for free cells")]
+    BadCell { line: usize, found: char },
+    #[error("there's a wall where a player starts, at line {line}, column {column}")]
+    BlocksStart { line: usize, column: usize },
+}
+
+/// A board layout with walls on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Map {
+    pub width: usize,
+    pub height: usize,
+    /// Positions of the walls, in increasing order
+    pub walls: Vec<usize>,
+}
+
+impl Map {
+    /// Parses a map, checking that it's rectangular and leaves the starting
+    /// positions free.
+    pub fn parse(text: &str) -> Result<Self, MapError> {
+        let rows: Vec<&str> = text.lines().map(str::trim_end).collect();
+        let height = rows
+            .iter()
+            .rposition(|row| !row.is_empty())
+            .ok_or(MapError::Empty)?
+            + 1;
+        let width = rows[0].chars().count();
+        let mut walls = Vec::new();
+        for (y, row) in rows[..height].iter().enumerate() {
+            let len = row.chars().count();
+            if len != width {
+                return Err(MapError::Ragged {
+                    line: y + 1,
+                    len,
+                    width,
+                });
+            }
+            for (x, cell) in row.chars().enumerate() {
+                match cell {
+                    '#' => walls.push(y * width + x),
+                    '.' => (),
+                    found => return Err(MapError::BadCell { line: y + 1, found }),
+                }
+            }
+        }
+        if !(4..=1024).contains(&width) || !(4..=1024).contains(&height) {
+            return Err(MapError::Size { width, height });
+        }
+        let starts = start_positions(width, height);
+        for start in [starts.red, starts.blue] {
+            if walls.binary_search(&start).is_ok() {
+                return Err(MapError::BlocksStart {
+                    line: start / width + 1,
+                    column: start % width + 1,
+                });
+            }
+        }
+        Ok(Map {
+            width,
+            height,
+            walls,
+        })
+    }
+
+    /// Scatters walls over a board, in pairs rotated half a turn from each
+    /// other so that both players face the same layout. About `density` of
+    /// the cells become walls, except that the players' starting cells and
+    /// the ones around them are left free.
+    pub fn random(width: usize, height: usize, density: f64, rng: &mut impl Rng) -> Self {
+        let cells = width * height;
+        let starts = start_positions(width, height);
+        let near_start = |pos: usize| {
+            [starts.red, starts.blue].iter().any(|&start| {
+                (pos % width).abs_diff(start % width) <= 1
+                    && (pos / width).abs_diff(start / width) <= 1
+            })
+        };
+        let mut walls = Vec::new();
+        // the first half of the board, and the middle cell if there is one
+        for pos in 0..cells.div_ceil(2) {
+            if near_start(pos) || !rng.gen_bool(density) {
+                continue;
+            }
+            walls.push(pos);
+            if invert_pos(pos, cells) != pos {
+                walls.push(invert_pos(pos, cells));
+            }
+        }
+        walls.sort_unstable();
+        Map {
+            width,
+            height,
+            walls,
+        }
+    }
+}
+
+#[test]
+fn maps() {
+    let map = Map::parse("#...\n....\n....\n...#\n\n").unwrap();
+    assert_eq!((4, 4), (map.width, map.height));
+    assert_eq!(vec![0, 15], map.walls);
+    assert_eq!(Err(MapError::Empty), Map::parse("\n\n"));
+    assert_eq!(
+        Err(MapError::Ragged {
+            line: 2,
+            len: 3,
+            width: 4
+        }),
+        Map::parse("....\n...\n....\n....\n")
+    );
+    assert_eq!(
+        Err(MapError::BadCell {
+            line: 1,
+            found: 'x'
+        }),
+        Map::parse("...x\n....\n....\n....\n")
+    );
+    // red starts at the start of the second row of a 4x4 board
+    assert_eq!(
+        Err(MapError::BlocksStart { line: 2, column: 1 }),
+        Map::parse("....\n#...\n....\n....\n")
+    );
+}
+
+#[test]
+fn random_maps() {
+    use rand::SeedableRng;
+    let seeded = |seed| rand::rngs::StdRng::seed_from_u64(seed);
+    for &(width, height) in [(32, 32), (12, 5), (9, 7)].iter() {
+        let map = Map::random(width, height, 0.3, &mut seeded(7));
+        assert_eq!(map, Map::random(width, height, 0.3, &mut seeded(7)));
+        assert!(!map.walls.is_empty());
+        let cells = width * height;
+        for &wall in &map.walls {
+            assert!(map.walls.binary_search(&invert_pos(wall, cells)).is_ok());
+        }
+        let starts = start_positions(width, height);
+        for &start in [starts.red, starts.blue].iter() {
+            for &near in [start - 1, start, start + 1, start - width, start + width].iter() {
+                assert!(map.walls.binary_search(&near).is_err());
+            }
+        }
+    }
+}
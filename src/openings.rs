@@ -0,0 +1,131 @@
+// Tallies how games recorded with --record opened, for the `openings`
+// subcommand: the moves of each game's first turns, how often each opening
+// was played and how its games ended, for each set of rules. Printed as JSON
+// for bot authors to build opening books from.
+use crate::replay::{self, Event};
+use serde::Serialize;
+use snake_ai_battle::Player;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Default, Serialize)]
+struct Tally {
+    games: usize,
+    red_wins: usize,
+    blue_wins: usize,
+    ties: usize,
+}
+
+impl Tally {
+    fn add(&mut self, winner: Option<Player>) {
+        self.games += 1;
+        match winner {
+            Some(Player::Red) => self.red_wins += 1,
+            Some(Player::Blue) => self.blue_wins += 1,
+            None => self.ties += 1,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Opening {
+    moves: Vec<String>,
+    #[serde(flatten)]
+    tally: Tally,
+}
+
+// the openings played under one set of rules, most played first
+#[derive(Serialize)]
+struct Book {
+    rules: serde_json::Value,
+    games: usize,
+    openings: Vec<Opening>,
+}
+
+pub fn run(files: &[PathBuf], turns: usize) -> Result<(), anyhow::Error> {
+    // by rules, as JSON, and then by opening
+    let mut tallies: BTreeMap<String, BTreeMap<Vec<String>, Tally>> = BTreeMap::new();
+    let mut unfinished = 0;
+    for path in files {
+        match opening(path, turns)? {
+            Some(game) => tallies
+                .entry(game.rules)
+                .or_default()
+                .entry(game.moves)
+                .or_default()
+                .add(game.winner),
+            None => unfinished += 1,
+        }
+    }
+    let mut books = Vec::new();
+    for (rules, openings) in tallies {
+        let mut openings: Vec<_> = openings
+            .into_iter()
+            .map(|(moves, tally)| Opening { moves, tally })
+            .collect();
+        openings.sort_by_key(|opening| Reverse(opening.tally.games));
+        books.push(Book {
+            rules: serde_json::from_str(&rules)?,
+            games: openings.iter().map(|opening| opening.tally.games).sum(),
+            openings,
+        });
+    }
+    books.sort_by_key(|book| Reverse(book.games));
+    println!("{}", serde_json::to_string_pretty(&books)?);
+    if unfinished > 0 {
+        eprintln!("left out {} game(s) that have no result", unfinished);
+    }
+    Ok(())
+}
+
+// A finished game's rules, as JSON, the moves of its first turns, and its
+// winner. Each turn's moves are red's and then blue's, as seen on the board.
+struct Game {
+    rules: String,
+    moves: Vec<String>,
+    winner: Option<Player>,
+}
+
+fn opening(path: &Path, turns: usize) -> Result<Option<Game>, anyhow::Error> {
+    let (_, records) = replay::records(path)?;
+    let mut rules = None;
+    let mut moves = Vec::new();
+    for record in records {
+        let record = record.map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        match record.event {
+            // everything but the seed
+            Event::Setup(setup) => {
+                let mut setup = serde_json::to_value(&setup)?;
+                if let Some(fields) = setup.as_object_mut() {
+                    fields.remove("seed");
+                }
+                rules = Some(setup.to_string());
+            }
+            Event::Turn { red, blue } if moves.len() < turns => {
+                moves.push(format!("{}{}", red.to_char(), blue.to_char()))
+            }
+            Event::Result { winner, .. } => {
+                return Ok(rules.map(|rules| Game {
+                    rules,
+                    moves,
+                    winner,
+                }));
+            }
+            _ => (),
+        }
+    }
+    Ok(None)
+}
+
+#[test]
+fn tallies() {
+    let mut tally = Tally::default();
+    tally.add(Some(Player::Red));
+    tally.add(None);
+    tally.add(Some(Player::Red));
+    assert_eq!(
+        r#"{"games":3,"red_wins":2,"blue_wins":0,"ties":1}"#,
+        serde_json::to_string(&tally).unwrap()
+    );
+}
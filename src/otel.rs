@@ -0,0 +1,47 @@
+// Exports the match and turn spans to an OpenTelemetry collector over OTLP/HTTP.
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+// Flushes any buffered spans when dropped.
+pub struct Guard(Option<SdkTracerProvider>);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.0.take() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+pub fn layer<S>(endpoint: Option<&str>) -> Result<(Option<impl Layer<S>>, Guard), anyhow::Error>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let endpoint = match endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok((None, Guard(None))),
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    // the batch processor exports from its own thread, keeping HTTP off the game loop
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name("snake-ai-battle")
+                .build(),
+        )
+        .build();
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("server"))
+        .with_filter(Targets::new().with_target("server", tracing::Level::INFO));
+    Ok((Some(layer), Guard(Some(provider))))
+}
@@ -1,11 +1,75 @@
 const canvas = document.getElementById("gc");
 const ctx = canvas.getContext("2d");
+const rules = document.getElementById("rules");
+const result = document.getElementById("result");
+const games = document.getElementById("games");
+const series = document.getElementById("series");
+const ratings = document.getElementById("ratings");
+
+// what ended a player's game, as reported by the server
+const END_REASONS = {
+  TurnLimit: () => "the turn limit was reached",
+  Wall: (player) => `${player} ran into a wall`,
+  Trail: (player) => `${player} ran into a trail`,
+  HeadOn: () => "the players collided head on",
+  Timeout: (player) => `${player} took too long to move`,
+  ParseError: (player) => `${player} sent a malformed move`,
+  Eof: (player) => `${player} disconnected`,
+  WriteFailure: (player) => `${player} lost its connection`,
+  Resign: (player) => `${player} resigned`,
+  RuleViolation: (player) => `${player} made an illegal move`,
+};
+
+function resultText(data) {
+  if (data.result == null) {
+    return "";
+  }
+  let headline = {
+    Win: "Red wins",
+    Loss: "Blue wins",
+    Tie: "Tie",
+  }[data.result];
+  let reasons = [];
+  for (let player of ["red", "blue"]) {
+    let reason = data.end_reasons[player];
+    let text = reason && END_REASONS[reason] && END_REASONS[reason](player);
+    if (text && !reasons.includes(text)) {
+      reasons.push(text);
+    }
+  }
+  return reasons.length ? `${headline}: ${reasons.join(", ")}` : headline;
+}
+
+// the score of a --best-of series, by the colors in the game shown. The
+// series can only be over once the game is.
+function seriesText(score, over) {
+  if (score == null) {
+    return "";
+  }
+  let wins = `red ${score.red}, blue ${score.blue}`;
+  let decided =
+    over &&
+    ((!score.play_all &&
+      Math.max(score.red, score.blue) > Math.floor(score.best_of / 2)) ||
+      score.game == score.best_of);
+  if (!decided) {
+    return `Game ${score.game} of ${score.best_of}: ${wins}`;
+  }
+  let winner =
+    score.red > score.blue ? "Red" : score.blue > score.red ? "Blue" : null;
+  return winner
+    ? `${winner} wins the series: ${wins}`
+    : `The series is tied: ${wins}`;
+}
 
 const GRIDE_SIZE = 20;
 
 function render(data) {
   canvas.width = GRIDE_SIZE * data.width + 2;
   canvas.height = GRIDE_SIZE * data.height + 2;
+  rules.textContent = data.wrap ? "Edges wrap around" : "";
+  result.textContent = resultText(data);
+  series.textContent = seriesText(data.series, data.result != null);
 
   ctx.fillStyle = "black";
   ctx.fillRect(0, 0, canvas.width, canvas.height);
@@ -18,6 +82,14 @@ function render(data) {
         ctx.fillStyle = "red";
       } else if (square == "Blue") {
         ctx.fillStyle = "blue";
+      } else if (square == "Food") {
+        ctx.fillStyle = "green";
+      } else if (square == "Wall") {
+        ctx.fillStyle = "white";
+      } else if (square == "Boost") {
+        ctx.fillStyle = "yellow";
+      } else if (square == "Bomb") {
+        ctx.fillStyle = "orange";
       } else {
         ctx.fillStyle = "grey";
       }
@@ -31,7 +103,43 @@ function render(data) {
   }
 }
 
-const sse = new EventSource("watch");
+// links to every match being played
+function listGames(list) {
+  games.replaceChildren();
+  for (let game of list) {
+    let link = document.createElement("a");
+    link.href = `/game/${game.id}`;
+    link.textContent = `${game.red} vs ${game.blue}`;
+    if (game.series) {
+      link.textContent += ` (game ${game.series.game} of ${game.series.best_of})`;
+    }
+    let item = document.createElement("li");
+    item.appendChild(link);
+    games.appendChild(item);
+  }
+}
+
+fetch("/games")
+  .then((response) => response.json())
+  .then(listGames);
+
+// with --ratings, every player rated so far, highest first
+function listRatings(list) {
+  ratings.replaceChildren();
+  for (let player of list) {
+    let item = document.createElement("li");
+    item.textContent = `${player.name}: ${Math.round(player.rating)}`;
+    ratings.appendChild(item);
+  }
+}
+
+fetch("/ratings")
+  .then((response) => response.json())
+  .then(listRatings);
+
+// /game/<id> follows that match, and anything else the newest
+const gameId = location.pathname.match(/^\/game\/([^/]+)/);
+const sse = new EventSource(gameId ? `/watch/${gameId[1]}` : "/watch");
 sse.addEventListener("render", (e) => {
   let data = JSON.parse(e.data);
   console.log(data);
@@ -0,0 +1,207 @@
+// Plays one side of a game recorded with --record to a bot, for the `quiz`
+// subcommand, as if the game were live. Whatever the bot answers, the game
+// goes on as it was recorded, so every position is one the recorded player
+// really faced. Each answer is scored against the recorded move: the same
+// move, or a different one that left the bot better or worse off a turn
+// later. Good for "guess the move" training, and for checking that a bot
+// still makes the moves it used to.
+use crate::replay::Replay;
+use crate::{check_rules, create_deadline, read_timed_move, Client, Config, CLI_OPTIONS};
+use snake_ai_battle::{
+    territory, Direction, ForfeitReason, Game, Player, RedBlue, ToClientMessage, WinState,
+};
+use std::cmp::Ordering;
+use std::path::Path;
+use tracing::info;
+
+// how each answer compared with the recorded move
+#[derive(Default)]
+struct Score {
+    matched: usize,
+    better: usize,
+    as_good: usize,
+    worse: usize,
+    missed: usize,
+}
+
+pub async fn run(path: &Path, seat: Player) -> Result<(), anyhow::Error> {
+    let replay = Replay::load(path)?;
+    let setup = match &replay.setup {
+        Some(setup) => setup,
+        None => anyhow::bail!("{} has no Setup event to play over", path.display()),
+    };
+    check_rules(&CLI_OPTIONS, setup).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let (mine, theirs) = match seat {
+        Player::Red => (&replay.names.red, &replay.names.blue),
+        Player::Blue => (&replay.names.blue, &replay.names.red),
+    };
+
+    let mut listener = tokio::net::TcpListener::bind(CLI_OPTIONS.host).await?;
+    info!(
+        "Waiting on {} for a bot to play {}'s side of {}",
+        CLI_OPTIONS.host, mine, replay.match_id
+    );
+    let (stream, _) = listener.accept().await?;
+    let mut client = Client::new(stream)?;
+    if let Err(reason) = client.recv_name(create_deadline()).await? {
+        anyhow::bail!("the bot didn't send its name: {}", reason);
+    }
+
+    let mut game = setup.game();
+    let rules = pick(game.rules(), seat);
+    let config = Config::new(&game.render_data(), pick(game.messages(), seat), &rules);
+    client.send_color(seat).await?;
+    client.send_opponent(theirs).await?;
+    client.send_config(&config).await?;
+    for rule in &rules {
+        client.send_rule(rule).await?;
+    }
+
+    let mut score = Score::default();
+    for (turn, &recorded) in replay.moves.iter().enumerate() {
+        if game.game_over() {
+            break;
+        }
+        let board = game.render_data();
+        for msg in pick(game.notices(), seat) {
+            client.send_message(msg, turn, seat, Some(&board)).await?;
+        }
+        let update = pick(game.messages(), seat);
+        client
+            .send_message(update, turn, seat, Some(&board))
+            .await?;
+        let answer = match read_timed_move(&mut client, turn).await?.res {
+            Ok(answer) => answer,
+            // the quiz goes on without an answer, but not without the bot
+            Err(ForfeitReason::Timeout) => {
+                println!("turn {}: no answer in time", turn);
+                score.missed += 1;
+                game.step(recorded);
+                continue;
+            }
+            Err(reason) => {
+                println!("turn {}: the bot forfeited: {}", turn, reason);
+                break;
+            }
+        };
+        let answer = pick(
+            game.board_moves(RedBlue {
+                red: answer,
+                blue: answer,
+            }),
+            seat,
+        );
+        let played = pick(recorded, seat);
+        if answer == played {
+            score.matched += 1;
+            game.step(recorded);
+            continue;
+        }
+        // the same position, with the bot's move played instead
+        let mut instead = setup.game();
+        for &moves in &replay.moves[..turn] {
+            instead.step(moves);
+        }
+        instead.step(with(recorded, seat, answer));
+        game.step(recorded);
+        let verdict = match standing(&instead, seat).cmp(&standing(&game, seat)) {
+            Ordering::Greater => {
+                score.better += 1;
+                "better"
+            }
+            Ordering::Equal => {
+                score.as_good += 1;
+                "as good"
+            }
+            Ordering::Less => {
+                score.worse += 1;
+                "worse"
+            }
+        };
+        println!(
+            "turn {}: {} played {:?}, the bot {:?}, which did {}",
+            turn, mine, played, answer, verdict
+        );
+    }
+    // a replay cut short leaves the bot waiting for a move that won't come
+    if game.game_over() {
+        let board = game.render_data();
+        let end = pick(game.messages(), seat);
+        let _ = client.send_message(end, 0, seat, Some(&board)).await;
+    }
+
+    let answered = score.matched + score.better + score.as_good + score.worse;
+    println!(
+        "{}: the bot matched {} of the {} moves it made as {}. Of the rest, {} were better, {} as good and {} worse. It missed {}.",
+        replay.match_id, score.matched, answered, mine, score.better, score.as_good, score.worse, score.missed
+    );
+    Ok(())
+}
+
+// How well off a player is a turn after a move: a win beats a game that goes
+// on, which beats a tie, which beats a loss, and a game that goes on is better
+// the more of the board the player reaches first.
+fn standing(game: &dyn Game, seat: Player) -> (u8, i64) {
+    let result = match (game.win_state(), seat) {
+        (Some(WinState::Tie), _) => WinState::Tie,
+        (Some(WinState::Win), Player::Red) | (Some(WinState::Loss), Player::Blue) => WinState::Win,
+        (Some(_), _) => WinState::Loss,
+        (None, _) => {
+            let heads = match game.messages().red {
+                ToClientMessage::Update(red, blue) | ToClientMessage::FoodUpdate(red, blue, _) => {
+                    RedBlue { red, blue }
+                }
+                _ => return (2, 0),
+            };
+            let board = game.render_data();
+            let areas = territory(&board.data, board.width, board.wrap, heads);
+            let lead = areas.red as i64 - areas.blue as i64;
+            return match seat {
+                Player::Red => (2, lead),
+                Player::Blue => (2, -lead),
+            };
+        }
+    };
+    match result {
+        WinState::Win => (3, 0),
+        WinState::Tie => (1, 0),
+        WinState::Loss => (0, 0),
+    }
+}
+
+fn pick<T>(both: RedBlue<T>, seat: Player) -> T {
+    match seat {
+        Player::Red => both.red,
+        Player::Blue => both.blue,
+    }
+}
+
+// the moves with this player's replaced
+fn with(moves: RedBlue<Direction>, seat: Player, direction: Direction) -> RedBlue<Direction> {
+    match seat {
+        Player::Red => RedBlue {
+            red: direction,
+            ..moves
+        },
+        Player::Blue => RedBlue {
+            blue: direction,
+            ..moves
+        },
+    }
+}
+
+#[test]
+fn standings() {
+    use snake_ai_battle::TronGame;
+    let mut game = TronGame::new(8, 8);
+    let moves = RedBlue {
+        red: Direction::Up,
+        blue: Direction::Up,
+    };
+    game.step(game.board_moves(moves));
+    // the board is split evenly
+    assert_eq!((2, 0), standing(&game, Player::Red));
+    game.set_win_state(WinState::Win);
+    assert_eq!((3, 0), standing(&game, Player::Red));
+    assert_eq!((0, 0), standing(&game, Player::Blue));
+}
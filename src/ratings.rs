@@ -0,0 +1,217 @@
+// Elo ratings by player name, for --ratings. They're loaded from a JSON file
+// at startup, updated after every game, ties included, and written back
+// straight away so a server that's stopped loses nothing. Each player's
+// timeouts, malformed moves and disconnects are kept with its rating, so a
+// long-running arena can see which bots misbehave, and turn them away with
+// --suspend-after.
+use serde::{Deserialize, Serialize};
+use snake_ai_battle::{RedBlue, WinState};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// a newcomer's rating
+const INITIAL: f64 = 1500.0;
+// the most a rating can change in one game
+const K: f64 = 32.0;
+
+#[derive(Clone)]
+pub struct Ratings(Option<Arc<Mutex<Table>>>);
+
+struct Table {
+    path: PathBuf,
+    ratings: BTreeMap<String, Record>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    rating: f64,
+    #[serde(flatten)]
+    conduct: Conduct,
+}
+
+// Files written before conduct was kept have only a rating for each player
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Saved {
+    Rating(f64),
+    Record(Record),
+}
+
+// how often a player broke the protocol
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Conduct {
+    pub timeouts: usize,
+    pub parse_errors: usize,
+    pub disconnects: usize,
+}
+
+impl Conduct {
+    pub fn violations(&self) -> usize {
+        self.timeouts + self.parse_errors + self.disconnects
+    }
+
+    fn add(&mut self, other: Conduct) {
+        self.timeouts += other.timeouts;
+        self.parse_errors += other.parse_errors;
+        self.disconnects += other.disconnects;
+    }
+}
+
+// a player's rating, as listed by the visualizer
+#[derive(Debug, Serialize)]
+pub struct Rating {
+    name: String,
+    rating: f64,
+    #[serde(flatten)]
+    conduct: Conduct,
+}
+
+impl Ratings {
+    // A file that doesn't exist yet is an empty table
+    pub fn load(path: Option<&PathBuf>) -> Result<Self, anyhow::Error> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Self(None)),
+        };
+        let saved: BTreeMap<String, Saved> = match std::fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json).map_err(|e| {
+                anyhow::anyhow!("couldn't read ratings from {}: {}", path.display(), e)
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let ratings = saved
+            .into_iter()
+            .map(|(name, saved)| {
+                let record = match saved {
+                    Saved::Rating(rating) => Record {
+                        rating,
+                        conduct: Conduct::default(),
+                    },
+                    Saved::Record(record) => record,
+                };
+                (name, record)
+            })
+            .collect();
+        Ok(Self(Some(Arc::new(Mutex::new(Table {
+            path: path.clone(),
+            ratings,
+        })))))
+    }
+
+    // Rates a game between these players, with the result from red's point
+    // of view, adds up how they broke the protocol in it, and returns how
+    // much each rating changed
+    pub fn update(
+        &self,
+        red: &str,
+        blue: &str,
+        result: WinState,
+        conduct: RedBlue<Conduct>,
+    ) -> Result<Option<RedBlue<f64>>, io::Error> {
+        let table = match &self.0 {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        let mut table = table.lock().unwrap();
+        let rating = |name| table.ratings.get(name).map_or(INITIAL, |r| r.rating);
+        let changes = changes(rating(red), rating(blue), result);
+        for (name, change, conduct) in [
+            (red, changes.red, conduct.red),
+            (blue, changes.blue, conduct.blue),
+        ] {
+            let record = table.ratings.entry(name.to_owned()).or_insert(Record {
+                rating: INITIAL,
+                conduct: Conduct::default(),
+            });
+            record.rating += change;
+            record.conduct.add(conduct);
+        }
+        save(&table.path, &table.ratings)?;
+        Ok(Some(changes))
+    }
+
+    // How often this player has broken the protocol, in every game rated
+    pub fn violations(&self, name: &str) -> usize {
+        let table = match &self.0 {
+            Some(table) => table.lock().unwrap(),
+            None => return 0,
+        };
+        table
+            .ratings
+            .get(name)
+            .map_or(0, |record| record.conduct.violations())
+    }
+
+    // Highest first
+    pub fn list(&self) -> Vec<Rating> {
+        let table = match &self.0 {
+            Some(table) => table.lock().unwrap(),
+            None => return Vec::new(),
+        };
+        let mut list: Vec<_> = table
+            .ratings
+            .iter()
+            .map(|(name, record)| Rating {
+                name: name.clone(),
+                rating: record.rating,
+                conduct: record.conduct,
+            })
+            .collect();
+        list.sort_by(|a, b| b.rating.partial_cmp(&a.rating).unwrap());
+        list
+    }
+}
+
+// writes to a temporary file first, so a crash can't leave half a table
+pub fn save(path: &Path, table: &impl Serialize) -> Result<(), io::Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(table)?)?;
+    std::fs::rename(&tmp, path)
+}
+
+// what each player gains, or loses if it's negative
+fn changes(red: f64, blue: f64, result: WinState) -> RedBlue<f64> {
+    let expected = 1.0 / (1.0 + 10f64.powf((blue - red) / 400.0));
+    let score = match result {
+        WinState::Win => 1.0,
+        WinState::Loss => 0.0,
+        WinState::Tie => 0.5,
+    };
+    let change = K * (score - expected);
+    RedBlue {
+        red: change,
+        blue: -change,
+    }
+}
+
+#[test]
+fn elo() {
+    // evenly matched players trade half of K
+    let even = changes(INITIAL, INITIAL, WinState::Win);
+    assert_eq!((16.0, -16.0), (even.red, even.blue));
+    assert_eq!(0.0, changes(INITIAL, INITIAL, WinState::Tie).red);
+    // a tie against a stronger player is worth something
+    assert!(changes(1400.0, 1600.0, WinState::Tie).red > 0.0);
+    // and beating a weaker one not much
+    assert!(changes(1800.0, 1400.0, WinState::Win).red < 3.0);
+}
+
+#[test]
+fn saved_records() {
+    // a rating on its own, as written before conduct was kept
+    let saved: BTreeMap<String, Saved> =
+        serde_json::from_str(r#"{"old":1516.0,"new":{"rating":1484.0,"timeouts":2}}"#).unwrap();
+    assert!(matches!(saved["old"], Saved::Rating(rating) if rating == 1516.0));
+    match &saved["new"] {
+        Saved::Record(record) => {
+            assert_eq!(1484.0, record.rating);
+            assert_eq!(2, record.conduct.violations());
+        }
+        Saved::Rating(_) => panic!("a record was read as a rating"),
+    }
+}
@@ -0,0 +1,563 @@
+// Plays back a game recorded with --record in the visualizer, for the
+// `replay` subcommand. Frames are shown at the pace the game was played,
+// sped up or slowed down, and Enter on stdin pauses and resumes playback. The
+// visualizer can also seek, pause and change speed through /control.
+use super::{
+    load_tls_config, start_webserver, GameInfo, Games, RenderFrame, Setup, CLI_OPTIONS,
+    REPLAY_VERSION,
+};
+use crate::ratings::Ratings;
+use crate::seal::Seal;
+use crate::tokens::Tokens;
+use serde::{Deserialize, Serialize};
+use snake_ai_battle::{
+    start_positions, step_pos, Direction, EndReason, ForfeitReason, Game, Occupancy, Player,
+    PowerUp, RedBlue, RenderData, ToClientMessage, WinState,
+};
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tracing::info;
+
+#[derive(Deserialize)]
+pub struct Header {
+    format: String,
+    version: u32,
+    pub match_id: String,
+}
+
+// the events replays are read for; the rest are passed over
+#[derive(Deserialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    Named {
+        player: Player,
+        name: String,
+    },
+    Settings {
+        mode: String,
+        width: usize,
+        height: usize,
+        flags: Vec<String>,
+    },
+    Setup(Setup),
+    // a game that went on from another's first turns, with --resume
+    Resumed {
+        moves: Vec<RedBlue<Direction>>,
+    },
+    Turn {
+        red: Direction,
+        blue: Direction,
+    },
+    Forfeit {
+        player: Player,
+        reason: ForfeitReason,
+    },
+    Aborted,
+    Board {
+        turn: usize,
+        changes: Vec<(usize, Occupancy)>,
+    },
+    Result {
+        winner: Option<Player>,
+        end_reasons: RedBlue<Option<EndReason>>,
+        turns: usize,
+        response_times: RedBlue<Responses>,
+    },
+    Sealed(Seal),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+pub struct Record {
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+// The records of a replay, read one line at a time into the same buffer, so
+// a replay of any length is read in the memory its longest line takes
+pub struct Records {
+    file: std::io::BufReader<std::fs::File>,
+    line: String,
+}
+
+impl Records {
+    // the line the last record was read from, as written but without its line
+    // feed, or the header's before the first record
+    pub fn line(&self) -> &str {
+        self.line.trim_end_matches(['\n', '\r'])
+    }
+}
+
+impl Iterator for Records {
+    type Item = Result<Record, anyhow::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.line.clear();
+        match self.file.read_line(&mut self.line) {
+            Ok(0) => None,
+            Ok(_) => Some(serde_json::from_str(self.line()).map_err(Into::into)),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+// Checks that a file is a replay this version can read, and returns its
+// header and the records after it
+pub fn records(path: &Path) -> Result<(Header, Records), anyhow::Error> {
+    let mut records = Records {
+        file: std::io::BufReader::new(std::fs::File::open(path)?),
+        line: String::new(),
+    };
+    if records.file.read_line(&mut records.line)? == 0 {
+        anyhow::bail!("{} is empty", path.display());
+    }
+    let header: Option<Header> = serde_json::from_str(records.line()).ok();
+    let header = match header {
+        Some(header) if header.format == "snake-ai-battle-replay" => header,
+        _ => anyhow::bail!("{} isn't a replay", path.display()),
+    };
+    if header.version != REPLAY_VERSION {
+        anyhow::bail!(
+            "{} is a version {} replay, but only version {} can be read",
+            path.display(),
+            header.version,
+            REPLAY_VERSION
+        );
+    }
+    Ok((header, records))
+}
+
+// The rules of a recorded game and the moves of every turn it was still going
+// after, for another game to go on from with --resume
+#[derive(Debug)]
+pub struct Prefix {
+    pub match_id: String,
+    pub setup: Setup,
+    pub moves: Vec<RedBlue<Direction>>,
+}
+
+impl Prefix {
+    // the game as it stood after its first turns
+    pub fn game_after(&self, turns: usize) -> Box<dyn Game> {
+        let mut game = self.setup.game();
+        for moves in &self.moves[..turns] {
+            game.step(*moves);
+        }
+        game
+    }
+}
+
+pub fn prefix(path: &Path) -> Result<Prefix, anyhow::Error> {
+    let (header, records) = records(path)?;
+    let mut setup = None;
+    let mut moves = Vec::new();
+    for record in records {
+        match record?.event {
+            Event::Setup(recorded) => setup = Some(recorded),
+            // a resumed game's turns start with the game it went on from
+            Event::Resumed { moves: earlier } => moves.extend(earlier),
+            Event::Turn { red, blue } => moves.push(RedBlue { red, blue }),
+            _ => (),
+        }
+    }
+    let setup = match setup {
+        Some(setup) => setup,
+        None => anyhow::bail!("{} has no Setup event to go on from", path.display()),
+    };
+    // the turn that ended the game, if it's there, has nothing after it
+    let mut game = setup.game();
+    let ended = moves.iter().position(|played| {
+        game.step(*played);
+        game.game_over()
+    });
+    if let Some(turn) = ended {
+        moves.truncate(turn);
+    }
+    Ok(Prefix {
+        match_id: header.match_id,
+        setup,
+        moves,
+    })
+}
+
+// the result from red's point of view
+pub fn win_state(winner: Option<Player>) -> WinState {
+    match winner {
+        Some(Player::Red) => WinState::Win,
+        Some(Player::Blue) => WinState::Loss,
+        None => WinState::Tie,
+    }
+}
+
+// each player's response times, from the Result event
+#[derive(Deserialize)]
+pub struct Responses {
+    pub moves: usize,
+    pub total_ms: f64,
+    pub max_ms: Option<f64>,
+}
+
+// A recorded game, as the boards to show and when they were first shown
+pub struct Replay {
+    pub match_id: String,
+    pub names: RedBlue<String>,
+    pub mode: String,
+    pub width: usize,
+    pub height: usize,
+    pub wrap: bool,
+    pub boards: Vec<Board>,
+    pub result: Option<WinState>,
+    pub end_reasons: RedBlue<Option<EndReason>>,
+    pub turns: usize,
+    pub response_times: Option<RedBlue<Responses>>,
+    pub setup: Option<Setup>,
+    // as seen on the board, from the start of the game, including any
+    // played before it was resumed
+    pub moves: Vec<RedBlue<Direction>>,
+}
+
+// the board as it was after a turn, and where each player's head was
+pub struct Board {
+    pub turn: usize,
+    pub timestamp_ms: u64,
+    pub cells: Vec<Occupancy>,
+    pub heads: RedBlue<usize>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let (header, records) = records(path)?;
+        let mut replay = Replay {
+            match_id: header.match_id,
+            names: RedBlue {
+                red: String::new(),
+                blue: String::new(),
+            },
+            mode: String::new(),
+            width: 0,
+            height: 0,
+            wrap: false,
+            boards: Vec::new(),
+            result: None,
+            end_reasons: RedBlue {
+                red: None,
+                blue: None,
+            },
+            turns: 0,
+            response_times: None,
+            setup: None,
+            moves: Vec::new(),
+        };
+        let mut board = Vec::new();
+        let mut heads = RedBlue { red: 0, blue: 0 };
+        // where the players were when a resumed game went on
+        let mut resumed = None;
+        for record in records {
+            let record = record?;
+            match record.event {
+                Event::Setup(recorded) => replay.setup = Some(recorded),
+                Event::Resumed { moves } => {
+                    let mut game = match &replay.setup {
+                        Some(setup) => setup.game(),
+                        None => anyhow::bail!("a game was resumed before its setup"),
+                    };
+                    for moves in &moves {
+                        game.step(*moves);
+                    }
+                    replay.moves.extend(moves);
+                    resumed = match game.messages().red {
+                        ToClientMessage::Update(red, blue)
+                        | ToClientMessage::FoodUpdate(red, blue, _) => Some(RedBlue { red, blue }),
+                        _ => anyhow::bail!("a game was resumed after it was over"),
+                    };
+                }
+                Event::Named { player, name } => match player {
+                    Player::Red => replay.names.red = name,
+                    Player::Blue => replay.names.blue = name,
+                },
+                Event::Settings {
+                    mode,
+                    width,
+                    height,
+                    flags,
+                } => {
+                    if width < 2 || height < 2 {
+                        anyhow::bail!("a {}x{} board is too small", width, height);
+                    }
+                    replay.mode = mode;
+                    replay.width = width;
+                    replay.height = height;
+                    replay.wrap = flags.iter().any(|flag| flag == "WRAP");
+                    board = vec![Occupancy::Free; width * height];
+                    heads = resumed.unwrap_or_else(|| start_positions(width, height));
+                }
+                // directions are as seen on the board, so heads can be
+                // followed without knowing the rules
+                Event::Turn { red, blue } => {
+                    if board.is_empty() {
+                        anyhow::bail!("a turn was played before the game's settings");
+                    }
+                    let step = |pos, d| {
+                        let to = step_pos(replay.width, board.len(), pos, d);
+                        // a boost carries a player one more cell the same way
+                        if board[to] == Occupancy::PowerUp(PowerUp::Boost) {
+                            step_pos(replay.width, board.len(), to, d)
+                        } else {
+                            to
+                        }
+                    };
+                    heads = RedBlue {
+                        red: step(heads.red, red),
+                        blue: step(heads.blue, blue),
+                    };
+                    replay.moves.push(RedBlue { red, blue });
+                }
+                Event::Board { turn, changes } => {
+                    for (pos, cell) in changes {
+                        match board.get_mut(pos) {
+                            Some(seen) => *seen = cell,
+                            None => anyhow::bail!("position {} is off the board", pos),
+                        }
+                    }
+                    replay.boards.push(Board {
+                        turn,
+                        timestamp_ms: record.timestamp_ms,
+                        cells: board.clone(),
+                        heads,
+                    });
+                }
+                Event::Result {
+                    winner,
+                    end_reasons,
+                    turns,
+                    response_times,
+                } => {
+                    replay.result = Some(win_state(winner));
+                    replay.end_reasons = end_reasons;
+                    replay.turns = turns;
+                    replay.response_times = Some(response_times);
+                }
+                _ => (),
+            }
+        }
+        if replay.boards.is_empty() {
+            anyhow::bail!("{} has no boards to play back", path.display());
+        }
+        Ok(replay)
+    }
+
+    // the result is only shown with the last board
+    fn frame(&self, index: usize) -> RenderFrame {
+        let last = index + 1 == self.boards.len();
+        RenderFrame::new(&RenderData {
+            width: self.width,
+            height: self.height,
+            data: self.boards[index].cells.as_slice().into(),
+            wrap: self.wrap,
+            result: if last { self.result } else { None },
+            end_reasons: if last {
+                self.end_reasons
+            } else {
+                RedBlue {
+                    red: None,
+                    blue: None,
+                }
+            },
+        })
+    }
+}
+
+// What the visualizer's timeline controls, or Enter on stdin, ask of playback
+pub enum Control {
+    // to the board after this turn, or the last one
+    Seek(usize),
+    // None toggles
+    Pause(Option<bool>),
+    Speed(f64),
+}
+
+// Where playback is, for the visualizer's timeline
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Status {
+    turn: usize,
+    turns: usize,
+    paused: bool,
+    speed: f64,
+}
+
+// The web server's handle on playback
+#[derive(Clone)]
+pub struct Controls {
+    send: mpsc::UnboundedSender<Control>,
+    status: Arc<Mutex<Status>>,
+}
+
+impl Controls {
+    pub fn send(&self, control: Control) {
+        // playback runs until the server stops
+        let _ = self.send.send(control);
+    }
+
+    pub fn status(&self) -> Status {
+        *self.status.lock().unwrap()
+    }
+}
+
+pub async fn run(path: &Path, speed: f64, paused: bool) -> Result<(), anyhow::Error> {
+    let replay = Replay::load(path)?;
+    let games = Games::new(replay.width, replay.height);
+    let tls = match (&CLI_OPTIONS.tls_cert, &CLI_OPTIONS.tls_key) {
+        (Some(cert), Some(key)) => Some(load_tls_config(cert, key)?),
+        _ => None,
+    };
+    let last = replay.boards.len() - 1;
+    let mut status = Status {
+        turn: replay.boards[0].turn,
+        turns: replay.boards[last].turn,
+        paused,
+        speed,
+    };
+    let (send, mut controls) = mpsc::unbounded_channel();
+    let handle = Controls {
+        send,
+        status: Arc::new(Mutex::new(status)),
+    };
+    let ratings = Ratings::load(None)?;
+    let tokens = Tokens::load(
+        CLI_OPTIONS.tokens.as_ref(),
+        CLI_OPTIONS.control_token.as_deref(),
+    )?;
+    let addr = CLI_OPTIONS.visualizer_addr;
+    let playback = Some(handle.clone());
+    start_webserver(games.clone(), ratings, tokens, addr, tls, playback).await?;
+    let (frames, recv) = watch::channel(replay.frame(0));
+    games.start(
+        GameInfo {
+            id: replay.match_id.clone(),
+            red: replay.names.red.clone(),
+            blue: replay.names.blue.clone(),
+            series: None,
+        },
+        recv,
+    );
+
+    // each line on stdin toggles pausing
+    let toggle = handle.clone();
+    std::thread::spawn(move || {
+        for _ in std::io::stdin().lock().lines() {
+            toggle.send(Control::Pause(None));
+        }
+    });
+    if paused {
+        info!("Paused; press Enter to play");
+    } else {
+        info!("Playing at {}x; press Enter to pause", speed);
+    }
+    let mut index = 0;
+    // when the board after this one is due, at this speed. The clock the
+    // timestamps came from may have been set back while recording.
+    let due = |index: usize, speed: f64| {
+        let boards = &replay.boards;
+        let gap = boards[index + 1]
+            .timestamp_ms
+            .saturating_sub(boards[index].timestamp_ms);
+        Instant::now() + Duration::from_millis(gap).div_f64(speed)
+    };
+    // a game that ended before its first turn has a single board
+    let mut next = if last > 0 {
+        due(0, speed)
+    } else {
+        Instant::now()
+    };
+    loop {
+        *handle.status.lock().unwrap() = status;
+        let control = if status.paused || index == last {
+            controls.recv().await
+        } else {
+            match tokio::time::timeout_at(next, controls.recv()).await {
+                Ok(control) => control,
+                Err(_) => {
+                    index += 1;
+                    status.turn = replay.boards[index].turn;
+                    let _ = frames.broadcast(replay.frame(index));
+                    if index == last {
+                        info!("Replay finished; the visualizer shows the end until interrupted");
+                    } else {
+                        next = due(index, status.speed);
+                    }
+                    continue;
+                }
+            }
+        };
+        match control {
+            Some(Control::Seek(turn)) => {
+                index = replay
+                    .boards
+                    .iter()
+                    .position(|board| board.turn >= turn)
+                    .unwrap_or(last);
+                status.turn = replay.boards[index].turn;
+                let _ = frames.broadcast(replay.frame(index));
+            }
+            Some(Control::Pause(paused)) => {
+                status.paused = paused.unwrap_or(!status.paused);
+                info!(paused = status.paused, "Playback");
+            }
+            Some(Control::Speed(speed)) => status.speed = speed,
+            // the web server holds a sender for as long as it runs
+            None => return Ok(()),
+        }
+        // the board shown now gets its full time at the new pace
+        if index < last {
+            next = due(index, status.speed);
+        }
+    }
+}
+
+#[test]
+fn events() {
+    let record: Record = serde_json::from_str(
+        r#"{"timestamp_ms":1,"match_id":"x","event":"Board","turn":3,"changes":[[0,"Red"],[1,"Wall"],[2,"Boost"],[3,null]]}"#,
+    )
+    .unwrap();
+    match record.event {
+        Event::Board { turn, changes } => assert_eq!(
+            (
+                3,
+                vec![
+                    (0, Occupancy::Occupied(Player::Red)),
+                    (1, Occupancy::Wall),
+                    (2, Occupancy::PowerUp(snake_ai_battle::PowerUp::Boost)),
+                    (3, Occupancy::Free),
+                ]
+            ),
+            (turn, changes)
+        ),
+        _ => panic!("expected a Board event"),
+    }
+    let record: Record = serde_json::from_str(
+        r#"{"timestamp_ms":2,"event":"Result","winner":"Blue","end_reasons":{"red":"Timeout","blue":null},"turns":3,"response_times":{"red":{"moves":2,"total_ms":3.0,"max_ms":2.0},"blue":{"moves":3,"total_ms":1.5,"max_ms":0.5}}}"#,
+    )
+    .unwrap();
+    match record.event {
+        Event::Result {
+            winner,
+            end_reasons,
+            ..
+        } => {
+            assert_eq!(Some(Player::Blue), winner);
+            let timeout = snake_ai_battle::ForfeitReason::Timeout;
+            assert_eq!(Some(EndReason::Forfeit(timeout)), end_reasons.red);
+        }
+        _ => panic!("expected a Result event"),
+    }
+    let record: Record =
+        serde_json::from_str(r#"{"timestamp_ms":3,"event":"Timing","turn":0}"#).unwrap();
+    assert!(matches!(record.event, Event::Other));
+}
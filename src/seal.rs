@@ -0,0 +1,128 @@
+// Seals replays written with --record against tampering, for tournaments with
+// something at stake. Every line of a replay, its header first, is chained
+// into a SHA-256 hash as it's written, and a Sealed event after the last line
+// gives the hash, signed with --signing-key's Ed25519 key if there is one.
+// Anyone can chain a changed replay over again, so only a signature from a
+// key they don't hold shows that a replay is as the server wrote it.
+use ring::digest;
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+
+// the hash of every line so far, each hashed with the one before
+pub struct Chain([u8; 32]);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Seal {
+    pub chain: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Chain([0; 32])
+    }
+
+    // a line as written, without its line feed
+    pub fn add(&mut self, line: &[u8]) {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(&self.0);
+        context.update(line);
+        self.0.copy_from_slice(context.finish().as_ref());
+    }
+
+    pub fn seal(&self, key: Option<&Ed25519KeyPair>) -> Seal {
+        Seal {
+            chain: hex(&self.0),
+            public_key: key.map(|key| hex(key.public_key().as_ref())),
+            signature: key.map(|key| hex(key.sign(&self.0).as_ref())),
+        }
+    }
+
+    // Checks that the seal is for the lines chained so far, and returns the
+    // public key it was signed with, if it was signed
+    pub fn check<'a>(&self, seal: &'a Seal) -> Result<Option<&'a str>, anyhow::Error> {
+        if seal.chain != hex(&self.0) {
+            anyhow::bail!("the replay was changed after it was sealed");
+        }
+        let (key, signature) = match (&seal.public_key, &seal.signature) {
+            (Some(key), Some(signature)) => (key, signature),
+            (None, None) => return Ok(None),
+            _ => anyhow::bail!("the replay's seal has only half of a signature"),
+        };
+        let (key_bytes, signature) = match (unhex(key), unhex(signature)) {
+            (Some(key), Some(signature)) => (key, signature),
+            _ => anyhow::bail!("the replay's signature isn't hex"),
+        };
+        signature::UnparsedPublicKey::new(&signature::ED25519, key_bytes)
+            .verify(&self.0, &signature)
+            .map_err(|_| anyhow::anyhow!("the replay's signature doesn't match it"))?;
+        Ok(Some(key))
+    }
+}
+
+pub fn load_key(path: &std::ffi::OsStr) -> Result<Ed25519KeyPair, std::ffi::OsString> {
+    let der = std::fs::read(path)
+        .map_err(|e| format!("couldn't read {}: {}", path.to_string_lossy(), e))?;
+    // openssl writes keys without their public half
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der).map_err(|e| {
+        format!(
+            "{} isn't an Ed25519 key in PKCS#8 DER: {}",
+            path.to_string_lossy(),
+            e
+        )
+        .into()
+    })
+}
+
+pub fn parse_public_key(s: &str) -> Result<String, String> {
+    match unhex(s) {
+        Some(key) if key.len() == 32 => Ok(s.to_ascii_lowercase()),
+        _ => Err("expected an Ed25519 public key, as 64 hex digits".to_owned()),
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[test]
+fn sealing() {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+    let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    let lines: [&[u8]; 2] = [
+        br#"{"format":"snake-ai-battle-replay"}"#,
+        br#"{"event":"Turn"}"#,
+    ];
+    let mut chain = Chain::new();
+    for line in lines {
+        chain.add(line);
+    }
+    let seal = chain.seal(Some(&key));
+    let public_key = hex(key.public_key().as_ref());
+    assert_eq!(Some(public_key.as_str()), chain.check(&seal).unwrap());
+    assert_eq!(None, chain.check(&chain.seal(None)).unwrap());
+
+    // a changed line, or a signature from another chain
+    let mut changed = Chain::new();
+    changed.add(lines[0]);
+    changed.add(br#"{"event":"Forfeit"}"#);
+    assert!(changed.check(&seal).is_err());
+    let forged = Seal {
+        chain: seal.chain.clone(),
+        ..changed.seal(Some(&key))
+    };
+    assert!(chain.check(&forged).is_err());
+}
@@ -0,0 +1,425 @@
+// Classic snake: an apple is always on the board somewhere, eating it makes
+// you one cell longer, and your tail follows you around.
+use super::{
+    announced_rules, close_ring, crash, end_messages, forfeit_result, hits_wall, invert_update,
+    start_positions, step_pos, Direction, EndReason, ForfeitReason, Game, Map, Occupancy, Player,
+    RedBlue, RenderData, Rule, ShrinkSchedule, ToClientMessage, WinState,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+
+// snakes start as one cell and grow to this over their first turns
+const START_LENGTH: usize = 3;
+
+/// A game of classic snake. A snake that crashes loses; if both crash on the
+/// same turn, or the turn limit is reached, the longer snake wins.
+pub struct SnakeGame {
+    width: usize,
+    height: usize,
+    board: Vec<Occupancy>,
+    // tail first, head last
+    bodies: RedBlue<VecDeque<usize>>,
+    // cells still to grow by
+    growth: RedBlue<usize>,
+    food: Option<usize>,
+    // where apples appear
+    rng: StdRng,
+    wrap: bool,
+    walls: Vec<usize>,
+    shrink: Option<ShrinkSchedule>,
+    turn: usize,
+    // one per cell by default
+    max_turns: Option<usize>,
+    // as seen on the board
+    last_moves: RedBlue<Option<Direction>>,
+    endgame: Option<WinState>,
+    end_reasons: RedBlue<Option<EndReason>>,
+    forfeits: RedBlue<Option<ForfeitReason>>,
+}
+
+impl SnakeGame {
+    /// Starts a game with both snakes where they'd start in Tron, and an
+    /// apple in a random free cell. Both dimensions must be at least 2.
+    pub fn new(width: usize, height: usize) -> Self {
+        Self::seeded(width, height, rand::random())
+    }
+
+    /// Starts a game whose apples appear just where they did in another game
+    /// with the same seed and moves.
+    pub fn seeded(width: usize, height: usize, seed: u64) -> Self {
+        let mut board = vec![Occupancy::Free; width * height];
+        let start = start_positions(width, height);
+        board[start.red] = Occupancy::Occupied(Player::Red);
+        board[start.blue] = Occupancy::Occupied(Player::Blue);
+        let mut game = Self {
+            width,
+            height,
+            board,
+            bodies: RedBlue {
+                red: VecDeque::from(vec![start.red]),
+                blue: VecDeque::from(vec![start.blue]),
+            },
+            growth: RedBlue {
+                red: START_LENGTH - 1,
+                blue: START_LENGTH - 1,
+            },
+            food: None,
+            rng: StdRng::seed_from_u64(seed),
+            wrap: false,
+            walls: Vec::new(),
+            shrink: None,
+            turn: 0,
+            max_turns: None,
+            last_moves: RedBlue {
+                red: None,
+                blue: None,
+            },
+            endgame: None,
+            end_reasons: RedBlue {
+                red: None,
+                blue: None,
+            },
+            forfeits: RedBlue {
+                red: None,
+                blue: None,
+            },
+        };
+        game.place_food();
+        game
+    }
+
+    /// Lets snakes wrap around the edges of the board instead of crashing
+    /// into them.
+    pub fn set_wrap(&mut self, wrap: bool) {
+        self.wrap = wrap;
+    }
+
+    /// Puts the walls from a map on the board. The map must be the same size
+    /// as the board.
+    pub fn add_map(&mut self, map: &Map) {
+        for &wall in &map.walls {
+            self.board[wall] = Occupancy::Wall;
+        }
+        self.walls.extend_from_slice(&map.walls);
+        if self
+            .food
+            .is_some_and(|food| self.board[food] == Occupancy::Wall)
+        {
+            self.place_food();
+        }
+    }
+
+    /// Shrinks the arena on this schedule, for sudden death.
+    pub fn set_shrink(&mut self, shrink: Option<ShrinkSchedule>) {
+        self.shrink = shrink;
+    }
+
+    /// Ends the game after this many turns rather than one per cell, and
+    /// gives it to the longer snake.
+    pub fn set_max_turns(&mut self, max_turns: Option<usize>) {
+        self.max_turns = max_turns;
+    }
+
+    pub fn heads(&self) -> RedBlue<usize> {
+        self.bodies.as_ref().map(|body| *body.back().unwrap())
+    }
+
+    pub fn lengths(&self) -> RedBlue<usize> {
+        self.bodies.as_ref().map(VecDeque::len)
+    }
+
+    /// Where the apple is. There's none only when the board is full.
+    pub fn food(&self) -> Option<usize> {
+        self.food
+    }
+
+    fn place_food(&mut self) {
+        let free = self.board.iter().filter(|o| **o == Occupancy::Free).count();
+        self.food = if free == 0 {
+            None
+        } else {
+            let nth = self.rng.gen_range(0, free);
+            let idx = (0..self.board.len())
+                .filter(|&i| self.board[i] == Occupancy::Free)
+                .nth(nth)
+                .unwrap();
+            self.board[idx] = Occupancy::Food;
+            Some(idx)
+        };
+    }
+
+    // the longer snake wins
+    fn by_length(&self) -> WinState {
+        let lengths = self.lengths();
+        if lengths.red > lengths.blue {
+            WinState::Win
+        } else if lengths.red < lengths.blue {
+            WinState::Loss
+        } else {
+            WinState::Tie
+        }
+    }
+}
+
+impl Game for SnakeGame {
+    fn board_moves(&self, moves: RedBlue<Direction>) -> RedBlue<Direction> {
+        RedBlue {
+            red: moves.red,
+            blue: super::invert_direction(moves.blue),
+        }
+    }
+
+    fn step(&mut self, moves: RedBlue<Direction>) {
+        if self.endgame.is_some() {
+            return;
+        }
+        self.last_moves = moves.map(Some);
+        let cells = self.board.len();
+        let heads = self.heads();
+        let red_wall = !self.wrap && hits_wall(self.width, cells, heads.red, moves.red);
+        let blue_wall = !self.wrap && hits_wall(self.width, cells, heads.blue, moves.blue);
+        let next = RedBlue {
+            red: step_pos(self.width, cells, heads.red, moves.red),
+            blue: step_pos(self.width, cells, heads.blue, moves.blue),
+        };
+
+        // tails move out of the way first, so snakes can follow them
+        for (body, growth) in [
+            (&mut self.bodies.red, &mut self.growth.red),
+            (&mut self.bodies.blue, &mut self.growth.blue),
+        ] {
+            if *growth > 0 {
+                *growth -= 1;
+            } else {
+                let tail = body.pop_front().unwrap();
+                // unless the arena has shrunk over it
+                if self.board[tail] != Occupancy::Wall {
+                    self.board[tail] = Occupancy::Free;
+                }
+            }
+        }
+
+        // if we didnt check this, who won would depend on update order.
+        // Snakes that swap cells each land on the other's neck, so both crash.
+        let head_on = !red_wall && !blue_wall && next.red == next.blue;
+        let crashed = |wall, pos| {
+            if wall {
+                Some(EndReason::Wall)
+            } else {
+                crash(self.board[pos], head_on).err()
+            }
+        };
+        self.end_reasons = RedBlue {
+            red: crashed(red_wall, next.red),
+            blue: crashed(blue_wall, next.blue),
+        };
+        let red_crashed = self.end_reasons.red.is_some();
+        let blue_crashed = self.end_reasons.blue.is_some();
+
+        let mut eaten = false;
+        for (crashed, pos, player) in [
+            (red_crashed, next.red, Player::Red),
+            (blue_crashed, next.blue, Player::Blue),
+        ] {
+            if crashed {
+                continue;
+            }
+            let (body, growth) = match player {
+                Player::Red => (&mut self.bodies.red, &mut self.growth.red),
+                Player::Blue => (&mut self.bodies.blue, &mut self.growth.blue),
+            };
+            if self.board[pos] == Occupancy::Food {
+                *growth += 1;
+                eaten = true;
+            }
+            self.board[pos] = Occupancy::Occupied(player);
+            body.push_back(pos);
+        }
+        self.turn += 1;
+        if let Some(ring) = self.shrink.and_then(|s| s.ring_closing(self.turn)) {
+            close_ring(&mut self.board, self.width, ring);
+            eaten |= self
+                .food
+                .is_some_and(|food| self.board[food] == Occupancy::Wall);
+        }
+        if eaten {
+            self.place_food();
+        }
+
+        self.endgame = match (red_crashed, blue_crashed) {
+            (true, true) => Some(self.by_length()),
+            (true, false) => Some(WinState::Loss),
+            (false, true) => Some(WinState::Win),
+            (false, false) if self.turn >= self.max_turns.unwrap_or(cells) => {
+                self.end_reasons = RedBlue {
+                    red: Some(EndReason::TurnLimit),
+                    blue: Some(EndReason::TurnLimit),
+                };
+                Some(self.by_length())
+            }
+            (false, false) => None,
+        };
+    }
+
+    fn messages(&self) -> RedBlue<ToClientMessage> {
+        if let Some(win) = self.endgame {
+            return end_messages(win, self.end_reasons);
+        }
+        let heads = self.heads();
+        let red = match self.food {
+            Some(food) => ToClientMessage::FoodUpdate(heads.red, heads.blue, food),
+            None => ToClientMessage::Update(heads.red, heads.blue),
+        };
+        RedBlue {
+            red,
+            blue: invert_update(red, self.board.len()),
+        }
+    }
+
+    fn render_data(&self) -> RenderData<'_> {
+        RenderData {
+            width: self.width,
+            height: self.height,
+            data: Cow::Borrowed(&self.board),
+            wrap: self.wrap,
+            result: self.endgame,
+            end_reasons: self.end_reasons,
+        }
+    }
+
+    fn win_state(&self) -> Option<WinState> {
+        self.endgame
+    }
+
+    fn set_win_state(&mut self, w: WinState) {
+        self.endgame = Some(w);
+        self.end_reasons = RedBlue {
+            red: None,
+            blue: None,
+        };
+    }
+
+    fn forfeit(&mut self, reasons: RedBlue<Option<ForfeitReason>>) {
+        if let Some(win) = forfeit_result(reasons) {
+            self.set_win_state(win);
+            self.end_reasons = reasons.map(|reason| reason.map(EndReason::Forfeit));
+            self.forfeits = reasons;
+        }
+    }
+
+    fn forfeits(&self) -> RedBlue<Option<ForfeitReason>> {
+        self.forfeits
+    }
+
+    fn end_reasons(&self) -> RedBlue<Option<EndReason>> {
+        self.end_reasons
+    }
+
+    fn previous_moves(&self) -> RedBlue<Option<Direction>> {
+        RedBlue {
+            red: self.last_moves.red,
+            blue: self.last_moves.blue.map(super::invert_direction),
+        }
+    }
+
+    fn turns(&self) -> usize {
+        self.turn
+    }
+
+    fn rules(&self) -> RedBlue<Vec<Rule>> {
+        announced_rules(self.wrap, &self.walls, self.shrink, self.board.len())
+    }
+}
+
+#[test]
+fn growth() {
+    use Direction::*;
+    // red starts at 25 and blue at 38
+    let mut game = SnakeGame::new(8, 8);
+    let move_food = |game: &mut SnakeGame, to: usize| {
+        if let Some(food) = game.food {
+            game.board[food] = Occupancy::Free;
+        }
+        game.food = Some(to);
+        game.board[to] = Occupancy::Food;
+    };
+    move_food(&mut game, 36);
+    let turns = [
+        (Right, Right),
+        (Right, Down),
+        (Right, Down),
+        // red eats the apple
+        (Down, Down),
+        // and grows on the turn after
+        (Right, Left),
+        (Right, Left),
+    ];
+    for (turn, &(red, blue)) in turns.iter().enumerate() {
+        game.step(RedBlue { red, blue });
+        if turn == 2 {
+            assert_eq!(game.lengths().red, 3);
+            assert_eq!(game.board[25], Occupancy::Free);
+            assert_eq!(game.board[26], Occupancy::Occupied(Player::Red));
+        }
+        if turn == 3 {
+            // out of the way of both snakes
+            move_food(&mut game, 0);
+        }
+    }
+    assert_eq!(game.heads().red, 38);
+    assert_eq!(game.lengths().red, 4);
+    assert_eq!(game.lengths().blue, 3);
+    assert_eq!(game.board[27], Occupancy::Free);
+    assert!(!game.game_over());
+    // turning back into its own body crashes
+    game.step(RedBlue {
+        red: Left,
+        blue: Left,
+    });
+    assert_eq!(game.win_state(), Some(WinState::Loss));
+}
+
+#[test]
+fn head_swaps() {
+    use Direction::*;
+    // red starts at 0 and blue at 13, and they meet in the middle
+    let mut game = SnakeGame::new(7, 2);
+    if let Some(food) = game.food {
+        game.board[food] = Occupancy::Free;
+    }
+    game.food = Some(3);
+    game.board[3] = Occupancy::Food;
+    for &(red, blue) in [(Down, Left), (Right, Left), (Right, Left)].iter() {
+        game.step(RedBlue { red, blue });
+    }
+    assert_eq!(9, game.heads().red);
+    assert_eq!(10, game.heads().blue);
+    // passing through each other crashes them both, and they're as long as
+    // each other
+    game.step(RedBlue {
+        red: Right,
+        blue: Left,
+    });
+    assert_eq!(Some(WinState::Tie), game.win_state());
+}
+
+#[test]
+fn seeds() {
+    use Direction::*;
+    // with the same seed and moves, every apple lands in the same place
+    let mut games = [SnakeGame::seeded(8, 8, 7), SnakeGame::seeded(8, 8, 7)];
+    for &(red, blue) in [(Up, Down), (Right, Left), (Right, Left)].iter() {
+        assert_eq!(games[0].food, games[1].food);
+        for game in games.iter_mut() {
+            // a fresh apple each turn, so there are plenty to compare
+            if let Some(food) = game.food {
+                game.board[food] = Occupancy::Free;
+            }
+            game.place_food();
+            game.step(RedBlue { red, blue });
+        }
+    }
+    assert_eq!(games[0].board, games[1].board);
+}
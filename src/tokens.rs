@@ -0,0 +1,193 @@
+// Named tokens for the visualizer's controls, for --tokens. Only their SHA-256
+// hashes are kept, in a JSON file that's rewritten whenever a token is issued,
+// rotated or revoked at /tokens, so tokens change without a restart and
+// without holding up the games being played. A "read" token can see which
+// tokens there are, and a "control" token can do that and use the controls.
+use crate::ratings::save;
+use crate::seal::hex;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Control,
+}
+
+#[derive(Clone)]
+pub struct Tokens(Arc<Mutex<Table>>);
+
+struct Table {
+    path: Option<PathBuf>,
+    tokens: BTreeMap<String, Token>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Token {
+    scope: Scope,
+    sha256: String,
+    // only for this run, like --control-token's, and never saved
+    #[serde(skip)]
+    temporary: bool,
+}
+
+// a token, as listed by the visualizer
+#[derive(Debug, Serialize)]
+pub struct Listed {
+    name: String,
+    scope: Scope,
+}
+
+impl Tokens {
+    // A file that doesn't exist yet has no tokens. --control-token's token
+    // goes in as "control-token".
+    pub fn load(
+        path: Option<&PathBuf>,
+        control_token: Option<&str>,
+    ) -> Result<Self, anyhow::Error> {
+        let mut tokens = match path.map(|path| (path, std::fs::read(path))) {
+            Some((path, Ok(json))) => serde_json::from_slice(&json).map_err(|e| {
+                anyhow::anyhow!("couldn't read tokens from {}: {}", path.display(), e)
+            })?,
+            Some((_, Err(e))) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => BTreeMap::new(),
+        };
+        if let Some(token) = control_token {
+            tokens.insert(
+                "control-token".to_owned(),
+                Token {
+                    scope: Scope::Control,
+                    sha256: sha256(token),
+                    temporary: true,
+                },
+            );
+        }
+        Ok(Self(Arc::new(Mutex::new(Table {
+            path: path.cloned(),
+            tokens,
+        }))))
+    }
+
+    // What the bearer of an Authorization header may do, if anything
+    pub fn scope(&self, header: Option<&str>) -> Option<Scope> {
+        let token = header?.strip_prefix("Bearer ")?;
+        let hash = sha256(token);
+        let table = self.0.lock().unwrap();
+        table
+            .tokens
+            .values()
+            .find(|known| same(known.sha256.as_bytes(), hash.as_bytes()))
+            .map(|known| known.scope)
+    }
+
+    pub fn has_control(&self) -> bool {
+        let table = self.0.lock().unwrap();
+        table
+            .tokens
+            .values()
+            .any(|token| token.scope == Scope::Control)
+    }
+
+    // Makes up a new token by this name, in place of any it had, and returns
+    // it. Without a scope, it keeps the old token's, and a new one can only
+    // read.
+    pub fn issue(
+        &self,
+        name: &str,
+        scope: Option<Scope>,
+        temporary: bool,
+    ) -> Result<String, io::Error> {
+        let token = format!(
+            "{:016x}{:016x}",
+            rand::random::<u64>(),
+            rand::random::<u64>()
+        );
+        let mut table = self.0.lock().unwrap();
+        let scope = scope
+            .or_else(|| table.tokens.get(name).map(|old| old.scope))
+            .unwrap_or(Scope::Read);
+        table.tokens.insert(
+            name.to_owned(),
+            Token {
+                scope,
+                sha256: sha256(&token),
+                temporary,
+            },
+        );
+        table.save()?;
+        Ok(token)
+    }
+
+    // false if there was no token by this name
+    pub fn revoke(&self, name: &str) -> Result<bool, io::Error> {
+        let mut table = self.0.lock().unwrap();
+        if table.tokens.remove(name).is_none() {
+            return Ok(false);
+        }
+        table.save()?;
+        Ok(true)
+    }
+
+    pub fn list(&self) -> Vec<Listed> {
+        let table = self.0.lock().unwrap();
+        table
+            .tokens
+            .iter()
+            .map(|(name, token)| Listed {
+                name: name.clone(),
+                scope: token.scope,
+            })
+            .collect()
+    }
+}
+
+impl Table {
+    fn save(&self) -> Result<(), io::Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let kept: BTreeMap<_, _> = self
+            .tokens
+            .iter()
+            .filter(|(_, token)| !token.temporary)
+            .collect();
+        save(path, &kept)
+    }
+}
+
+fn sha256(token: &str) -> String {
+    hex(digest::digest(&digest::SHA256, token.as_bytes()).as_ref())
+}
+
+// compares every byte whatever the first difference, so the time taken says
+// nothing about how much of a token was right
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[test]
+fn scopes() {
+    let tokens = Tokens::load(None, Some("secret")).unwrap();
+    assert_eq!(Some(Scope::Control), tokens.scope(Some("Bearer secret")));
+    assert_eq!(None, tokens.scope(Some("Bearer secreT")));
+    assert_eq!(None, tokens.scope(Some("secret")));
+    assert_eq!(None, tokens.scope(None));
+
+    let read = tokens.issue("dashboard", None, false).unwrap();
+    let bearer = format!("Bearer {}", read);
+    assert_eq!(Some(Scope::Read), tokens.scope(Some(&bearer)));
+    // rotating a token keeps its scope, and the old one stops working
+    let rotated = tokens.issue("dashboard", None, false).unwrap();
+    assert_eq!(None, tokens.scope(Some(&bearer)));
+    let bearer = format!("Bearer {}", rotated);
+    assert_eq!(Some(Scope::Read), tokens.scope(Some(&bearer)));
+    assert!(tokens.revoke("dashboard").unwrap());
+    assert_eq!(None, tokens.scope(Some(&bearer)));
+    assert!(!tokens.revoke("dashboard").unwrap());
+}
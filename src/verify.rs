@@ -0,0 +1,141 @@
+// Plays a game recorded with --record over again with the engine, for the
+// `verify` subcommand, and checks that every board and the result come out
+// as recorded. A replay that verifies holds everything it takes to reproduce
+// the game, and the game was played by the rules. Its seal, if it has one,
+// is checked too.
+use crate::replay::{self, Event};
+use crate::seal::Chain;
+use snake_ai_battle::{Game, Occupancy, Player, RedBlue, WinState};
+use std::path::Path;
+
+pub fn run(path: &Path, public_key: Option<&str>) -> Result<(), anyhow::Error> {
+    let (header, mut records) = replay::records(path)?;
+    let mut chain = Chain::new();
+    chain.add(records.line().as_bytes());
+    // who signed the replay, once its seal has been checked
+    let mut sealed: Option<Option<String>> = None;
+    let mut game: Option<Box<dyn Game>> = None;
+    let mut recorded = Vec::new();
+    let mut boards = 0;
+    let mut forfeits = RedBlue {
+        red: None,
+        blue: None,
+    };
+    let mut finished = false;
+    while let Some(record) = records.next() {
+        if sealed.is_some() {
+            anyhow::bail!("the replay goes on after its seal");
+        }
+        let event = record?.event;
+        if let Event::Sealed(seal) = &event {
+            sealed = Some(chain.check(seal)?.map(str::to_owned));
+            continue;
+        }
+        chain.add(records.line().as_bytes());
+        // forfeits come one player at a time, but happen on the same turn
+        if !matches!(event, Event::Forfeit { .. })
+            && (forfeits.red.is_some() || forfeits.blue.is_some())
+        {
+            playing(&mut game)?.forfeit(forfeits);
+            forfeits = RedBlue {
+                red: None,
+                blue: None,
+            };
+        }
+        match event {
+            Event::Setup(setup) => {
+                recorded = vec![Occupancy::Free; setup.width * setup.height];
+                game = Some(setup.game());
+            }
+            Event::Resumed { moves } => {
+                let game = playing(&mut game)?;
+                for moves in moves {
+                    game.step(moves);
+                }
+            }
+            Event::Turn { red, blue } => playing(&mut game)?.step(RedBlue { red, blue }),
+            Event::Forfeit { player, reason } => match player {
+                Player::Red => forfeits.red = Some(reason),
+                Player::Blue => forfeits.blue = Some(reason),
+            },
+            Event::Aborted => playing(&mut game)?.set_win_state(WinState::Tie),
+            Event::Board { turn, changes } => {
+                let game = playing(&mut game)?;
+                for (pos, cell) in changes {
+                    match recorded.get_mut(pos) {
+                        Some(seen) => *seen = cell,
+                        None => anyhow::bail!("position {} is off the board", pos),
+                    }
+                }
+                let board = game.render_data();
+                let differs = board.data.iter().zip(&recorded).position(|(a, b)| a != b);
+                if let Some(pos) = differs {
+                    anyhow::bail!(
+                        "after turn {}, the replay has {:?} at position {}, but the engine has {:?}",
+                        turn,
+                        recorded[pos],
+                        pos,
+                        board.data[pos]
+                    );
+                }
+                boards += 1;
+            }
+            Event::Result {
+                winner,
+                end_reasons,
+                ..
+            } => {
+                let game = playing(&mut game)?;
+                let result = replay::win_state(winner);
+                if game.win_state() != Some(result) {
+                    anyhow::bail!(
+                        "the replay's result is {:?} for red, but the engine's is {:?}",
+                        result,
+                        game.win_state()
+                    );
+                }
+                let engine = game.end_reasons();
+                if (engine.red, engine.blue) != (end_reasons.red, end_reasons.blue) {
+                    anyhow::bail!(
+                        "the replay's games ended with {:?}, but the engine's with {:?}",
+                        end_reasons,
+                        engine
+                    );
+                }
+                finished = true;
+            }
+            _ => (),
+        }
+    }
+    if !finished {
+        anyhow::bail!("the replay ends before the game's result");
+    }
+    println!(
+        "{}: all {} boards and the result match the engine's",
+        header.match_id, boards
+    );
+    let signer = match sealed {
+        Some(signer) => signer,
+        None if public_key.is_some() => anyhow::bail!("the replay isn't sealed"),
+        None => {
+            println!("It isn't sealed, so it could have been changed");
+            return Ok(());
+        }
+    };
+    match (signer, public_key) {
+        (Some(signer), Some(key)) if signer != key => {
+            anyhow::bail!("the replay was signed with {}, not {}", signer, key)
+        }
+        (Some(signer), _) => println!("It's sealed and signed with {}", signer),
+        (None, Some(_)) => anyhow::bail!("the replay's seal isn't signed"),
+        (None, None) => println!("It's sealed, but not signed"),
+    }
+    Ok(())
+}
+
+// the game being played over, once the replay has said how to set it up
+fn playing(game: &mut Option<Box<dyn Game>>) -> Result<&mut Box<dyn Game>, anyhow::Error> {
+    game.as_mut().ok_or_else(|| {
+        anyhow::anyhow!("the replay has no Setup event, so its game can't be played over")
+    })
+}